@@ -0,0 +1,473 @@
+use crate::button_types::{ButtonEvent, ButtonEventType};
+use crate::input_simulator::{self, HeldInput, InputSimulator, WaitingCommand};
+use crate::token_based_config::{ButtonName, ExecutableAction, InputBackendKind, WhenBusy};
+use anyhow::{Context, Result, anyhow};
+use enigo::Direction;
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Pause after every action, so that applications see separate input events
+const ACTION_GAP: Duration = Duration::from_millis(10);
+/// How often a program that an `Exec` action waits for is checked on
+const WAIT_POLL: Duration = Duration::from_millis(10);
+/// Longest the worker waits without a command, to reap finished `Exec` programs
+const IDLE_POLL: Duration = Duration::from_millis(250);
+
+enum Command {
+    Run {
+        job: Job,
+        when_busy: WhenBusy,
+    },
+    /// Stop the action sequences of a pedal and release what they pressed
+    ReleaseDevice {
+        device: String,
+    },
+}
+
+/// Action sequence waiting to be run
+struct Job {
+    /// Path of the pedal whose button triggered the actions
+    device: String,
+    button: ButtonName,
+    event: ButtonEventType,
+    label: String,
+    actions: Vec<ExecutableAction>,
+}
+
+/// Runs action sequences on a worker thread, so that sleeps and slow programs do not
+/// hold up button processing
+pub struct ActionExecutor {
+    sender: Option<Sender<Command>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Job {
+    /// Whether this is a REPEAT of the same button as `other`
+    fn repeats(&self, other: &Job) -> bool {
+        matches!(self.event, ButtonEventType::REPEAT)
+            && self.device == other.device
+            && self.button == other.button
+    }
+
+    /// Whether this job releases keys or mouse buttons, which must not be dropped to
+    /// make room for another job or the inputs would stay held
+    fn releases(&self) -> bool {
+        matches!(self.event, ButtonEventType::RELEASING)
+            || self.actions.iter().any(|action| {
+                matches!(
+                    action,
+                    ExecutableAction::KeyRelease { .. }
+                        | ExecutableAction::ReleaseAfter { .. }
+                        | ExecutableAction::ReleaseAll
+                        | ExecutableAction::ReleaseAllAfter { .. }
+                        | ExecutableAction::MouseButton {
+                            direction: Direction::Release,
+                            ..
+                        }
+                )
+            })
+    }
+}
+
+impl ActionExecutor {
+    /// Start the worker and its input simulator, see [`InputSimulator::new`]
+    pub fn start(preferred: Option<InputBackendKind>) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+
+        let worker = std::thread::Builder::new()
+            .name("action-executor".to_string())
+            .spawn(move || {
+                // Created here because not every backend can be moved between threads
+                match InputSimulator::new(preferred) {
+                    Ok(simulator) => {
+                        let _ = ready_sender.send(Ok(()));
+                        Worker::new(simulator).run(receiver);
+                    }
+                    Err(e) => {
+                        let _ = ready_sender.send(Err(e));
+                    }
+                }
+            })
+            .context("Failed to start the action executor thread.")?;
+
+        ready_receiver
+            .recv()
+            .context("The action executor thread stopped during startup.")??;
+
+        Ok(ActionExecutor {
+            sender: Some(sender),
+            worker: Some(worker),
+        })
+    }
+
+    /// Run the `actions` bound to `event` of the pedal at `device` in the background,
+    /// `label` names them in the log
+    pub fn run(
+        &self,
+        device: &str,
+        event: &ButtonEvent,
+        label: String,
+        actions: Vec<ExecutableAction>,
+        when_busy: WhenBusy,
+    ) -> Result<()> {
+        self.send(Command::Run {
+            job: Job {
+                device: device.to_string(),
+                button: event.button_name,
+                event: event.event_type,
+                label,
+                actions,
+            },
+            when_busy,
+        })
+    }
+
+    /// Stop the running and queued actions of the pedal at `device` and release the
+    /// keys and mouse buttons they pressed. Other pedals are not affected.
+    pub fn release_device(&self, device: &str) -> Result<()> {
+        self.send(Command::ReleaseDevice {
+            device: device.to_string(),
+        })
+    }
+
+    fn send(&self, command: Command) -> Result<()> {
+        self.sender
+            .as_ref()
+            .and_then(|sender| sender.send(command).ok())
+            .ok_or_else(|| anyhow!("The action executor has stopped."))
+    }
+}
+
+impl Drop for ActionExecutor {
+    fn drop(&mut self) {
+        // Closing the channel makes the worker release held input and exit
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// An action sequence in progress
+struct Sequence {
+    id: u64,
+    job: Job,
+    next: usize,
+    /// When the next action is due, or the waited-for program is checked again
+    resume_at: Instant,
+    /// Program of an `Exec` action that has to finish before the next action
+    waiting: Option<WaitingCommand>,
+}
+
+struct Worker {
+    simulator: InputSimulator,
+    running: Option<Sequence>,
+    queued: VecDeque<Job>,
+    next_id: u64,
+    /// Sequence that last pressed each key or mouse button, and its pedal
+    pressed_by: HashMap<HeldInput, (u64, String)>,
+}
+
+impl Worker {
+    fn new(simulator: InputSimulator) -> Self {
+        Worker {
+            simulator,
+            running: None,
+            queued: VecDeque::new(),
+            next_id: 0,
+            pressed_by: HashMap::new(),
+        }
+    }
+
+    fn run(mut self, receiver: Receiver<Command>) {
+        loop {
+            let timeout = self.next_wakeup().saturating_duration_since(Instant::now());
+            match receiver.recv_timeout(timeout) {
+                Ok(command) => self.handle_command(command),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            self.run_due_actions();
+            if let Err(e) = self.simulator.process_scheduled_releases() {
+                eprintln!("Error processing scheduled releases: {e}");
+            }
+            self.simulator.reap_finished_commands();
+        }
+
+        self.cancel();
+        if let Err(e) = self.simulator.release_all_keys() {
+            eprintln!("Failed to release held keys on shutdown: {e}");
+        }
+    }
+
+    /// When the next action or scheduled release is due
+    fn next_wakeup(&self) -> Instant {
+        let mut wakeup = Instant::now() + IDLE_POLL;
+        if let Some(sequence) = &self.running {
+            wakeup = wakeup.min(sequence.resume_at);
+        }
+        if let Some(release) = self.simulator.next_scheduled_release() {
+            wakeup = wakeup.min(release);
+        }
+        wakeup
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Run { job, when_busy } => {
+                if job.actions.is_empty() {
+                    return;
+                }
+                // Repeats would pile up if the actions take longer than the interval
+                if matches!(job.event, ButtonEventType::REPEAT)
+                    && self.is_pending(|other| other.repeats(&job))
+                {
+                    println!(
+                        "⏭️  Skipping {}, the previous one has not finished",
+                        job.label
+                    );
+                    return;
+                }
+                if matches!(job.event, ButtonEventType::RELEASING) {
+                    let queued = self.queued.len();
+                    self.queued.retain(|other| !other.repeats(&job));
+                    if self.queued.len() < queued {
+                        println!(
+                            "⏹️  Dropped {} queued REPEAT action sequence(s)",
+                            queued - self.queued.len()
+                        );
+                    }
+                }
+                if self.running.is_none() {
+                    self.start(job);
+                    return;
+                }
+                match when_busy {
+                    WhenBusy::Queue => {
+                        println!(
+                            "⏳ {} waits for {} earlier action sequence(s)",
+                            job.label,
+                            self.queued.len() + 1
+                        );
+                        self.queued.push_back(job);
+                    }
+                    WhenBusy::Preempt => self.preempt(job),
+                }
+            }
+            Command::ReleaseDevice { device } => {
+                let queued = self.queued.len();
+                self.queued.retain(|job| job.device != device);
+                if self.queued.len() < queued {
+                    println!(
+                        "⏹️  Dropped {} queued action sequence(s)",
+                        queued - self.queued.len()
+                    );
+                }
+                if self
+                    .running
+                    .as_ref()
+                    .is_some_and(|sequence| sequence.job.device == device)
+                {
+                    self.stop_running();
+                    self.finish();
+                }
+                self.release_pressed_by(|_, pressed_by| pressed_by == device);
+            }
+        }
+    }
+
+    fn start(&mut self, job: Job) {
+        println!("Executing Action Sequence: {}", job.label);
+        println!("{}", "-".repeat(60));
+        println!("| {:<3} | {:<50} |", "No.", "Action");
+        println!("{}", "-".repeat(60));
+
+        self.next_id += 1;
+        self.running = Some(Sequence {
+            id: self.next_id,
+            job,
+            next: 0,
+            resume_at: Instant::now(),
+            waiting: None,
+        });
+    }
+
+    /// Whether a running or queued job matches
+    fn is_pending(&self, matches: impl Fn(&Job) -> bool) -> bool {
+        self.running
+            .iter()
+            .map(|sequence| &sequence.job)
+            .chain(&self.queued)
+            .any(matches)
+    }
+
+    /// Move on to the next queued sequence, if any
+    fn finish(&mut self) {
+        self.running = None;
+        if let Some(job) = self.queued.pop_front() {
+            self.start(job);
+        }
+    }
+
+    /// Start `job` right away. The running sequence is stopped and the queued jobs of the
+    /// same button are dropped, except for those that release held input: a running one
+    /// finishes first and queued ones keep their place.
+    fn preempt(&mut self, job: Job) {
+        let queued = self.queued.len();
+        self.queued.retain(|other| {
+            other.releases() || other.device != job.device || other.button != job.button
+        });
+        if self.queued.len() < queued {
+            println!(
+                "⏹️  Dropped {} queued action sequence(s)",
+                queued - self.queued.len()
+            );
+        }
+        if self
+            .running
+            .as_ref()
+            .is_some_and(|sequence| sequence.job.releases())
+        {
+            println!("⏳ {} starts once the running release finishes", job.label);
+            self.queued.push_front(job);
+        } else {
+            self.stop_running();
+            self.start(job);
+        }
+    }
+
+    /// Drop the running sequence and everything queued behind it
+    fn cancel(&mut self) {
+        self.stop_running();
+        if !self.queued.is_empty() {
+            println!(
+                "⏹️  Dropped {} queued action sequence(s)",
+                self.queued.len()
+            );
+            self.queued.clear();
+        }
+    }
+
+    /// Stop the running sequence. The program it waits for is killed and the keys and
+    /// mouse buttons it pressed are released.
+    fn stop_running(&mut self) {
+        if let Some(mut sequence) = self.running.take() {
+            println!(
+                "⏹️  Stopped {} after {} of {} actions",
+                sequence.job.label,
+                sequence.next,
+                sequence.job.actions.len()
+            );
+            if let Some(waiting) = &mut sequence.waiting
+                && let Err(e) = waiting.kill()
+            {
+                eprintln!("Failed to stop the program of {}: {e}", sequence.job.label);
+            }
+            self.release_pressed_by(|id, _| id == sequence.id);
+        }
+    }
+
+    /// Release what the matching sequences pressed and is still held
+    fn release_pressed_by(&mut self, matches: impl Fn(u64, &str) -> bool) {
+        let mut inputs = Vec::new();
+        self.pressed_by.retain(|input, (id, device)| {
+            if matches(*id, device) {
+                inputs.push(*input);
+                false
+            } else {
+                true
+            }
+        });
+        if let Err(e) = self.simulator.release_inputs(inputs) {
+            eprintln!("Failed to release held keys: {e}");
+        }
+    }
+
+    /// Run every action that is due, starting queued sequences as others finish
+    fn run_due_actions(&mut self) {
+        while let Some(sequence) = &mut self.running {
+            let now = Instant::now();
+            if sequence.resume_at > now {
+                return;
+            }
+
+            if let Some(waiting) = &mut sequence.waiting {
+                match waiting.poll() {
+                    Ok(false) => {
+                        sequence.resume_at = (now + WAIT_POLL).min(waiting.deadline());
+                    }
+                    Ok(true) => {
+                        sequence.waiting = None;
+                        sequence.resume_at = now + ACTION_GAP;
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to execute actions: {e}");
+                        self.finish();
+                    }
+                }
+                continue;
+            }
+
+            let Some(action) = sequence.job.actions.get(sequence.next) else {
+                println!("{}", "-".repeat(60));
+                println!("Action sequence completed successfully");
+                self.finish();
+                continue;
+            };
+            sequence.next += 1;
+            println!(
+                "| {:<3} | {:<50} |",
+                sequence.next,
+                input_simulator::describe_action(action)
+            );
+
+            if let ExecutableAction::Sleep { duration_ms } = action {
+                // A timed step instead of blocking, so new commands are still handled
+                sequence.resume_at = now + Duration::from_millis(*duration_ms);
+                continue;
+            }
+            match self.simulator.execute_action(action) {
+                Ok(waiting) => {
+                    let pressed = match action {
+                        ExecutableAction::KeyPress {
+                            key,
+                            auto_release: false,
+                        } => Some(HeldInput::Key(*key)),
+                        ExecutableAction::MouseButton {
+                            button,
+                            direction: Direction::Press,
+                        } => Some(HeldInput::Mouse(*button)),
+                        _ => None,
+                    };
+                    match action {
+                        ExecutableAction::KeyRelease { key } => {
+                            self.pressed_by.remove(&HeldInput::Key(*key));
+                        }
+                        ExecutableAction::MouseButton {
+                            button,
+                            direction: Direction::Release,
+                        } => {
+                            self.pressed_by.remove(&HeldInput::Mouse(*button));
+                        }
+                        ExecutableAction::ReleaseAll => self.pressed_by.clear(),
+                        _ => {}
+                    }
+                    if let Some(input) = pressed {
+                        self.pressed_by
+                            .insert(input, (sequence.id, sequence.job.device.clone()));
+                    }
+                    sequence.waiting = waiting;
+                    sequence.resume_at = Instant::now() + ACTION_GAP;
+                }
+                Err(e) => {
+                    eprintln!("Failed to execute actions: {e}");
+                    self.finish();
+                }
+            }
+        }
+    }
+}
@@ -6,8 +6,13 @@ pub struct ButtonStateMachine<S> {
     current_state: S,
     first_signal_time: Option<Instant>,
     last_signal_time: Option<Instant>,
+    last_release_time: Option<Instant>,
     signal_count: u32,
     action_fired: bool,
+    repeat_count: u32,
+    next_repeat_time: Option<Instant>,
+    hold_stage: Option<usize>,
+    layer: Option<String>,
 }
 
 impl<S> ButtonStateMachine<S>
@@ -19,8 +24,13 @@ where
             current_state: initial_state,
             first_signal_time: None,
             last_signal_time: None,
+            last_release_time: None,
             signal_count: 0,
             action_fired: false,
+            repeat_count: 0,
+            next_repeat_time: None,
+            hold_stage: None,
+            layer: None,
         }
     }
 
@@ -51,6 +61,16 @@ where
             .map(|first| now.duration_since(first))
     }
 
+    /// Record a release at the given time
+    pub fn record_release(&mut self, now: Instant) {
+        self.last_release_time = Some(now);
+    }
+
+    /// Get the time elapsed since the most recent release
+    pub fn time_since_last_release(&self, now: Instant) -> Option<std::time::Duration> {
+        self.last_release_time.map(|last| now.duration_since(last))
+    }
+
     /// Get the number of signals recorded
     pub fn signal_count(&self) -> u32 {
         self.signal_count
@@ -66,13 +86,54 @@ where
         self.action_fired = true;
     }
 
+    /// Get the number of repeats fired during the current hold
+    pub fn repeat_count(&self) -> u32 {
+        self.repeat_count
+    }
+
+    /// Get the time the next repeat is due, if one has been scheduled
+    pub fn next_repeat_time(&self) -> Option<Instant> {
+        self.next_repeat_time
+    }
+
+    /// Record a repeat and schedule the next one
+    pub fn record_repeat(&mut self, next: Instant) {
+        self.repeat_count += 1;
+        self.next_repeat_time = Some(next);
+    }
+
+    /// Get the last hold stage that fired, if any
+    pub fn hold_stage(&self) -> Option<usize> {
+        self.hold_stage
+    }
+
+    /// Record that a hold stage has fired
+    pub fn set_hold_stage(&mut self, stage: usize) {
+        self.hold_stage = Some(stage);
+    }
+
+    /// Get the layer the current press resolves its actions from
+    pub fn layer(&self) -> Option<&str> {
+        self.layer.as_deref()
+    }
+
+    /// Remember the layer active at the start of a press
+    pub fn set_layer(&mut self, layer: Option<String>) {
+        self.layer = layer;
+    }
+
     /// Reset the state machine to initial state
     pub fn reset(&mut self, initial_state: S) {
         self.current_state = initial_state;
         self.first_signal_time = None;
         self.last_signal_time = None;
+        self.last_release_time = None;
         self.signal_count = 0;
         self.action_fired = false;
+        self.repeat_count = 0;
+        self.next_repeat_time = None;
+        self.hold_stage = None;
+        self.layer = None;
     }
 }
 
@@ -83,6 +144,8 @@ pub enum StateTransition<E> {
     Continue,
     /// Generate events and optionally transition state
     EmitEvents(Vec<E>),
+    /// Generate events, then reset the state machine
+    EmitEventsAndReset(Vec<E>),
     /// Reset the state machine
     Reset,
 }
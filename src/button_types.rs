@@ -1,21 +1,25 @@
-use crate::token_based_config::PhysicalButtonName;
+use crate::token_based_config::ButtonName;
 
 /// Generic button states that can be used by any button detection system
 #[derive(Debug, Copy, Clone, PartialEq)]
-#[allow(clippy::upper_case_acronyms)]
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
 pub enum ButtonState {
-    IDLE,       // No recent activity
-    EVALUATING, // Received first signal, evaluating user intent
-    HELD,       // Button is being held down
-    RELEASING,  // Button is in the process of being released
+    IDLE,         // No recent activity
+    EVALUATING,   // Received first signal, evaluating user intent
+    HELD,         // Button is being held down
+    RELEASING,    // Button is in the process of being released
+    AWAITING_TAP, // Released after a tap, waiting to see if another tap follows
 }
 
 /// Types of button events that can be generated
 #[derive(Debug, Copy, Clone)]
-#[allow(clippy::upper_case_acronyms)]
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
 pub enum ButtonEventType {
     PRESSED,
+    DOUBLE_PRESSED,
+    TRIPLE_PRESSED,
     HELD,
+    REPEAT,    // Fired repeatedly while the button stays held
     RELEASING, // Button is being released (transition event)
 }
 
@@ -23,22 +27,39 @@ impl ButtonEventType {
     pub fn as_str(&self) -> &str {
         match self {
             ButtonEventType::PRESSED => "PRESSED",
+            ButtonEventType::DOUBLE_PRESSED => "DOUBLE_PRESSED",
+            ButtonEventType::TRIPLE_PRESSED => "TRIPLE_PRESSED",
             ButtonEventType::HELD => "HELD",
+            ButtonEventType::REPEAT => "REPEAT",
             ButtonEventType::RELEASING => "RELEASING",
         }
     }
+
+    /// Event fired for a completed sequence of `tap_count` quick taps
+    pub fn for_tap_count(tap_count: u32) -> Self {
+        match tap_count {
+            0 | 1 => ButtonEventType::PRESSED,
+            2 => ButtonEventType::DOUBLE_PRESSED,
+            _ => ButtonEventType::TRIPLE_PRESSED,
+        }
+    }
 }
 
 /// Generic button event structure
 #[derive(Debug, Clone)]
 pub struct ButtonEvent {
-    pub button_name: PhysicalButtonName,
+    pub button_name: ButtonName,
     pub event_type: ButtonEventType,
+    /// Hold stage reached: set on HELD events for the stage that fired, and on
+    /// RELEASING for the last stage reached before release. 0 is the plain HELD stage.
+    pub hold_stage: Option<usize>,
+    /// Layer that was active when the button was pressed
+    pub layer: Option<String>,
 }
 
 /// Input data for button processing
 #[derive(Debug, Clone)]
 pub struct ButtonInput {
-    pub button_name: PhysicalButtonName,
+    pub button_name: ButtonName,
     pub is_pressed: bool,
 }
@@ -1,8 +1,12 @@
+use crate::config_migration;
 use crate::token_based_config::{
-    ActionItem, ActionValue, ButtonConfig, DeviceConfig, TokenBasedConfig, TokenBasedParser,
+    ButtonConfig, CONFIG_VERSION, DeviceConfig, DocumentVersion, ExecutableAction,
+    TokenBasedConfig, TokenBasedParser, with_document_version,
 };
 use enigo::Key;
+use serde::de::DeserializeOwned;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
 use std::sync::{Arc, Mutex};
 
@@ -13,6 +17,101 @@ pub struct ConfigManager {
 
 static CONFIG_MANAGER: OnceLock<ConfigManager> = OnceLock::new();
 
+/// JSON Schema written next to the config file and referenced from its `$schema`
+const SCHEMA_FILE_NAME: &str = "elgato_pedal_controller.schema.json";
+
+/// Config file extensions, in the order they are looked for
+const CONFIG_EXTENSIONS: [&str; 4] = ["json", "toml", "yaml", "yml"];
+
+/// Environment variable naming the config file to use, overridden by `--config`
+pub const CONFIG_PATH_ENV_VAR: &str = "ELGATO_PEDAL_CONFIG";
+
+/// Error for YAML files in builds without the `yaml` feature
+#[cfg(not(feature = "yaml"))]
+pub const YAML_UNAVAILABLE: &str =
+    "YAML support is not included in this build, rebuild with `--features yaml`";
+
+/// Expand a leading `~/` to the home directory, other paths are returned as they are
+pub fn expand_home(path: &str) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => home::home_dir().unwrap_or_default().join(rest),
+        None => PathBuf::from(path),
+    }
+}
+
+/// Config file given on the command line with `--config`
+static CONFIG_PATH_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// File formats the config can be written in, chosen by file extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Format of a config file, from its extension
+    pub fn from_path(path: &Path) -> Result<Self, String> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(ConfigFormat::Json),
+            Some("toml") => Ok(ConfigFormat::Toml),
+            Some("yaml" | "yml") => Ok(ConfigFormat::Yaml),
+            _ => Err(format!(
+                "\"{}\" has no known config extension, expected .json, .toml, .yaml or .yml",
+                path.display()
+            )),
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ConfigFormat::Json => "json",
+            ConfigFormat::Toml => "toml",
+            ConfigFormat::Yaml => "yaml",
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(
+        self,
+        content: &str,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        Ok(match self {
+            ConfigFormat::Json => serde_json::from_str(content)?,
+            ConfigFormat::Toml => toml::from_str(content)?,
+            #[cfg(feature = "yaml")]
+            ConfigFormat::Yaml => serde_yaml::from_str(content)?,
+            #[cfg(not(feature = "yaml"))]
+            ConfigFormat::Yaml => return Err(YAML_UNAVAILABLE.into()),
+        })
+    }
+
+    /// Parse a config file, reading its actions in the format of its `version`
+    pub fn parse_config(
+        self,
+        content: &str,
+    ) -> Result<TokenBasedConfig, Box<dyn std::error::Error>> {
+        let DocumentVersion { version } = self.parse(content)?;
+        with_document_version(version, || self.parse(content))
+    }
+
+    pub fn serialize(
+        self,
+        config: &TokenBasedConfig,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(match self {
+            ConfigFormat::Json => serde_json::to_string_pretty(config)?,
+            ConfigFormat::Toml => toml::to_string_pretty(config)?,
+            // Through a JSON value, so that keys like `{"Unicode": "o"}` stay maps instead
+            // of YAML tags, which cannot be read back inside tagged actions
+            #[cfg(feature = "yaml")]
+            ConfigFormat::Yaml => serde_yaml::to_string(&serde_json::to_value(config)?)?,
+            #[cfg(not(feature = "yaml"))]
+            ConfigFormat::Yaml => return Err(YAML_UNAVAILABLE.into()),
+        })
+    }
+}
+
 impl ConfigManager {
     /// Get the global shared config manager instance
     pub fn global() -> &'static ConfigManager {
@@ -40,6 +139,21 @@ impl ConfigManager {
         let config_path = Self::get_config_path();
 
         if config_path.exists() {
+            let other_files: Vec<PathBuf> =
+                match (Self::explicit_config_path(), config_path.parent()) {
+                    (None, Some(dir)) => Self::config_files_in(dir)
+                        .filter(|path| *path != config_path && path.exists())
+                        .collect(),
+                    _ => Vec::new(),
+                };
+            for path in other_files {
+                println!(
+                    "⚠️  Ignoring \"{}\", \"{}\" takes precedence",
+                    path.display(),
+                    config_path.display()
+                );
+            }
+
             let config_content = std::fs::read_to_string(&config_path)?;
 
             // Check if the file is empty or contains only whitespace
@@ -48,10 +162,25 @@ impl ConfigManager {
                 return Self::create_and_save_default_config();
             }
 
-            // Try to parse the JSON, if it fails, warn user and exit
-            match serde_json::from_str::<TokenBasedConfig>(&config_content) {
+            // Try to parse the config, if it fails, warn user and exit
+            let format = ConfigFormat::from_path(&config_path)?;
+            match format.parse_config(&config_content) {
                 Ok(config) => {
                     println!("Using config file path: \"{}\"", config_path.display());
+                    // Keep the referenced schema in step with this version of the program,
+                    // except next to a system-wide default, which is never written to
+                    if config.schema.as_deref() == Some(Self::schema_reference().as_str())
+                        && !Self::is_system_config(&config_path)
+                        && let Err(e) = Self::save_schema()
+                    {
+                        eprintln!("⚠️  Failed to update the config schema: {e}");
+                    }
+                    if config.version < CONFIG_VERSION {
+                        println!(
+                            "ℹ️  The config file uses the v{} format, which is still read. Run `elgato-pedal-controller migrate` to rewrite it in the v{CONFIG_VERSION} format.",
+                            config.version
+                        );
+                    }
                     Ok(config)
                 }
                 Err(e) => {
@@ -61,16 +190,18 @@ impl ConfigManager {
                     );
                     eprintln!("Parse error: {e}");
                     eprintln!();
-                    eprintln!("⚠️  Your configuration file exists but contains invalid JSON.");
-                    eprintln!("Please fix the JSON syntax errors, or");
+                    eprintln!("⚠️  Your configuration file exists but contains invalid entries.");
+                    eprintln!("Please fix the errors, or");
                     eprintln!(" Delete the file to generate a new default config.");
                     eprintln!();
-                    eprintln!("- Common JSON issues:");
-                    eprintln!("   • Missing commas between objects");
-                    eprintln!("   • Trailing commas after last items");
-                    eprintln!("   • Unmatched brackets {{ }} or [ ]");
-                    eprintln!("   • Missing quotes around strings");
-                    eprintln!();
+                    if format == ConfigFormat::Json {
+                        eprintln!("- Common JSON issues:");
+                        eprintln!("   • Missing commas between objects");
+                        eprintln!("   • Trailing commas after last items");
+                        eprintln!("   • Unmatched brackets {{ }} or [ ]");
+                        eprintln!("   • Missing quotes around strings");
+                        eprintln!();
+                    }
                     eprintln!("Application cannot start with invalid config.");
                     Err(format!("Invalid configuration file: {e}").into())
                 }
@@ -81,11 +212,76 @@ impl ConfigManager {
         }
     }
 
+    /// Read and validate the config file again for a hot reload.
+    /// Unlike `load_config`, a missing or broken file is an error rather than replaced.
+    pub fn read_config_for_reload() -> Result<TokenBasedConfig, Box<dyn std::error::Error>> {
+        let config_path = Self::get_config_path();
+        let config_content = std::fs::read_to_string(&config_path)?;
+        let config = ConfigFormat::from_path(&config_path)?.parse_config(&config_content)?;
+        let config = TokenBasedParser::resolve_config(config, &config_path)?;
+        config
+            .validate()
+            .map_err(|e| format!("Invalid configuration: {e}"))?;
+        Ok(config)
+    }
+
+    /// Rewrite the config file in the current format, keeping a backup of the original
+    pub fn migrate_config() -> Result<(), Box<dyn std::error::Error>> {
+        let config_path = Self::get_config_path();
+        let config_content = std::fs::read_to_string(&config_path)?;
+        let format = ConfigFormat::from_path(&config_path)?;
+        let config = format.parse_config(&config_content)?;
+
+        if config.version >= CONFIG_VERSION {
+            println!(
+                "✅ \"{}\" already uses the v{} format",
+                config_path.display(),
+                config.version
+            );
+            return Ok(());
+        }
+
+        let config_path = Self::config_path_for_writing()?;
+        let mut backup_path = config_path.clone().into_os_string();
+        backup_path.push(format!(".v{}.bak", config.version));
+        std::fs::copy(&config_path, &backup_path)?;
+        println!(
+            "💾 Saved the v{} config as \"{}\"",
+            config.version,
+            backup_path.to_string_lossy()
+        );
+
+        let schema = config.schema.is_none().then(Self::schema_reference);
+        let (migrated, rewritten_actions) =
+            config_migration::migrate_document(format, &config_content, schema.as_deref())?;
+        if schema.is_some() {
+            Self::save_schema()?;
+        }
+        std::fs::write(&config_path, migrated)?;
+        println!(
+            "✅ Migrated \"{}\" to the v{CONFIG_VERSION} format",
+            config_path.display()
+        );
+        match format {
+            ConfigFormat::Yaml => println!(
+                "ℹ️  Comments are not carried over to the migrated file, \"{}\" still has them",
+                backup_path.to_string_lossy()
+            ),
+            ConfigFormat::Toml if rewritten_actions > 0 => println!(
+                "ℹ️  Comments inside rewritten actions are not carried over, \"{}\" still has them",
+                backup_path.to_string_lossy()
+            ),
+            _ => {}
+        }
+        Ok(())
+    }
+
     /// Create and save default configuration
     pub fn create_and_save_default_config() -> Result<TokenBasedConfig, Box<dyn std::error::Error>>
     {
         let default_config = Self::create_default_config();
         Self::save_config(&default_config)?;
+        Self::save_schema()?;
         let config_path = Self::get_config_path();
         println!(
             "Created default config file at: \"{}\"",
@@ -96,25 +292,233 @@ impl ConfigManager {
 
     /// Save configuration to file
     pub fn save_config(config: &TokenBasedConfig) -> Result<(), Box<dyn std::error::Error>> {
-        let config_path = Self::get_config_path();
+        let config_path = Self::config_path_for_writing()?;
 
         // Create directory if it doesn't exist
         if let Some(parent) = config_path.parent() {
             std::fs::create_dir_all(parent)?;
         }
 
-        let config_json = serde_json::to_string_pretty(config)?;
-        std::fs::write(&config_path, config_json)?;
+        let config_content = ConfigFormat::from_path(&config_path)?.serialize(config)?;
+        std::fs::write(&config_path, config_content)?;
+        Ok(())
+    }
+
+    /// Write a config file in another format. Without an output path the result goes
+    /// next to the input; if the input is the active config file found in the config
+    /// directories, it is then renamed to `*.bak` so that the converted file takes its
+    /// place. A file chosen with `--config` or `ELGATO_PEDAL_CONFIG` is left in place.
+    pub fn convert_config(
+        to: ConfigFormat,
+        input: Option<PathBuf>,
+        output: Option<PathBuf>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let input = input.unwrap_or_else(Self::get_config_path);
+        let from = ConfigFormat::from_path(&input)?;
+        let config = from.parse_config(&std::fs::read_to_string(&input)?)?;
+
+        let is_active_config =
+            std::path::absolute(&input).is_ok_and(|input| input == Self::get_config_path());
+        let explicitly_chosen = is_active_config && Self::explicit_config_path().is_some();
+        // A system-wide default stays untouched, the converted file goes to the user's
+        // config directory, where it takes precedence
+        let system_config = is_active_config && Self::is_system_config(&input);
+        let replaces_active_config =
+            output.is_none() && is_active_config && !explicitly_chosen && !system_config;
+        let output = output.unwrap_or_else(|| {
+            let output = input.with_extension(to.extension());
+            match output.file_name() {
+                Some(name) if system_config => Self::user_config_dir().join(name),
+                _ => output,
+            }
+        });
+        if let Some(parent) = output.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        if output.exists() {
+            return Err(format!("\"{}\" already exists", output.display()).into());
+        }
+
+        std::fs::write(&output, to.serialize(&config)?)?;
+        println!(
+            "✅ Converted \"{}\" to \"{}\"",
+            input.display(),
+            output.display()
+        );
+        if from != ConfigFormat::Json {
+            println!("ℹ️  Comments are not carried over to the converted file");
+        }
+
+        if replaces_active_config {
+            let mut backup_path = input.clone().into_os_string();
+            backup_path.push(".bak");
+            std::fs::rename(&input, &backup_path)?;
+            println!(
+                "💾 Moved the old config to \"{}\", \"{}\" is used from now on",
+                backup_path.to_string_lossy(),
+                output.display()
+            );
+        } else if system_config {
+            println!(
+                "ℹ️  \"{}\" is a system-wide default and stays as it is, \"{}\" takes precedence over it",
+                input.display(),
+                output.display()
+            );
+        } else if explicitly_chosen {
+            println!(
+                "ℹ️  \"{}\" was chosen with --config or ELGATO_PEDAL_CONFIG and stays in use, point it at \"{}\" to use the converted file",
+                input.display(),
+                output.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Write the JSON Schema of the config file next to it
+    pub fn save_schema() -> Result<(), Box<dyn std::error::Error>> {
+        let schema_path = Self::get_schema_path();
+        if let Some(parent) = schema_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let schema_json = serde_json::to_string_pretty(&TokenBasedConfig::json_schema())?;
+        std::fs::write(&schema_path, schema_json)?;
         Ok(())
     }
 
-    /// Get the configuration file path
-    pub fn get_config_path() -> std::path::PathBuf {
-        // Use the home directory for the config file
-        let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
-        std::path::Path::new(&home)
-            .join(".config")
-            .join("elgato_pedal_controller.config.json")
+    /// Get the path of the JSON Schema written next to the config file
+    pub fn get_schema_path() -> PathBuf {
+        Self::writable_config_path().with_file_name(SCHEMA_FILE_NAME)
+    }
+
+    /// `$schema` value pointing at the schema file, relative to the config file
+    fn schema_reference() -> String {
+        format!("./{SCHEMA_FILE_NAME}")
+    }
+
+    /// Use this config file instead of searching for one. Set once at startup from
+    /// the `--config` flag, before anything reads the configuration.
+    pub fn set_config_path(path: PathBuf) {
+        let _ = CONFIG_PATH_OVERRIDE.set(path);
+    }
+
+    /// Config file chosen explicitly through `--config` or `ELGATO_PEDAL_CONFIG`
+    pub fn explicit_config_path() -> Option<PathBuf> {
+        CONFIG_PATH_OVERRIDE.get().cloned().or_else(|| {
+            std::env::var_os(CONFIG_PATH_ENV_VAR)
+                .filter(|path| !path.is_empty())
+                .map(|path| std::path::absolute(&path).unwrap_or_else(|_| PathBuf::from(path)))
+        })
+    }
+
+    /// Get the configuration file path, in order of precedence:
+    /// 1. `--config` or `ELGATO_PEDAL_CONFIG`
+    /// 2. a JSON, TOML or YAML file in `$XDG_CONFIG_HOME` (`~/.config`)
+    /// 3. a system-wide default in one of `$XDG_CONFIG_DIRS` (`/etc/xdg`)
+    /// 4. the JSON file in `$XDG_CONFIG_HOME`, which is created if needed
+    pub fn get_config_path() -> PathBuf {
+        if let Some(path) = Self::explicit_config_path() {
+            return path;
+        }
+
+        let user_config_dir = Self::user_config_dir();
+        std::iter::once(user_config_dir.clone())
+            .chain(Self::system_config_dirs())
+            .flat_map(|dir| Self::config_files_in(&dir))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| user_config_dir.join("elgato_pedal_controller.config.json"))
+    }
+
+    /// Whether `path` is a system-wide default found in `$XDG_CONFIG_DIRS`. These are
+    /// only read; changes go to a copy in `$XDG_CONFIG_HOME`.
+    pub fn is_system_config(path: &Path) -> bool {
+        Self::explicit_config_path().is_none()
+            && Self::system_config_dirs()
+                .iter()
+                .any(|dir| path.starts_with(dir))
+    }
+
+    /// Where changes to the config are written: the config file itself, or for a
+    /// system-wide default, the same file name in `$XDG_CONFIG_HOME`
+    fn writable_config_path() -> PathBuf {
+        let config_path = Self::get_config_path();
+        match config_path.file_name() {
+            Some(name) if Self::is_system_config(&config_path) => {
+                Self::user_config_dir().join(name)
+            }
+            _ => config_path,
+        }
+    }
+
+    /// Path to write the config to. A system-wide default is first copied into
+    /// `$XDG_CONFIG_HOME`, where the copy takes precedence from then on.
+    pub fn config_path_for_writing() -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let config_path = Self::get_config_path();
+        let writable_path = Self::writable_config_path();
+        if writable_path != config_path {
+            if let Some(parent) = writable_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::copy(&config_path, &writable_path)?;
+            Self::save_schema()?;
+            println!(
+                "📄 Copied the system-wide config \"{}\" to \"{}\", which is used from now on",
+                config_path.display(),
+                writable_path.display()
+            );
+
+            // Relative includes now resolve next to the copy
+            let content = std::fs::read_to_string(&writable_path)?;
+            let config: serde_json::Value = ConfigFormat::from_path(&writable_path)?
+                .parse(&content)
+                .unwrap_or_default();
+            let relative_includes = config
+                .get("include")
+                .and_then(|include| include.as_array())
+                .into_iter()
+                .flatten()
+                .filter_map(|entry| entry.as_str())
+                .filter(|entry| !entry.starts_with("~/") && Path::new(entry).is_relative());
+            for entry in relative_includes {
+                println!(
+                    "⚠️  The include \"{entry}\" is now looked up next to \"{}\", copy it there or make the path absolute",
+                    writable_path.display()
+                );
+            }
+        }
+        Ok(writable_path)
+    }
+
+    /// `$XDG_CONFIG_HOME`, or `~/.config` when it is unset or not an absolute path
+    pub fn user_config_dir() -> PathBuf {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .unwrap_or_else(|| expand_home("~/.config"))
+    }
+
+    /// `$XDG_CONFIG_DIRS`, or `/etc/xdg` when it is unset
+    fn system_config_dirs() -> Vec<PathBuf> {
+        let dirs: Vec<PathBuf> = std::env::var_os("XDG_CONFIG_DIRS")
+            .map(|dirs| {
+                std::env::split_paths(&dirs)
+                    .filter(|dir| dir.is_absolute())
+                    .collect()
+            })
+            .unwrap_or_default();
+        if dirs.is_empty() {
+            vec![PathBuf::from("/etc/xdg")]
+        } else {
+            dirs
+        }
+    }
+
+    /// Possible config files in a directory, in order of precedence
+    fn config_files_in(dir: &Path) -> impl Iterator<Item = PathBuf> + use<> {
+        let dir = dir.to_path_buf();
+        CONFIG_EXTENSIONS
+            .into_iter()
+            .map(move |extension| dir.join(format!("elgato_pedal_controller.config.{extension}")))
     }
 
     /// Create default configuration
@@ -126,31 +530,25 @@ impl ConfigManager {
         button_0_actions.insert(
             "PRESSED".to_string(),
             vec![
-                ActionItem {
-                    action_type: "Key".to_string(),
-                    direction: Some("Press".to_string()),
-                    value: Some(ActionValue::Key(Key::Meta)),
-                    auto_release: Some(false),
-                },
-                ActionItem {
-                    action_type: "Key".to_string(),
-                    direction: Some("Press".to_string()),
-                    value: Some(ActionValue::Key(Key::Unicode('o'))),
-                    auto_release: None,
+                ExecutableAction::KeyPress {
+                    key: Key::Meta,
+                    auto_release: false,
                 },
-                ActionItem {
-                    action_type: "ReleaseAll".to_string(),
-                    direction: None,
-                    value: None,
-                    auto_release: None,
+                ExecutableAction::KeyPress {
+                    key: Key::Unicode('o'),
+                    auto_release: true,
                 },
+                ExecutableAction::ReleaseAll,
             ],
         );
 
         buttons.insert(
             "button_0".to_string(),
             ButtonConfig {
+                label: None,
                 actions: button_0_actions,
+                layers: HashMap::new(),
+                layer_key: None,
                 settings: None, // Use default settings
             },
         );
@@ -159,27 +557,20 @@ impl ConfigManager {
         let mut button_1_actions = HashMap::new();
         button_1_actions.insert(
             "HELD".to_string(),
-            vec![ActionItem {
-                action_type: "Key".to_string(),
-                direction: Some("Press".to_string()),
-                value: Some(ActionValue::Key(Key::Meta)),
-                auto_release: Some(false),
-            }],
-        );
-        button_1_actions.insert(
-            "RELEASING".to_string(),
-            vec![ActionItem {
-                action_type: "ReleaseAll".to_string(),
-                direction: None,
-                value: None,
-                auto_release: None,
+            vec![ExecutableAction::KeyPress {
+                key: Key::Meta,
+                auto_release: false,
             }],
         );
+        button_1_actions.insert("RELEASING".to_string(), vec![ExecutableAction::ReleaseAll]);
 
         buttons.insert(
             "button_1".to_string(),
             ButtonConfig {
+                label: None,
                 actions: button_1_actions,
+                layers: HashMap::new(),
+                layer_key: None,
                 settings: None, // Use default settings
             },
         );
@@ -188,37 +579,49 @@ impl ConfigManager {
         let mut button_2_actions = HashMap::new();
         button_2_actions.insert(
             "PRESSED".to_string(),
-            vec![ActionItem {
-                action_type: "Key".to_string(),
-                direction: Some("Press".to_string()),
-                value: Some(ActionValue::Key(Key::MicMute)),
-                auto_release: None,
+            vec![ExecutableAction::KeyPress {
+                key: Key::MicMute,
+                auto_release: true,
             }],
         );
         button_2_actions.insert(
             "HELD".to_string(),
-            vec![ActionItem {
-                action_type: "Key".to_string(),
-                direction: Some("Press".to_string()),
-                value: Some(ActionValue::Key(Key::F5)),
-                auto_release: None,
+            vec![ExecutableAction::KeyPress {
+                key: Key::F5,
+                auto_release: true,
             }],
         );
 
         buttons.insert(
             "button_2".to_string(),
             ButtonConfig {
+                label: None,
                 actions: button_2_actions,
+                layers: HashMap::new(),
+                layer_key: None,
                 settings: None, // Use default settings
             },
         );
 
         TokenBasedConfig {
+            schema: Some(Self::schema_reference()),
+            version: CONFIG_VERSION,
+            include: Vec::new(),
+            macros: HashMap::new(),
             device: DeviceConfig {
+                vendor_id: None,
+                product_id: None,
+                serial_number: None,
+                hidraw: None,
+                decoder: None,
                 button_count: 3,
                 buttons,
+                chords: HashMap::new(),
                 settings: None,
             },
+            devices: Vec::new(),
+            profiles: Vec::new(),
+            included_files: Vec::new(),
         }
     }
 }
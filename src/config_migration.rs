@@ -0,0 +1,220 @@
+use crate::config_manager::ConfigFormat;
+#[cfg(not(feature = "yaml"))]
+use crate::config_manager::YAML_UNAVAILABLE;
+use crate::config_validation::action_lists;
+use crate::token_based_config::{CONFIG_VERSION, ConfigPath, PathSegment, migrated_action};
+use serde::Serialize;
+use serde_json::Value;
+use toml_edit::{DocumentMut, Item, TableLike};
+
+/// Rewrite a v3 config file in the current format. Only the v3 actions are rewritten and
+/// `version` is set, with `$schema` set to `schema` when given, so keys keep their order. TOML
+/// files keep their comments, except inside rewritten actions.
+/// Returns the new contents and the number of rewritten actions.
+pub fn migrate_document(
+    format: ConfigFormat,
+    source: &str,
+    schema: Option<&str>,
+) -> Result<(String, usize), Box<dyn std::error::Error>> {
+    let mut document: Value = format.parse(source)?;
+    let mut migrated = Vec::new();
+    for (path, actions) in action_lists(&mut document) {
+        for (i, action) in actions.iter_mut().enumerate() {
+            if let Some(new_action) = migrated_action(action)
+                && new_action != *action
+            {
+                *action = new_action.clone();
+                migrated.push((path.index(i), new_action));
+            }
+        }
+    }
+
+    let contents = match format {
+        ConfigFormat::Toml => migrate_toml(source, &migrated, schema)?,
+        ConfigFormat::Json => serde_json::to_string_pretty(&with_version(document, schema))?,
+        #[cfg(feature = "yaml")]
+        ConfigFormat::Yaml => serde_yaml::to_string(&with_version(document, schema))?,
+        #[cfg(not(feature = "yaml"))]
+        ConfigFormat::Yaml => return Err(YAML_UNAVAILABLE.into()),
+    };
+    Ok((contents, migrated.len()))
+}
+
+/// The document with `$schema` and `version` in front of the other keys
+fn with_version(document: Value, schema: Option<&str>) -> Value {
+    let mut root = serde_json::Map::new();
+    if let Some(schema) = schema {
+        root.insert("$schema".to_string(), schema.into());
+    }
+    root.insert("version".to_string(), CONFIG_VERSION.into());
+    if let Value::Object(members) = document {
+        for (key, value) in members {
+            root.entry(key).or_insert(value);
+        }
+    }
+    Value::Object(root)
+}
+
+fn migrate_toml(
+    source: &str,
+    migrated: &[(ConfigPath, Value)],
+    schema: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut document: DocumentMut = source.parse()?;
+    for (path, action) in migrated {
+        let action = action.serialize(toml_edit::ser::ValueSerializer::new())?;
+        let toml_edit::Value::InlineTable(action) = action else {
+            return Err(format!("{path}: action is not a table").into());
+        };
+        replace_toml_action(&mut document, path.segments(), action)
+            .ok_or_else(|| format!("{path}: action not found"))?;
+    }
+
+    let root = document.as_table_mut();
+    if let Some(schema) = schema {
+        root.insert("$schema", toml_edit::value(schema));
+    }
+    root.insert("version", toml_edit::value(i64::from(CONFIG_VERSION)));
+    root.sort_values_by(|key, _, other, _| rank(key).cmp(&rank(other)));
+    Ok(document.to_string())
+}
+
+/// Order of the top-level keys: `$schema` and `version` first, the others as they were
+fn rank(key: &toml_edit::Key) -> u8 {
+    match key.get() {
+        "$schema" => 0,
+        "version" => 1,
+        _ => 2,
+    }
+}
+
+/// Replace the action at `path` with `action`, keeping the comments before it
+fn replace_toml_action(
+    document: &mut DocumentMut,
+    path: &[PathSegment],
+    action: toml_edit::InlineTable,
+) -> Option<()> {
+    let (PathSegment::Index(index), list_path) = path.split_last()? else {
+        return None;
+    };
+    let (PathSegment::Key(list_key), table_path) = list_path.split_last()? else {
+        return None;
+    };
+    let list = toml_table(document.as_table_mut(), table_path)?.get_mut(list_key)?;
+
+    match list {
+        Item::ArrayOfTables(tables) => {
+            let old = tables.get_mut(*index)?;
+            let decor = old.decor().clone();
+            *old = action.into_table();
+            *old.decor_mut() = decor;
+        }
+        Item::Value(toml_edit::Value::Array(values)) => {
+            let old = values.get_mut(*index)?;
+            let decor = old.decor().clone();
+            *old = toml_edit::Value::InlineTable(action);
+            *old.decor_mut() = decor;
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// The table at `path`, below `table`
+fn toml_table<'a>(
+    table: &'a mut dyn TableLike,
+    path: &[PathSegment],
+) -> Option<&'a mut dyn TableLike> {
+    let Some((PathSegment::Key(key), rest)) = path.split_first() else {
+        return path.is_empty().then_some(table);
+    };
+    let item = table.get_mut(key)?;
+    match rest.split_first() {
+        Some((PathSegment::Index(i), rest)) => {
+            let entry: &mut dyn TableLike = match item {
+                Item::ArrayOfTables(tables) => tables.get_mut(*i)?,
+                Item::Value(toml_edit::Value::Array(values)) => {
+                    values.get_mut(*i)?.as_inline_table_mut()?
+                }
+                _ => return None,
+            };
+            toml_table(entry, rest)
+        }
+        _ => toml_table(item.as_table_like_mut()?, rest),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_keeps_keys_and_their_order() {
+        let source = r#"{"device": {"button_count": 3, "zeta": 1, "buttons": {
+            "button_2": {"actions": {"PRESSED": [{"type": "Key", "value": "F5"}]}},
+            "button_0": {"actions": {"PRESSED": [{"type": "ReleaseAll"}]}}}}}"#;
+        let (migrated, rewritten) =
+            migrate_document(ConfigFormat::Json, source, Some("./schema.json")).unwrap();
+        assert_eq!(rewritten, 1);
+
+        let document: Value = serde_json::from_str(&migrated).unwrap();
+        let keys =
+            |value: &Value| -> Vec<String> { value.as_object().unwrap().keys().cloned().collect() };
+        assert_eq!(keys(&document), ["$schema", "version", "device"]);
+        assert_eq!(
+            keys(&document["device"]),
+            ["button_count", "zeta", "buttons"]
+        );
+        assert_eq!(
+            keys(&document["device"]["buttons"]),
+            ["button_2", "button_0"]
+        );
+        assert_eq!(
+            document["device"]["buttons"]["button_2"]["actions"]["PRESSED"][0],
+            serde_json::json!({"type": "KeyPress", "key": "F5", "auto_release": true})
+        );
+    }
+
+    #[test]
+    fn toml_keeps_comments_outside_rewritten_actions() {
+        let source = r#"# My pedal
+[device]
+button_count = 3 # three pedals
+
+# copy
+[[device.buttons.button_0.actions.PRESSED]]
+type = "Key"
+value = "Control"
+
+[device.buttons.button_1.actions]
+PRESSED = [
+  # greeting
+  { type = "Text", value = "hi" },
+  { type = "Sleep", duration_ms = 10 }, # unchanged
+]
+"#;
+        let (migrated, rewritten) = migrate_document(ConfigFormat::Toml, source, None).unwrap();
+        assert_eq!(rewritten, 2);
+        assert_eq!(
+            migrated,
+            r#"version = 4
+# My pedal
+[device]
+button_count = 3 # three pedals
+
+# copy
+[[device.buttons.button_0.actions.PRESSED]]
+type = "KeyPress"
+key = "Control"
+auto_release = true
+
+[device.buttons.button_1.actions]
+PRESSED = [
+  # greeting
+  { type = "Text", text = "hi" },
+  { type = "Sleep", duration_ms = 10 }, # unchanged
+]
+"#
+        );
+    }
+}
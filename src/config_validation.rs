@@ -0,0 +1,311 @@
+use crate::config_manager::ConfigFormat;
+#[cfg(not(feature = "yaml"))]
+use crate::config_manager::YAML_UNAVAILABLE;
+use crate::token_based_config::{
+    CONFIG_VERSION, ConfigPath, ConfigProblem, DocumentVersion, ExecutableAction, PathSegment,
+    TokenBasedConfig, TokenBasedParser, unknown_action_keys, with_document_version,
+};
+use serde::de::{DeserializeSeed, Error as _, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use serde_json::{Value, json};
+use std::path::Path;
+
+/// Check a config file and print every problem with its path and line/column.
+/// Returns false when the file cannot be used.
+pub fn run_validate(config_path: &Path) -> bool {
+    let location = config_path.display();
+    let format = match ConfigFormat::from_path(config_path) {
+        Ok(format) => format,
+        Err(e) => {
+            eprintln!("❌ {e}");
+            return false;
+        }
+    };
+    let source = match std::fs::read_to_string(config_path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("❌ {location}: cannot read file: {e}");
+            return false;
+        }
+    };
+
+    let mut document = match parse_document(format, &source) {
+        Ok(document) => document,
+        Err(((line, column), message)) => {
+            eprintln!("❌ {location}:{line}:{column}: {message}");
+            return false;
+        }
+    };
+
+    // A malformed version is reported by the full read below, until then actions are strict
+    let version =
+        DocumentVersion::deserialize(&document).map_or(CONFIG_VERSION, |found| found.version);
+    let ((mut problems, warnings), config) = with_document_version(version, || {
+        let checked = check_actions(&mut document);
+        (
+            checked,
+            serde_path_to_error::deserialize::<_, TokenBasedConfig>(document),
+        )
+    });
+    match config {
+        Ok(config) => {
+            problems.extend(config.problems());
+            if let Err(problem) = TokenBasedParser::resolve_config(config, config_path) {
+                problems.push(problem);
+            }
+        }
+        Err(e) => problems.push(ConfigProblem::new(
+            config_path_of(e.path()),
+            e.inner().to_string(),
+        )),
+    }
+    for warning in &warnings {
+        let (line, column) = locate(format, &source, &warning.path);
+        eprintln!("⚠️  {location}:{line}:{column}: {warning}");
+    }
+    for problem in &problems {
+        let (line, column) = locate(format, &source, &problem.path);
+        eprintln!("❌ {location}:{line}:{column}: {problem}");
+    }
+
+    if problems.is_empty() && warnings.is_empty() {
+        println!("✅ {location} is valid");
+        true
+    } else if problems.is_empty() {
+        println!("✅ {location} is valid, with {} warning(s)", warnings.len());
+        true
+    } else {
+        eprintln!("\n{} problem(s) found in {location}", problems.len());
+        false
+    }
+}
+
+/// Parse error with its line and column
+type ParseError = ((usize, usize), String);
+
+/// Read the file into a generic document, reporting syntax errors where the parser found them
+fn parse_document(format: ConfigFormat, source: &str) -> Result<Value, ParseError> {
+    match format {
+        ConfigFormat::Json => serde_json::from_str(source).map_err(|e| {
+            let position = (e.line(), e.column());
+            (
+                position,
+                format!("invalid JSON: {}", without_position(&e, position)),
+            )
+        }),
+        ConfigFormat::Toml => toml::from_str(source).map_err(|e: toml::de::Error| {
+            let offset = e.span().map_or(0, |span| span.start);
+            (
+                line_column(source, offset),
+                e.message().trim_end().to_string(),
+            )
+        }),
+        #[cfg(not(feature = "yaml"))]
+        ConfigFormat::Yaml => Err(((1, 1), YAML_UNAVAILABLE.to_string())),
+        #[cfg(feature = "yaml")]
+        ConfigFormat::Yaml => serde_yaml::from_str(source).map_err(|e: serde_yaml::Error| {
+            let position = e
+                .location()
+                .map_or((1, 1), |location| (location.line(), location.column()));
+            (position, without_position(&e, position))
+        }),
+    }
+}
+
+/// Error message without the " at line L column C" that serde_json and serde_yaml append
+fn without_position(error: &impl std::fmt::Display, (line, column): (usize, usize)) -> String {
+    let message = error.to_string();
+    let suffix = format!(" at line {line} column {column}");
+    message
+        .strip_suffix(&suffix)
+        .unwrap_or(&message)
+        .to_string()
+}
+
+/// Check every action on its own, so that all malformed actions are reported instead of
+/// only the first one. Each is replaced by a no-op, so the rest of the file can still be read.
+/// Returns the problems, and warnings for keys that v3 action types ignore.
+fn check_actions(document: &mut Value) -> (Vec<ConfigProblem>, Vec<ConfigProblem>) {
+    let mut problems = Vec::new();
+    let mut warnings = Vec::new();
+    for (path, actions) in action_lists(document) {
+        for (i, action) in actions.iter_mut().enumerate() {
+            for key in unknown_action_keys(action) {
+                warnings.push(ConfigProblem::new(
+                    path.index(i).key(key),
+                    format!("unknown key \"{key}\" is ignored"),
+                ));
+            }
+            if let Err(e) = ExecutableAction::deserialize(&*action) {
+                problems.push(ConfigProblem::new(path.index(i), e.to_string()));
+                *action = json!({ "type": "Sleep", "duration_ms": 0 });
+            }
+        }
+    }
+    (problems, warnings)
+}
+
+/// Every action list in the document: the macros, and the base and layer actions of
+/// every button and chord in the device blocks and the profiles
+pub fn action_lists(document: &mut Value) -> Vec<(ConfigPath, &mut Vec<Value>)> {
+    let root = ConfigPath::default();
+    let mut lists = Vec::new();
+    let mut blocks = Vec::new();
+    for (key, value) in members(document) {
+        let path = root.key(key);
+        match (key.as_str(), value) {
+            ("device", block) => blocks.push((path, block)),
+            ("devices" | "profiles", Value::Array(entries)) => blocks.extend(
+                entries
+                    .iter_mut()
+                    .enumerate()
+                    .map(|(i, block)| (path.index(i), block)),
+            ),
+            ("macros", macros) => lists.extend(event_lists(&path, macros)),
+            _ => {}
+        }
+    }
+
+    for (block, value) in blocks {
+        for (map, buttons) in members(value) {
+            if map != "buttons" && map != "chords" {
+                continue;
+            }
+            for (key, button) in members(buttons) {
+                let button_path = block.key(map).key(key);
+                for (field, value) in members(button) {
+                    match field.as_str() {
+                        "actions" => lists.extend(event_lists(&button_path.key(field), value)),
+                        "layers" => {
+                            for (layer, events) in members(value) {
+                                let layer_path = button_path.key(field).key(layer);
+                                lists.extend(event_lists(&layer_path, events));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    lists
+}
+
+/// Members of an object, none for any other value
+fn members(value: &mut Value) -> impl Iterator<Item = (&String, &mut Value)> {
+    value
+        .as_object_mut()
+        .into_iter()
+        .flat_map(|object| object.iter_mut())
+}
+
+/// The action lists of an object keyed by event or macro name
+fn event_lists<'a>(
+    path: &ConfigPath,
+    events: &'a mut Value,
+) -> Vec<(ConfigPath, &'a mut Vec<Value>)> {
+    members(events)
+        .filter_map(|(name, actions)| Some((path.key(name), actions.as_array_mut()?)))
+        .collect()
+}
+
+fn config_path_of(path: &serde_path_to_error::Path) -> ConfigPath {
+    path.iter()
+        .fold(ConfigPath::default(), |path, segment| match segment {
+            serde_path_to_error::Segment::Seq { index } => path.index(*index),
+            serde_path_to_error::Segment::Map { key } => path.key(key),
+            _ => path,
+        })
+}
+
+/// Line and column (both 1-based) of a byte offset
+fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+    (line, column)
+}
+
+/// Line and column of the value at `path`, or of the deepest part of the path that exists
+fn locate(format: ConfigFormat, source: &str, path: &ConfigPath) -> (usize, usize) {
+    let segments = path.segments();
+    (0..=segments.len())
+        .rev()
+        .find_map(|depth| position_of(format, source, &segments[..depth]))
+        .unwrap_or((1, 1))
+}
+
+/// Let the parser read the document down to `path` and fail there, so that its error
+/// carries the position of that value. `None` when the path does not exist.
+fn position_of(format: ConfigFormat, source: &str, path: &[PathSegment]) -> Option<(usize, usize)> {
+    let seed = Locate { path };
+    match format {
+        ConfigFormat::Json => {
+            let mut deserializer = serde_json::Deserializer::from_str(source);
+            let error = seed.deserialize(&mut deserializer).err()?;
+            Some((error.line(), error.column()))
+        }
+        ConfigFormat::Toml => {
+            let error = seed.deserialize(toml::Deserializer::new(source)).err()?;
+            Some(line_column(source, error.span()?.start))
+        }
+        #[cfg(not(feature = "yaml"))]
+        ConfigFormat::Yaml => None,
+        #[cfg(feature = "yaml")]
+        ConfigFormat::Yaml => {
+            let error = seed
+                .deserialize(serde_yaml::Deserializer::from_str(source))
+                .err()?;
+            let location = error.location()?;
+            Some((location.line(), location.column()))
+        }
+    }
+}
+
+/// Walks the document along a path and fails at the value it leads to. A scalar on the
+/// way fails too, which locates the deepest part of the path that exists.
+struct Locate<'a> {
+    path: &'a [PathSegment],
+}
+
+impl<'de> DeserializeSeed<'de> for Locate<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Locate<'_> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("an object or array")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let Some((PathSegment::Key(wanted), rest)) = self.path.split_first() else {
+            return Err(A::Error::custom("found"));
+        };
+        while let Some(key) = map.next_key::<String>()? {
+            if key == *wanted {
+                return map.next_value_seed(Locate { path: rest });
+            }
+            map.next_value::<IgnoredAny>()?;
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let Some((PathSegment::Index(wanted), rest)) = self.path.split_first() else {
+            return Err(A::Error::custom("found"));
+        };
+        for _ in 0..*wanted {
+            if seq.next_element::<IgnoredAny>()?.is_none() {
+                return Ok(());
+            }
+        }
+        seq.next_element_seed(Locate { path: rest })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,107 @@
+use crate::device_discovery::DeviceMessage;
+use inotify::{Inotify, WatchDescriptor, WatchMask, Watches};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// File names of interest in each watched directory
+type WatchedFiles = HashMap<WatchDescriptor, HashSet<OsString>>;
+
+/// Watches the config file and the files it includes
+pub struct ConfigWatcher {
+    config_path: PathBuf,
+    watches: Watches,
+    watched: Arc<Mutex<WatchedFiles>>,
+}
+
+/// Watch the config file and `included_files` with inotify and notify the event loop
+/// whenever one of them was rewritten.
+///
+/// Parent directories are watched rather than the files themselves, because most editors
+/// save by writing a temporary file and renaming it over the original. Symlinks are
+/// followed, so editing the target of a symlinked config is noticed as well.
+pub fn spawn_config_watcher(
+    config_path: PathBuf,
+    included_files: &[PathBuf],
+    sender: Sender<DeviceMessage>,
+) -> std::io::Result<ConfigWatcher> {
+    let mut inotify = Inotify::init()?;
+    let mut watcher = ConfigWatcher {
+        config_path,
+        watches: inotify.watches(),
+        watched: Arc::default(),
+    };
+    watcher.update(included_files)?;
+    println!(
+        "👀 Watching \"{}\" for configuration changes",
+        watcher.config_path.display()
+    );
+
+    let watched = Arc::clone(&watcher.watched);
+    std::thread::Builder::new()
+        .name("config-watcher".to_string())
+        .spawn(move || {
+            let mut buffer = [0u8; 4096];
+            loop {
+                let events = match inotify.read_events_blocking(&mut buffer) {
+                    Ok(events) => events,
+                    Err(e) => {
+                        eprintln!("⚠️  Config watcher stopped, hot reload is disabled: {e}");
+                        return;
+                    }
+                };
+
+                let watched = watched.lock().unwrap_or_else(PoisonError::into_inner);
+                let config_changed = events.into_iter().any(|event| {
+                    event.name.is_some_and(|name| {
+                        watched
+                            .get(&event.wd)
+                            .is_some_and(|names| names.contains(name))
+                    })
+                });
+                drop(watched);
+                if config_changed && sender.send(DeviceMessage::ConfigChanged).is_err() {
+                    return;
+                }
+            }
+        })?;
+    Ok(watcher)
+}
+
+impl ConfigWatcher {
+    /// Watch the config file, its symlink target and `included_files`, and stop watching
+    /// directories that are no longer needed. Called again after every successful reload.
+    pub fn update(&mut self, included_files: &[PathBuf]) -> std::io::Result<()> {
+        let mut files = vec![self.config_path.clone()];
+        if let Ok(target) = self.config_path.canonicalize()
+            && target != self.config_path
+        {
+            files.push(target);
+        }
+        files.extend(included_files.iter().cloned());
+
+        let mut watched = WatchedFiles::new();
+        for file in &files {
+            let Some(name) = file.file_name() else {
+                continue;
+            };
+            let directory = match file.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            let wd = self
+                .watches
+                .add(directory, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)?;
+            watched.entry(wd).or_default().insert(name.to_os_string());
+        }
+
+        let mut current = self.watched.lock().unwrap_or_else(PoisonError::into_inner);
+        for wd in current.keys().filter(|wd| !watched.contains_key(*wd)) {
+            let _ = self.watches.remove(wd.clone());
+        }
+        *current = watched;
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use crate::token_based_config::{FocusedWindow, TokenBasedConfig};
+use hidapi::{DeviceInfo, HidApi, HidDevice};
+use std::sync::mpsc::Sender;
+use std::thread::JoinHandle;
+
+/// USB vendor ID of Elgato (Corsair) devices
+pub const ELGATO_VENDOR_ID: u16 = 0x0fd9;
+/// USB product ID of the Stream Deck Pedal
+pub const STREAM_DECK_PEDAL_PRODUCT_ID: u16 = 0x0086;
+
+/// Identity of a connected pedal, used to pick its configuration block
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceIdentity {
+    /// hidraw path of the open device, unique while it stays connected
+    pub path: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial_number: Option<String>,
+}
+
+impl DeviceIdentity {
+    pub fn from_device_info(info: &DeviceInfo) -> Self {
+        Self {
+            path: info.path().to_string_lossy().into_owned(),
+            vendor_id: info.vendor_id(),
+            product_id: info.product_id(),
+            serial_number: info
+                .serial_number()
+                .filter(|serial| !serial.is_empty())
+                .map(str::to_string),
+        }
+    }
+
+    /// Short name for log output: the serial number when known, otherwise the path
+    pub fn label(&self) -> &str {
+        self.serial_number.as_deref().unwrap_or(&self.path)
+    }
+}
+
+/// Messages sent to the event loop from the per-device reader threads and the
+/// focus and config watchers
+#[derive(Debug)]
+pub enum DeviceMessage {
+    Report { path: String, data: Vec<u8> },
+    Disconnected { path: String, error: String },
+    FocusChanged(FocusedWindow),
+    ConfigChanged,
+}
+
+/// List every HID device selected by the `device` or `devices` matchers in the config
+pub fn find_pedals<'a>(api: &'a HidApi, config: &TokenBasedConfig) -> Vec<&'a DeviceInfo> {
+    api.device_list()
+        .filter(|device| config.matches_any_device(&DeviceIdentity::from_device_info(device)))
+        .collect()
+}
+
+/// Print the hints shown when no pedal can be found, along with every HID
+/// device that is visible so the right matcher can be copied into the config
+pub fn report_no_pedals_found(api: &HidApi, config: &TokenBasedConfig) {
+    eprintln!("\nError:");
+    let id = |id: Option<u16>| id.map_or_else(|| "not set".to_string(), |id| format!("0x{id:04x}"));
+    eprintln!(
+        "❌ No pedal matching the configuration was found (default: Vendor ID {}, Product ID {}).",
+        id(config.device.vendor_id()),
+        id(config.device.product_id())
+    );
+    eprintln!("Please ensure:");
+    eprintln!("   - The device is connected via USB");
+    eprintln!("   - Your user has the correct permissions (input group)");
+    eprintln!("   - The device is not being used by another application");
+    eprintln!(
+        "   - \"vendor_id\", \"product_id\", \"serial_number\" and \"hidraw\" in the config match the device"
+    );
+
+    let candidates: Vec<&DeviceInfo> = api.device_list().collect();
+
+    if candidates.is_empty() {
+        eprintln!(
+            "\nNo HID devices are visible at all, check the udev rules and group membership."
+        );
+    } else {
+        eprintln!("\nCandidate HID devices:");
+        eprintln!("{}", "-".repeat(100));
+        eprintln!(
+            "| {:<14} | {:<9} | {:<20} | {:<24} | {:<16} |",
+            "hidraw", "VID:PID", "Manufacturer", "Product", "Serial"
+        );
+        eprintln!("{}", "-".repeat(100));
+
+        for device in candidates {
+            eprintln!(
+                "| {:<14} | {:04x}:{:04x} | {:<20} | {:<24} | {:<16} |",
+                device.path().to_string_lossy(),
+                device.vendor_id(),
+                device.product_id(),
+                device.manufacturer_string().unwrap_or("?"),
+                device.product_string().unwrap_or("?"),
+                device.serial_number().unwrap_or("?")
+            );
+        }
+
+        eprintln!("{}", "-".repeat(100));
+    }
+
+    eprintln!("Waiting for a matching device to appear...");
+}
+
+/// Read reports from `device` on a dedicated thread and forward them to `sender`.
+///
+/// The thread exits after the first read error (typically the pedal being
+/// unplugged), reporting it as `DeviceMessage::Disconnected`, or once the
+/// receiving side of the channel has gone away.
+pub fn spawn_reader(
+    device: HidDevice,
+    identity: DeviceIdentity,
+    sender: Sender<DeviceMessage>,
+) -> std::io::Result<JoinHandle<()>> {
+    std::thread::Builder::new()
+        .name(format!("hid-reader-{}", identity.label()))
+        .spawn(move || {
+            loop {
+                let mut buf = [0u8; 64];
+
+                match device.read_timeout(&mut buf, 142) {
+                    Ok(len) if len > 0 => {
+                        println!(
+                            "Received {} bytes from HID device {}: {:?}",
+                            len,
+                            identity.label(),
+                            &buf[..len]
+                        );
+                        let message = DeviceMessage::Report {
+                            path: identity.path.clone(),
+                            data: buf[..len].to_vec(),
+                        };
+                        if sender.send(message).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        let _ = sender.send(DeviceMessage::Disconnected {
+                            path: identity.path.clone(),
+                            error: err.to_string(),
+                        });
+                        return;
+                    }
+                }
+            }
+        })
+}
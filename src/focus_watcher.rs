@@ -0,0 +1,331 @@
+use crate::device_discovery::DeviceMessage;
+use crate::token_based_config::FocusedWindow;
+use std::sync::mpsc::{self, SendError, Sender};
+use std::thread::JoinHandle;
+
+/// Start a thread that reports the focused window to the event loop whenever it changes.
+///
+/// Wayland sessions use the wlr foreign-toplevel protocol (sway, Hyprland, river, labwc, ...)
+/// when built with the `wayland` feature; otherwise, and on X11, `_NET_ACTIVE_WINDOW` is
+/// watched on the root window. Returns `None`, after saying why, when no backend could be started.
+pub fn spawn_focus_watcher(sender: Sender<DeviceMessage>) -> Option<JoinHandle<()>> {
+    let session_type = std::env::var("XDG_SESSION_TYPE").unwrap_or_default();
+    let on_wayland = session_type == "wayland" || std::env::var("WAYLAND_DISPLAY").is_ok();
+
+    #[cfg(feature = "wayland")]
+    if on_wayland {
+        match start("focus-watcher-wayland", wayland::watch, sender.clone()) {
+            Ok(handle) => return Some(handle),
+            Err(e) => {
+                eprintln!("⚠️  Wayland focus tracking is unavailable: {e}");
+                eprintln!(
+                    "   It needs wlr-foreign-toplevel-management (sway, Hyprland, river, labwc, ...), which GNOME and KDE do not offer"
+                );
+            }
+        }
+    }
+
+    #[cfg(not(feature = "wayland"))]
+    if on_wayland {
+        eprintln!(
+            "⚠️  Built without the `wayland` feature, native Wayland windows cannot be tracked"
+        );
+    }
+
+    if std::env::var("DISPLAY").is_err() {
+        eprintln!("⚠️  No focus source available, per-application profiles are disabled");
+        return None;
+    }
+
+    match start("focus-watcher-x11", x11::watch, sender) {
+        Ok(handle) => {
+            if on_wayland {
+                eprintln!("⚠️  Profiles only switch while an XWayland window is focused");
+            }
+            Some(handle)
+        }
+        Err(e) => {
+            eprintln!("⚠️  X11 focus tracking is unavailable: {e}");
+            eprintln!("⚠️  No focus source available, per-application profiles are disabled");
+            None
+        }
+    }
+}
+
+/// Run a watcher on its own thread and wait until it has connected, so that a missing
+/// focus source is reported at startup. `watch` calls `ready` once it is connected.
+fn start(
+    name: &str,
+    watch: fn(&Sender<DeviceMessage>, &dyn Fn()) -> anyhow::Result<()>,
+    sender: Sender<DeviceMessage>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let (ready_sender, ready_receiver) = mpsc::channel();
+    let thread_name = name.to_string();
+    let handle = std::thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            let ready = || {
+                let _ = ready_sender.send(Ok(()));
+            };
+            if let Err(e) = watch(&sender, &ready)
+                && let Err(SendError(Err(e))) = ready_sender.send(Err(e))
+            {
+                // Already running, nobody waits for the result any more
+                eprintln!("⚠️  {thread_name} stopped: {e}");
+            }
+        })?;
+
+    match ready_receiver.recv() {
+        Ok(Ok(())) => Ok(handle),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(anyhow::anyhow!("the watcher stopped without connecting")),
+    }
+}
+
+/// Forward a focus change unless it is identical to the previous one.
+/// Returns false once the event loop has gone away.
+fn report(
+    sender: &Sender<DeviceMessage>,
+    last: &mut Option<FocusedWindow>,
+    window: FocusedWindow,
+) -> bool {
+    if last.as_ref() == Some(&window) {
+        return true;
+    }
+    *last = Some(window.clone());
+    sender.send(DeviceMessage::FocusChanged(window)).is_ok()
+}
+
+mod x11 {
+    use super::report;
+    use crate::device_discovery::DeviceMessage;
+    use crate::token_based_config::FocusedWindow;
+    use std::sync::mpsc::Sender;
+    use x11rb::connection::Connection;
+    use x11rb::protocol::Event;
+    use x11rb::protocol::xproto::{
+        Atom, AtomEnum, ChangeWindowAttributesAux, ConnectionExt, EventMask, Window,
+    };
+
+    struct Atoms {
+        net_active_window: Atom,
+        net_wm_name: Atom,
+        utf8_string: Atom,
+    }
+
+    pub fn watch(sender: &Sender<DeviceMessage>, ready: &dyn Fn()) -> anyhow::Result<()> {
+        let (conn, screen_num) = x11rb::connect(None)?;
+        let root = conn.setup().roots[screen_num].root;
+        let atoms = Atoms {
+            net_active_window: conn
+                .intern_atom(false, b"_NET_ACTIVE_WINDOW")?
+                .reply()?
+                .atom,
+            net_wm_name: conn.intern_atom(false, b"_NET_WM_NAME")?.reply()?.atom,
+            utf8_string: conn.intern_atom(false, b"UTF8_STRING")?.reply()?.atom,
+        };
+
+        let property_changes =
+            ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE);
+        conn.change_window_attributes(root, &property_changes)?;
+        conn.flush()?;
+        println!("👀 Watching the focused window through X11 _NET_ACTIVE_WINDOW");
+        ready();
+
+        let mut last = None;
+        let mut active = active_window(&conn, root, &atoms)?;
+        loop {
+            if let Some(window) = active {
+                // Also follow title changes of the focused window, e.g. switching browser tabs
+                let _ = conn.change_window_attributes(window, &property_changes);
+                conn.flush()?;
+                if !report(sender, &mut last, describe(&conn, window, &atoms)) {
+                    return Ok(());
+                }
+            }
+
+            loop {
+                if let Event::PropertyNotify(event) = conn.wait_for_event()? {
+                    if event.window == root && event.atom == atoms.net_active_window {
+                        active = active_window(&conn, root, &atoms)?;
+                        break;
+                    }
+                    if Some(event.window) == active
+                        && (event.atom == atoms.net_wm_name
+                            || event.atom == u32::from(AtomEnum::WM_NAME))
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    fn active_window(
+        conn: &impl Connection,
+        root: Window,
+        atoms: &Atoms,
+    ) -> anyhow::Result<Option<Window>> {
+        let reply = conn
+            .get_property(false, root, atoms.net_active_window, AtomEnum::WINDOW, 0, 1)?
+            .reply()?;
+        Ok(reply
+            .value32()
+            .and_then(|mut values| values.next())
+            .filter(|window| *window != x11rb::NONE))
+    }
+
+    fn describe(conn: &impl Connection, window: Window, atoms: &Atoms) -> FocusedWindow {
+        let property = |atom: Atom, kind: Atom| {
+            conn.get_property(false, window, atom, kind, 0, 1024)
+                .ok()
+                .and_then(|cookie| cookie.reply().ok())
+                .map(|reply| reply.value)
+                .unwrap_or_default()
+        };
+
+        // WM_CLASS holds the instance and class names, NUL separated
+        let wm_class = property(AtomEnum::WM_CLASS.into(), AtomEnum::STRING.into());
+        let class = wm_class
+            .split(|byte| *byte == 0)
+            .filter(|part| !part.is_empty())
+            .nth(1)
+            .map(|class| String::from_utf8_lossy(class).into_owned())
+            .unwrap_or_default();
+
+        let mut title = property(atoms.net_wm_name, atoms.utf8_string);
+        if title.is_empty() {
+            title = property(AtomEnum::WM_NAME.into(), AtomEnum::STRING.into());
+        }
+
+        FocusedWindow {
+            class,
+            title: String::from_utf8_lossy(&title).into_owned(),
+        }
+    }
+}
+
+#[cfg(feature = "wayland")]
+mod wayland {
+    use super::report;
+    use crate::device_discovery::DeviceMessage;
+    use crate::token_based_config::FocusedWindow;
+    use std::collections::HashMap;
+    use std::sync::mpsc::Sender;
+    use wayland_client::backend::ObjectId;
+    use wayland_client::globals::{GlobalListContents, registry_queue_init};
+    use wayland_client::protocol::wl_registry::WlRegistry;
+    use wayland_client::{Connection, Dispatch, Proxy, QueueHandle, event_created_child};
+    use wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_handle_v1::{
+        self, ZwlrForeignToplevelHandleV1,
+    };
+    use wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_manager_v1::{
+        self, ZwlrForeignToplevelManagerV1,
+    };
+
+    #[derive(Default)]
+    struct Toplevel {
+        window: FocusedWindow,
+        activated: bool,
+    }
+
+    struct FocusState {
+        sender: Sender<DeviceMessage>,
+        toplevels: HashMap<ObjectId, Toplevel>,
+        last: Option<FocusedWindow>,
+        closed: bool,
+    }
+
+    pub fn watch(sender: &Sender<DeviceMessage>, ready: &dyn Fn()) -> anyhow::Result<()> {
+        let conn = Connection::connect_to_env()?;
+        let (globals, mut queue) = registry_queue_init::<FocusState>(&conn)?;
+        let qh = queue.handle();
+        let _manager: ZwlrForeignToplevelManagerV1 = globals.bind(&qh, 1..=3, ())?;
+        println!("👀 Watching the focused window through wlr-foreign-toplevel-management");
+        ready();
+
+        let mut state = FocusState {
+            sender: sender.clone(),
+            toplevels: HashMap::new(),
+            last: None,
+            closed: false,
+        };
+        while !state.closed {
+            queue.blocking_dispatch(&mut state)?;
+        }
+        Ok(())
+    }
+
+    impl Dispatch<WlRegistry, GlobalListContents> for FocusState {
+        fn event(
+            _: &mut Self,
+            _: &WlRegistry,
+            _: <WlRegistry as Proxy>::Event,
+            _: &GlobalListContents,
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+        }
+    }
+
+    impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for FocusState {
+        fn event(
+            state: &mut Self,
+            _: &ZwlrForeignToplevelManagerV1,
+            event: zwlr_foreign_toplevel_manager_v1::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+            match event {
+                zwlr_foreign_toplevel_manager_v1::Event::Toplevel { toplevel } => {
+                    state.toplevels.insert(toplevel.id(), Toplevel::default());
+                }
+                zwlr_foreign_toplevel_manager_v1::Event::Finished => state.closed = true,
+                _ => {}
+            }
+        }
+
+        event_created_child!(FocusState, ZwlrForeignToplevelManagerV1, [
+            zwlr_foreign_toplevel_manager_v1::EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ()),
+        ]);
+    }
+
+    impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for FocusState {
+        fn event(
+            state: &mut Self,
+            handle: &ZwlrForeignToplevelHandleV1,
+            event: zwlr_foreign_toplevel_handle_v1::Event,
+            _: &(),
+            _: &Connection,
+            _: &QueueHandle<Self>,
+        ) {
+            let toplevel = state.toplevels.entry(handle.id()).or_default();
+            match event {
+                zwlr_foreign_toplevel_handle_v1::Event::Title { title } => {
+                    toplevel.window.title = title
+                }
+                zwlr_foreign_toplevel_handle_v1::Event::AppId { app_id } => {
+                    toplevel.window.class = app_id
+                }
+                zwlr_foreign_toplevel_handle_v1::Event::State { state: states } => {
+                    let activated = zwlr_foreign_toplevel_handle_v1::State::Activated as u32;
+                    toplevel.activated = states.chunks_exact(4).any(|entry| {
+                        u32::from_ne_bytes([entry[0], entry[1], entry[2], entry[3]]) == activated
+                    });
+                }
+                zwlr_foreign_toplevel_handle_v1::Event::Done if toplevel.activated => {
+                    let window = toplevel.window.clone();
+                    if !report(&state.sender, &mut state.last, window) {
+                        state.closed = true;
+                    }
+                }
+                zwlr_foreign_toplevel_handle_v1::Event::Closed => {
+                    state.toplevels.remove(&handle.id());
+                    handle.destroy();
+                }
+                _ => {}
+            }
+        }
+    }
+}
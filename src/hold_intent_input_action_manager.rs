@@ -1,39 +1,69 @@
+use crate::AppConfig;
+use crate::action_executor::ActionExecutor;
 use crate::button_types::{ButtonEvent, ButtonEventType};
 use crate::config_manager::ConfigManager;
+use crate::device_discovery::DeviceIdentity;
 use crate::hold_intent_parser::HoldIntentParser;
-use crate::input_simulator::InputSimulator;
-use crate::token_based_config::TokenBasedParser;
+use crate::token_based_config::{InputBackendKind, TokenBasedParser};
 use anyhow::{Context, Result, anyhow};
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 pub struct HoldIntentInputActionManager {
-    parser: HoldIntentParser,
+    app_config: AppConfig,
+    parsers: HashMap<String, HoldIntentParser>, // One parser per connected pedal, keyed by device path
     config: Arc<Mutex<TokenBasedParser>>,
-    input_simulator: InputSimulator,
+    action_executor: ActionExecutor,
 }
 
 impl HoldIntentInputActionManager {
-    pub fn new(global_default_threshold_ms: u64) -> Result<Self> {
-        let parser = HoldIntentParser::new(global_default_threshold_ms)
-            .context("Failed to create HoldIntentParser.")?;
+    /// `input_backend` overrides the backend chosen in the config's device settings
+    pub fn new(app_config: &AppConfig, input_backend: Option<InputBackendKind>) -> Result<Self> {
         let config_manager = ConfigManager::global();
         let config = config_manager.get_parser();
-        let input_simulator = InputSimulator::new().context("Failed to create InputSimulator.")?;
+        let input_backend = match input_backend {
+            Some(backend) => Some(backend),
+            None => config
+                .lock()
+                .map_err(|e| anyhow!("Failed to lock config: {}", e))?
+                .config()
+                .device
+                .settings
+                .as_ref()
+                .and_then(|settings| settings.input_backend),
+        };
+        let action_executor =
+            ActionExecutor::start(input_backend).context("Failed to create InputSimulator.")?;
 
         Ok(HoldIntentInputActionManager {
-            parser,
+            app_config: app_config.clone(),
+            parsers: HashMap::new(),
             config,
-            input_simulator,
+            action_executor,
         })
     }
 
-    pub fn process_hid_data(&mut self, data: &[u8]) -> Result<()> {
+    /// Start tracking a newly connected pedal with fresh button state
+    pub fn add_device(&mut self, device: DeviceIdentity) -> Result<()> {
+        let parser = HoldIntentParser::new(&self.app_config, device.clone())
+            .context("Failed to create HoldIntentParser.")?;
+        self.parsers.insert(device.path, parser);
+        Ok(())
+    }
+
+    pub fn process_hid_data(&mut self, device_path: &str, data: &[u8]) -> Result<()> {
         let now = Instant::now();
 
+        let parser = self
+            .parsers
+            .get_mut(device_path)
+            .ok_or_else(|| anyhow!("Received data for unknown device {}", device_path))?;
+        let device = parser.device().clone();
+
         // Collect events first to avoid borrowing issues
         let mut events = Vec::new();
-        self.parser
+        parser
             .parse_hid_data(data, now, |event| {
                 events.push(event);
             })
@@ -41,7 +71,7 @@ impl HoldIntentInputActionManager {
 
         // Then process the collected events
         for event in events {
-            if let Err(e) = self.handle_button_event(event) {
+            if let Err(e) = self.handle_button_event(&device, event) {
                 eprintln!("Error handling button event: {e}");
             }
         }
@@ -49,30 +79,24 @@ impl HoldIntentInputActionManager {
         Ok(())
     }
 
-    /// Process any pending timer-based events (scheduled releases, timeouts, etc.)
-    /// This should be called regularly even when no HID data is received
-    pub fn process_timers(&mut self) -> Result<()> {
-        self.input_simulator
-            .process_scheduled_releases()
-            .context("Failed to process scheduled releases.")?;
-        Ok(())
-    }
-
     /// Process button timeout events (evaluation windows, hold thresholds, etc.)
     /// This should be called regularly to handle state machine timeouts
     pub fn process_button_timeouts(&mut self) -> Result<()> {
         let now = std::time::Instant::now();
 
         let mut events = Vec::new();
-        self.parser
-            .process_button_timeouts(now, |event| {
-                events.push(event);
-            })
-            .context("Failed to process button timeouts.")?;
+        for parser in self.parsers.values_mut() {
+            let device = parser.device().clone();
+            parser
+                .process_button_timeouts(now, |event| {
+                    events.push((device.clone(), event));
+                })
+                .context("Failed to process button timeouts.")?;
+        }
 
         // Then process the collected events
-        for event in events {
-            if let Err(e) = self.handle_button_event(event) {
+        for (device, event) in events {
+            if let Err(e) = self.handle_button_event(&device, event) {
                 eprintln!("Error handling timeout event: {e}");
             }
         }
@@ -80,30 +104,104 @@ impl HoldIntentInputActionManager {
         Ok(())
     }
 
-    fn handle_button_event(&mut self, event: ButtonEvent) -> Result<()> {
-        println!(
-            "Button {} event: {} -> executing actions",
-            event.button_name.as_str(),
-            event.event_type.as_str()
-        );
+    /// How long the event loop may wait for HID data before timers have to run again.
+    /// Auto-repeat and chord detection need a finer resolution than the idle poll.
+    pub fn timer_poll_interval(&self) -> Duration {
+        if self
+            .parsers
+            .values()
+            .any(HoldIntentParser::has_pending_timers)
+        {
+            Duration::from_millis(10)
+        } else {
+            Duration::from_millis(142)
+        }
+    }
 
+    /// Refresh per-pedal settings that are cached outside the shared config after a reload
+    pub fn reload_device_configs(&mut self) -> Result<()> {
+        for parser in self.parsers.values_mut() {
+            parser
+                .reload_device_config()
+                .context("Failed to apply reloaded config to a pedal.")?;
+        }
+        Ok(())
+    }
+
+    /// Whether no button on any pedal is in the middle of a press
+    pub fn is_idle(&self) -> bool {
+        !self
+            .parsers
+            .values()
+            .any(HoldIntentParser::has_pending_timers)
+    }
+
+    /// Clean up after a pedal has been unplugged or stopped responding.
+    /// Its button state machines are discarded, so a reconnect starts from IDLE.
+    /// Its running and queued actions are stopped and the keys they pressed released,
+    /// while other pedals carry on.
+    pub fn handle_device_disconnected(&mut self, device_path: &str) -> Result<()> {
+        println!("🔌 Device disconnected - releasing held keys and resetting button state");
+
+        self.parsers.remove(device_path);
+        self.action_executor
+            .release_device(device_path)
+            .context("Failed to release held keys after disconnect.")?;
+
+        Ok(())
+    }
+
+    fn handle_button_event(&mut self, device: &DeviceIdentity, event: ButtonEvent) -> Result<()> {
         let config = self
             .config
             .lock()
             .map_err(|e| anyhow!("Failed to lock config: {}", e))?;
 
+        println!(
+            "[{}] Button {} event: {} (layer: {}) -> executing actions",
+            device.label(),
+            config.get_button_label(device, event.button_name),
+            event.event_type.as_str(),
+            event.layer.as_deref().unwrap_or("base")
+        );
+
+        let layer = event.layer.as_deref();
+        let label = format!(
+            "{} {}",
+            config.get_button_label(device, event.button_name),
+            event.event_type.as_str()
+        );
+        let when_busy = config.get_when_busy(device, event.button_name);
         let actions = match event.event_type {
             ButtonEventType::PRESSED => {
-                config.get_actions_for_button_event(event.button_name, "PRESSED")
+                config.get_actions_for_button_event(device, event.button_name, "PRESSED", layer)
+            }
+            ButtonEventType::DOUBLE_PRESSED | ButtonEventType::TRIPLE_PRESSED => config
+                .get_actions_for_button_event(
+                    device,
+                    event.button_name,
+                    event.event_type.as_str(),
+                    layer,
+                ),
+            ButtonEventType::HELD => {
+                let stage_name = config.get_hold_stage_name(
+                    device,
+                    event.button_name,
+                    event.hold_stage.unwrap_or(0),
+                );
+                config.get_actions_for_button_event(device, event.button_name, &stage_name, layer)
+            }
+            ButtonEventType::REPEAT => {
+                config.get_actions_for_button_event(device, event.button_name, "REPEAT", layer)
             }
-            ButtonEventType::HELD => config.get_actions_for_button_event(event.button_name, "HELD"),
             ButtonEventType::RELEASING => {
-                println!(
-                    "Looking for RELEASING actions for {}",
-                    event.button_name.as_str()
+                println!("Looking for RELEASING actions for {}", event.button_name);
+                let releasing_actions = config.get_releasing_actions(
+                    device,
+                    event.button_name,
+                    event.hold_stage,
+                    layer,
                 );
-                let releasing_actions =
-                    config.get_actions_for_button_event(event.button_name, "RELEASING");
                 if releasing_actions.is_some() {
                     println!("✅ Found RELEASING actions!");
                 } else {
@@ -117,7 +215,7 @@ impl HoldIntentInputActionManager {
         if let Some(actions) = actions {
             println!(
                 " Button {} event: {}",
-                event.button_name.as_str(),
+                event.button_name,
                 event.event_type.as_str()
             );
             println!("> Executing {} actions", actions.len());
@@ -126,14 +224,13 @@ impl HoldIntentInputActionManager {
                 println!(" Executing action {}: {:?}", i + 1, action);
             }
 
-            match self.input_simulator.execute_actions(&actions) {
-                Ok(_) => {}
-                Err(e) => eprintln!("Failed to execute actions: {e}"),
-            }
+            self.action_executor
+                .run(&device.path, &event, label, actions, when_busy)
+                .context("Failed to queue actions.")?;
         } else {
             println!(
                 "No actions configured for button {} event {}",
-                event.button_name.as_str(),
+                event.button_name,
                 event.event_type.as_str()
             );
         }
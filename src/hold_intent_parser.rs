@@ -1,28 +1,81 @@
+use crate::AppConfig;
 use crate::button_state_machine::{ButtonStateMachine, StateMachineLogic, StateTransition};
 use crate::button_types::{ButtonEvent, ButtonEventType, ButtonInput, ButtonState};
 use crate::config_manager::ConfigManager;
+use crate::device_discovery::DeviceIdentity;
 use crate::hold_intent_state_machine::HoldIntentLogic;
-use crate::token_based_config::PhysicalButtonName;
+use crate::report_decoder::ReportDecoder;
+use crate::token_based_config::{ButtonName, ChordName, LayerMode, PhysicalButtonName};
 use std::collections::HashMap;
 use std::time::Instant;
 
+/// A recognised chord and the member buttons that are still physically down
+struct ActiveChord {
+    chord: ChordName,
+    members_down: Vec<PhysicalButtonName>,
+}
+
 pub struct HoldIntentParser {
-    state_machines: HashMap<PhysicalButtonName, ButtonStateMachine<ButtonState>>,
+    device: DeviceIdentity,
+    decoder: Box<dyn ReportDecoder>,
+    button_count: usize,
+    state_machines: HashMap<ButtonName, ButtonStateMachine<ButtonState>>,
+    pending_chord_presses: Vec<(PhysicalButtonName, Instant)>, // Chord members waiting for the rest of the chord
+    active_chords: Vec<ActiveChord>,
+    active_layers: Vec<String>, // Most recently activated layer last; the last one applies
     logic: HoldIntentLogic,
     previous_button_states: HashMap<PhysicalButtonName, bool>, // Track previous states
 }
 
 impl HoldIntentParser {
-    pub fn new(global_default_threshold_ms: u64) -> anyhow::Result<Self> {
+    pub fn new(app_config: &AppConfig, device: DeviceIdentity) -> anyhow::Result<Self> {
         let config_manager = ConfigManager::global();
         let config_parser = config_manager.get_parser();
+        let (decoder, button_count) = {
+            let config_parser = config_parser
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock config: {}", e))?;
+            let device_config = config_parser.config().device_config(&device);
+            (device_config.decoder().build(), device_config.button_count)
+        };
+        println!(
+            "Using {} report decoder for pedal {} with {} button(s)",
+            decoder.name(),
+            device.label(),
+            button_count
+        );
+
         Ok(Self {
+            decoder,
+            button_count,
+            logic: HoldIntentLogic::new(app_config, config_parser, device.clone()), // Use dynamic thresholds based on button configuration
+            device,
             state_machines: HashMap::new(),
-            logic: HoldIntentLogic::new(global_default_threshold_ms, config_parser), // Use dynamic thresholds based on button configuration
+            pending_chord_presses: Vec::new(),
+            active_chords: Vec::new(),
+            active_layers: Vec::new(),
             previous_button_states: HashMap::new(),
         })
     }
 
+    /// Pick up decoder and button count changes after the configuration was reloaded.
+    /// Only called while every button is idle, so no state machine is affected.
+    pub fn reload_device_config(&mut self) -> anyhow::Result<()> {
+        let config_parser = ConfigManager::global().get_parser();
+        let config_parser = config_parser
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock config: {}", e))?;
+        let device_config = config_parser.config().device_config(&self.device);
+        self.decoder = device_config.decoder().build();
+        self.button_count = device_config.button_count;
+        Ok(())
+    }
+
+    /// The pedal whose reports this parser handles
+    pub fn device(&self) -> &DeviceIdentity {
+        &self.device
+    }
+
     pub fn parse_hid_data<F>(
         &mut self,
         data: &[u8],
@@ -40,77 +93,232 @@ impl HoldIntentParser {
         println!("🔍 Extracted button states: {button_states:?}");
 
         for (button_name, is_pressed) in button_states {
-            let input = ButtonInput {
-                button_name,
-                is_pressed,
-            };
-
             println!(
                 "🔍 Processing input: button={}, is_pressed={}",
-                button_name.as_str(),
-                is_pressed
+                button_name, is_pressed
             );
 
-            // Get or create state machine for this button
-            let state_machine = self
-                .state_machines
-                .entry(button_name)
-                .or_insert_with(|| ButtonStateMachine::new(self.logic.initial_state()));
+            self.route_input(button_name, is_pressed, now, &mut event_handler);
+        }
 
-            // Process the input through the state machine
-            match self.logic.process_input(state_machine, input, now) {
-                StateTransition::Continue => {
-                    // No events to emit, continue processing
-                }
-                StateTransition::EmitEvents(events) => {
-                    for event in events {
-                        // Check if this is a RELEASING event, which should reset the state machine
-                        if matches!(event.event_type, ButtonEventType::RELEASING) {
-                            state_machine.reset(self.logic.initial_state());
-                        }
-                        event_handler(event);
-                    }
+        // Note: Timeout processing is now handled separately via process_button_timeouts()
+        // This avoids conflicts between HID data processing and timeout logic
+
+        Ok(())
+    }
+
+    /// Switch layers for a layer key; returns false for ordinary buttons
+    fn handle_layer_key(&mut self, button_name: PhysicalButtonName, is_pressed: bool) -> bool {
+        let Some(layer_key) = self.logic.get_layer_key(button_name) else {
+            return false;
+        };
+
+        let was_active = self.active_layers.contains(&layer_key.layer);
+        let activate = match (layer_key.mode, is_pressed) {
+            (LayerMode::Momentary, is_pressed) => is_pressed,
+            (LayerMode::Toggle, true) => !was_active,
+            (LayerMode::Toggle, false) => return true,
+        };
+
+        self.active_layers.retain(|layer| *layer != layer_key.layer);
+        if activate {
+            self.active_layers.push(layer_key.layer.clone());
+        }
+
+        let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
+        println!(
+            "[{}] 🗂️  Layer '{}' {} by {} - active layer: {}",
+            timestamp,
+            layer_key.layer,
+            if activate { "activated" } else { "deactivated" },
+            button_name,
+            self.active_layers.last().map_or("base", String::as_str)
+        );
+        self.logic
+            .set_active_layer(self.active_layers.last().cloned());
+        true
+    }
+
+    /// Send a physical transition either to its own state machine or into chord detection
+    fn route_input<F>(
+        &mut self,
+        button_name: PhysicalButtonName,
+        is_pressed: bool,
+        now: Instant,
+        event_handler: &mut F,
+    ) where
+        F: FnMut(ButtonEvent),
+    {
+        if self.handle_layer_key(button_name, is_pressed) {
+            return;
+        }
+
+        if is_pressed {
+            let chords = self.logic.get_chords();
+            if chords.iter().any(|chord| chord.contains(button_name)) {
+                println!(
+                    "🎹 Button {} is part of a chord - waiting up to {}ms for the other chord buttons",
+                    button_name,
+                    self.logic.get_chord_window_ms()
+                );
+                self.pending_chord_presses.push((button_name, now));
+                self.try_activate_chord(&chords, now, event_handler);
+                return;
+            }
+        } else {
+            // Releases of active chord members end the chord and are not seen individually
+            if let Some(position) = self
+                .active_chords
+                .iter()
+                .position(|active| active.members_down.contains(&button_name))
+            {
+                let active = &mut self.active_chords[position];
+                let first_release = active.members_down.len() as u32 == active.chord.member_count();
+                let chord = active.chord;
+                active.members_down.retain(|member| *member != button_name);
+                if active.members_down.is_empty() {
+                    self.active_chords.remove(position);
                 }
-                StateTransition::Reset => {
-                    state_machine.reset(self.logic.initial_state());
+
+                if first_release {
+                    self.feed_input(ButtonName::Chord(chord), false, now, event_handler);
                 }
+                return;
+            }
+
+            // Released before the chord window closed: it was a plain press after all
+            if self
+                .pending_chord_presses
+                .iter()
+                .any(|(pending, _)| *pending == button_name)
+            {
+                self.flush_pending_press(button_name, event_handler);
             }
         }
 
-        // Note: Timeout processing is now handled separately via process_button_timeouts()
-        // This avoids conflicts between HID data processing and timeout logic
+        self.feed_input(
+            ButtonName::Physical(button_name),
+            is_pressed,
+            now,
+            event_handler,
+        );
+    }
 
-        Ok(())
+    /// Activate the largest chord whose members are all waiting in the chord window
+    fn try_activate_chord<F>(&mut self, chords: &[ChordName], now: Instant, event_handler: &mut F)
+    where
+        F: FnMut(ButtonEvent),
+    {
+        let chord = chords
+            .iter()
+            .filter(|chord| {
+                chord.members().all(|member| {
+                    self.pending_chord_presses
+                        .iter()
+                        .any(|(pending, _)| *pending == member)
+                })
+            })
+            .max_by_key(|chord| chord.member_count())
+            .copied();
+
+        let Some(chord) = chord else {
+            return;
+        };
+
+        println!("🎹 Chord {chord} recognised - suppressing individual button events");
+        self.pending_chord_presses
+            .retain(|(pending, _)| !chord.contains(*pending));
+        self.active_chords.push(ActiveChord {
+            chord,
+            members_down: chord.members().collect(),
+        });
+        self.feed_input(ButtonName::Chord(chord), true, now, event_handler);
+    }
+
+    /// Hand a press that was held back for chord detection to its own state machine,
+    /// keeping the original press time so hold thresholds are measured correctly
+    fn flush_pending_press<F>(&mut self, button_name: PhysicalButtonName, event_handler: &mut F)
+    where
+        F: FnMut(ButtonEvent),
+    {
+        if let Some(position) = self
+            .pending_chord_presses
+            .iter()
+            .position(|(pending, _)| *pending == button_name)
+        {
+            let (_, pressed_at) = self.pending_chord_presses.remove(position);
+            self.feed_input(
+                ButtonName::Physical(button_name),
+                true,
+                pressed_at,
+                event_handler,
+            );
+        }
+    }
+
+    /// Run one input through the state machine of a button or chord
+    fn feed_input<F>(
+        &mut self,
+        button_name: ButtonName,
+        is_pressed: bool,
+        now: Instant,
+        event_handler: &mut F,
+    ) where
+        F: FnMut(ButtonEvent),
+    {
+        let input = ButtonInput {
+            button_name,
+            is_pressed,
+        };
+
+        // Get or create state machine for this button
+        let state_machine = self
+            .state_machines
+            .entry(button_name)
+            .or_insert_with(|| ButtonStateMachine::new(self.logic.initial_state()));
+
+        // Process the input through the state machine
+        match self.logic.process_input(state_machine, input, now) {
+            StateTransition::Continue => {
+                // No events to emit, continue processing
+            }
+            StateTransition::EmitEvents(events) => {
+                for event in events {
+                    // Check if this is a RELEASING event, which should reset the state machine
+                    if matches!(event.event_type, ButtonEventType::RELEASING) {
+                        state_machine.reset(self.logic.initial_state());
+                    }
+                    event_handler(event);
+                }
+            }
+            StateTransition::EmitEventsAndReset(events) => {
+                state_machine.reset(self.logic.initial_state());
+                for event in events {
+                    event_handler(event);
+                }
+            }
+            StateTransition::Reset => {
+                state_machine.reset(self.logic.initial_state());
+            }
+        }
     }
 
     fn extract_button_states(&mut self, data: &[u8]) -> Vec<(PhysicalButtonName, bool)> {
-        if data.len() < 8 {
+        let Some(inputs) = self.decoder.decode(data) else {
             return vec![];
-        }
+        };
 
         let mut button_states = vec![];
 
-        // Extract current button states from HID data
-        let current_button_0 = data[4] & 0x01 != 0;
-        let current_button_1 = data[5] & 0x01 != 0;
-        let current_button_2 = data[6] & 0x01 != 0;
-
-        // Check for state changes and generate events only on transitions
-        self.check_button_transition(
-            PhysicalButtonName::Button0,
-            current_button_0,
-            &mut button_states,
-        );
-        self.check_button_transition(
-            PhysicalButtonName::Button1,
-            current_button_1,
-            &mut button_states,
-        );
-        self.check_button_transition(
-            PhysicalButtonName::Button2,
-            current_button_2,
-            &mut button_states,
-        );
+        // Check for state changes and generate events only on transitions.
+        // Buttons beyond the configured count are ignored.
+        for input in inputs {
+            if let ButtonName::Physical(button_name) = input.button_name
+                && button_name.index < self.button_count
+            {
+                self.check_button_transition(button_name, input.is_pressed, &mut button_states);
+            }
+        }
 
         button_states
     }
@@ -131,7 +339,7 @@ impl HoldIntentParser {
         if current_state != previous_state {
             println!(
                 "🔄 Button {} state transition: {} -> {}",
-                button_name.as_str(),
+                button_name,
                 if previous_state {
                     "PRESSED"
                 } else {
@@ -155,12 +363,46 @@ impl HoldIntentParser {
     where
         F: FnMut(ButtonEvent),
     {
+        // Presses held back for chord detection become plain presses once the window closes
+        let chord_window_ms = self.logic.get_chord_window_ms();
+        let expired: Vec<PhysicalButtonName> = self
+            .pending_chord_presses
+            .iter()
+            .filter(|(_, pressed_at)| {
+                (now.duration_since(*pressed_at).as_millis() as u64) >= chord_window_ms
+            })
+            .map(|(button_name, _)| *button_name)
+            .collect();
+        for button_name in expired {
+            println!("🎹 Chord window closed for {button_name} - handling it as a single button");
+            self.flush_pending_press(button_name, &mut event_handler);
+        }
+
         let mut buttons_to_process = vec![];
+        let mut taps_to_resolve = vec![];
+        let mut held_buttons = vec![];
 
         // Collect buttons that need timeout processing
         for (&button_name, state_machine) in &self.state_machines {
-            if state_machine.state() == ButtonState::EVALUATING {
-                buttons_to_process.push(button_name);
+            match state_machine.state() {
+                ButtonState::EVALUATING => buttons_to_process.push(button_name),
+                ButtonState::AWAITING_TAP => taps_to_resolve.push(button_name),
+                ButtonState::HELD => held_buttons.push(button_name),
+                _ => {}
+            }
+        }
+
+        // Resolve tap sequences whose multi-tap window has closed
+        for button_name in taps_to_resolve {
+            if let Some(state_machine) = self.state_machines.get_mut(&button_name)
+                && let StateTransition::EmitEventsAndReset(events) =
+                    self.logic
+                        .process_tap_timeout(state_machine, &button_name, now)
+            {
+                state_machine.reset(self.logic.initial_state());
+                for event in events {
+                    event_handler(event);
+                }
             }
         }
 
@@ -170,52 +412,78 @@ impl HoldIntentParser {
                 // For timeout processing, we need to check if hold threshold has been reached
                 // without simulating a button release
                 if let Some(time_since_first) = state_machine.time_since_first_signal(now) {
-                    let config = self.logic.get_button_config(&button_name);
+                    let config = self
+                        .logic
+                        .get_button_config(&button_name, state_machine.layer());
 
-                    // Check if hold threshold has been reached and no action has been fired yet
+                    // Check if hold threshold has been reached and no action has been fired yet.
+                    // Only the first press can become a hold; later taps of a multi-tap are
+                    // resolved on release. Auto-repeat also starts after an immediate PRESSED.
                     if (time_since_first.as_millis() as u64) >= config.threshold_ms
-                        && !state_machine.action_fired()
+                        && (!state_machine.action_fired() || config.has_repeat_action)
+                        && state_machine.signal_count() <= 1
                     {
                         let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
                         println!(
                             "[{}] ⏰ Hold threshold reached for {} ({}ms elapsed >= {}ms threshold, action_fired={})",
                             timestamp,
-                            button_name.as_str(),
+                            button_name,
                             time_since_first.as_millis(),
                             config.threshold_ms,
                             state_machine.action_fired()
                         );
 
-                        if config.has_held_action {
+                        if config.has_hold_behaviour() {
                             if config.has_held_action && !config.has_pressed_action {
                                 // HELD-only button: Fire HELD after threshold
                                 println!(
                                     "[{}] 🔥 HELD action for {} (HELD-only button - threshold reached)",
-                                    timestamp,
-                                    button_name.as_str()
+                                    timestamp, button_name
                                 );
                             } else if config.has_held_action && config.has_pressed_action {
                                 // PRESSED+HELD button: Fire HELD after threshold
                                 println!(
                                     "[{}] 🔥 HELD action for {} (PRESSED+HELD button - threshold reached)",
-                                    timestamp,
-                                    button_name.as_str()
+                                    timestamp, button_name
                                 );
                             }
 
                             // Mark that we've fired the action AND transition to HELD state
                             state_machine.mark_action_fired();
                             state_machine.transition_to(ButtonState::HELD);
+                            state_machine.set_hold_stage(0);
                             println!(
                                 "[{}] 🔄 Transitioning to HELD state for {} (action fired, threshold passed)",
-                                timestamp,
-                                button_name.as_str()
+                                timestamp, button_name
                             );
 
-                            let event = ButtonEvent {
-                                button_name,
-                                event_type: ButtonEventType::HELD,
-                            };
+                            if config.has_held_action {
+                                let event = ButtonEvent {
+                                    button_name,
+                                    event_type: ButtonEventType::HELD,
+                                    hold_stage: Some(0),
+                                    layer: state_machine.layer().map(str::to_string),
+                                };
+                                event_handler(event);
+                            }
+                            held_buttons.push(button_name);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Fire later hold stages and auto-repeat for held buttons, including ones that
+        // just crossed the threshold
+        for button_name in held_buttons {
+            if let Some(state_machine) = self.state_machines.get_mut(&button_name) {
+                for transition in [
+                    self.logic
+                        .process_hold_stages(state_machine, &button_name, now),
+                    self.logic.process_repeat(state_machine, &button_name, now),
+                ] {
+                    if let StateTransition::EmitEvents(events) = transition {
+                        for event in events {
                             event_handler(event);
                         }
                     }
@@ -225,6 +493,15 @@ impl HoldIntentParser {
 
         Ok(())
     }
-}
 
-// Re-export the types that are still used by the action manager
+    /// Whether a timer may be due soon enough that the event loop has to poll quickly:
+    /// a button that is not idle (hold stages, auto-repeat, multi-tap) or a press
+    /// waiting for the rest of its chord
+    pub fn has_pending_timers(&self) -> bool {
+        !self.pending_chord_presses.is_empty()
+            || self
+                .state_machines
+                .values()
+                .any(|state_machine| state_machine.state() != self.logic.initial_state())
+    }
+}
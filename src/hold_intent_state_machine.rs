@@ -1,6 +1,10 @@
+use crate::AppConfig;
 use crate::button_state_machine::{ButtonStateMachine, StateMachineLogic, StateTransition};
 use crate::button_types::{ButtonEvent, ButtonEventType, ButtonInput, ButtonState};
-use crate::token_based_config::{PhysicalButtonName, TokenBasedParser};
+use crate::device_discovery::DeviceIdentity;
+use crate::token_based_config::{
+    ButtonName, ChordName, HoldStage, LayerKey, PhysicalButtonName, TokenBasedParser,
+};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
@@ -8,78 +12,383 @@ use std::time::Instant;
 #[derive(Debug, Clone)]
 pub struct ButtonConfig {
     pub has_pressed_action: bool,
+    pub has_double_pressed_action: bool,
+    pub has_triple_pressed_action: bool,
     pub has_held_action: bool,
+    pub has_repeat_action: bool,
     pub threshold_ms: u64,
+    pub multi_tap_window_ms: u64,
+    pub repeat_interval_ms: u64,
+    pub repeat_min_interval_ms: u64,
+    pub repeat_acceleration: f64,
+    pub hold_stages: Vec<HoldStage>,
+}
+
+impl ButtonConfig {
+    /// Whether PRESSED has to wait for the multi-tap window before firing
+    pub fn has_multi_tap_action(&self) -> bool {
+        self.has_double_pressed_action || self.has_triple_pressed_action
+    }
+
+    /// Number of taps after which there is nothing left to wait for
+    pub fn max_taps(&self) -> u32 {
+        if self.has_triple_pressed_action { 3 } else { 2 }
+    }
+
+    /// Whether holding the button fires HELD or a hold stage, so that a press has to
+    /// wait for the threshold before it counts as PRESSED
+    pub fn has_hold_action(&self) -> bool {
+        self.has_held_action || !self.hold_stages.is_empty()
+    }
+
+    /// Whether reaching the hold threshold does anything for this button
+    pub fn has_hold_behaviour(&self) -> bool {
+        self.has_held_action || self.has_repeat_action || !self.hold_stages.is_empty()
+    }
+
+    /// Delay before the repeat following `repeat_count` earlier repeats,
+    /// shrinking by `repeat_acceleration` each time down to `repeat_min_interval_ms`
+    pub fn repeat_interval_after(&self, repeat_count: u32) -> u64 {
+        let interval =
+            self.repeat_interval_ms as f64 * self.repeat_acceleration.powi(repeat_count as i32);
+        (interval as u64).max(self.repeat_min_interval_ms).max(1)
+    }
 }
 
 /// Hold intent detection logic
 pub struct HoldIntentLogic {
     global_default_threshold_ms: u64,
+    global_default_multi_tap_window_ms: u64,
+    global_default_chord_window_ms: u64,
+    global_default_repeat_interval_ms: u64,
     config_parser: Arc<Mutex<TokenBasedParser>>,
+    device: DeviceIdentity,
+    active_layer: Option<String>,
 }
 
 impl HoldIntentLogic {
     pub fn new(
-        global_default_threshold_ms: u64,
+        app_config: &AppConfig,
         config_parser: Arc<Mutex<TokenBasedParser>>,
+        device: DeviceIdentity,
     ) -> Self {
         Self {
-            global_default_threshold_ms,
+            global_default_threshold_ms: app_config.default_hold_threshold_ms,
+            global_default_multi_tap_window_ms: app_config.default_multi_tap_window_ms,
+            global_default_chord_window_ms: app_config.default_chord_window_ms,
+            global_default_repeat_interval_ms: app_config.default_repeat_interval_ms,
             config_parser,
+            device,
+            active_layer: None,
         }
     }
 
     /// Calculate the quick release threshold as 60% of the button's hold threshold (minimum 200ms)
-    fn get_quick_release_threshold_ms(&self, button_name: &PhysicalButtonName) -> u64 {
-        let hold_threshold = {
-            let config_parser = match self.config_parser.lock() {
-                Ok(parser) => parser,
-                Err(_) => return 200, // Default fallback
-            };
-            config_parser.get_hold_threshold_ms(*button_name, self.global_default_threshold_ms)
-        };
-        let calculated = (hold_threshold * 60) / 100;
+    fn get_quick_release_threshold_ms(&self, config: &ButtonConfig) -> u64 {
+        let calculated = (config.threshold_ms * 60) / 100;
         calculated.max(200)
     }
 
     /// Calculate the evaluation window as 120% of the button's hold threshold
-    fn get_evaluation_window_ms(&self, button_name: &PhysicalButtonName) -> u64 {
-        let hold_threshold = {
-            let config_parser = match self.config_parser.lock() {
-                Ok(parser) => parser,
-                Err(_) => return 1200, // Default fallback (120% of 1000ms)
-            };
-            config_parser.get_hold_threshold_ms(*button_name, self.global_default_threshold_ms)
-        };
-        (hold_threshold * 120) / 100
+    fn get_evaluation_window_ms(&self, config: &ButtonConfig) -> u64 {
+        (config.threshold_ms * 120) / 100
     }
 
-    pub fn get_button_config(&self, button_name: &PhysicalButtonName) -> ButtonConfig {
+    /// Set the layer that new presses resolve their actions from
+    pub fn set_active_layer(&mut self, layer: Option<String>) {
+        self.active_layer = layer;
+    }
+
+    pub fn get_button_config(&self, button_name: &ButtonName, layer: Option<&str>) -> ButtonConfig {
         let config_parser = match self.config_parser.lock() {
             Ok(parser) => parser,
             Err(_) => {
                 return ButtonConfig {
                     has_pressed_action: false,
+                    has_double_pressed_action: false,
+                    has_triple_pressed_action: false,
                     has_held_action: false,
+                    has_repeat_action: false,
                     threshold_ms: self.global_default_threshold_ms,
+                    multi_tap_window_ms: self.global_default_multi_tap_window_ms,
+                    repeat_interval_ms: self.global_default_repeat_interval_ms,
+                    repeat_min_interval_ms: self.global_default_repeat_interval_ms,
+                    repeat_acceleration: 1.0,
+                    hold_stages: Vec::new(),
                 };
             }
         };
         let has_pressed_action = config_parser
-            .get_actions_for_button_event(*button_name, "PRESSED")
+            .get_actions_for_button_event(&self.device, *button_name, "PRESSED", layer)
+            .is_some();
+        let has_double_pressed_action = config_parser
+            .get_actions_for_button_event(&self.device, *button_name, "DOUBLE_PRESSED", layer)
+            .is_some();
+        let has_triple_pressed_action = config_parser
+            .get_actions_for_button_event(&self.device, *button_name, "TRIPLE_PRESSED", layer)
             .is_some();
         let has_held_action = config_parser
-            .get_actions_for_button_event(*button_name, "HELD")
+            .get_actions_for_button_event(&self.device, *button_name, "HELD", layer)
+            .is_some();
+        let has_repeat_action = config_parser
+            .get_actions_for_button_event(&self.device, *button_name, "REPEAT", layer)
             .is_some();
 
-        // Use hierarchical threshold resolution: per-button > device > global default
-        let threshold_ms =
-            config_parser.get_hold_threshold_ms(*button_name, self.global_default_threshold_ms);
+        // Stages only count when this button binds actions to one of them, so that
+        // device-level stages leave buttons without stage bindings alone
+        let mut hold_stages = config_parser.get_hold_stages(&self.device, *button_name);
+        let has_stage_action = hold_stages.iter().any(|stage| {
+            [stage.name.clone(), format!("RELEASING_{}", stage.name)]
+                .iter()
+                .any(|event| {
+                    config_parser
+                        .get_actions_for_button_event(&self.device, *button_name, event, layer)
+                        .is_some()
+                })
+        });
+        if !has_stage_action {
+            hold_stages.clear();
+        }
+        // Use hierarchical threshold resolution: per-button > device > global default.
+        // Without a plain HELD the first hold stage takes its place, so that a release
+        // before that stage can still count as PRESSED.
+        let threshold_ms = match hold_stages.first() {
+            Some(first_stage) if !has_held_action => first_stage.threshold_ms,
+            _ => config_parser.get_hold_threshold_ms(
+                &self.device,
+                *button_name,
+                self.global_default_threshold_ms,
+            ),
+        };
+        let multi_tap_window_ms = config_parser.get_multi_tap_window_ms(
+            &self.device,
+            *button_name,
+            self.global_default_multi_tap_window_ms,
+        );
+        let repeat_interval_ms = config_parser.get_repeat_interval_ms(
+            &self.device,
+            *button_name,
+            self.global_default_repeat_interval_ms,
+        );
+        // Without acceleration the minimum only matters if someone sets it explicitly
+        let repeat_min_interval_ms =
+            config_parser.get_repeat_min_interval_ms(&self.device, *button_name, 0);
+        let repeat_acceleration = config_parser.get_repeat_acceleration(&self.device, *button_name);
 
         ButtonConfig {
             has_pressed_action,
+            has_double_pressed_action,
+            has_triple_pressed_action,
             has_held_action,
+            has_repeat_action,
             threshold_ms,
+            multi_tap_window_ms,
+            repeat_interval_ms,
+            repeat_min_interval_ms,
+            repeat_acceleration,
+            hold_stages,
+        }
+    }
+
+    /// Fire every later hold stage whose threshold has passed, in order and at most once each
+    pub fn process_hold_stages(
+        &self,
+        state_machine: &mut ButtonStateMachine<ButtonState>,
+        button_name: &ButtonName,
+        now: Instant,
+    ) -> StateTransition<ButtonEvent> {
+        let config = self.get_button_config(button_name, state_machine.layer());
+        let Some(elapsed_ms) = state_machine
+            .time_since_first_signal(now)
+            .map(|elapsed| elapsed.as_millis() as u64)
+        else {
+            return StateTransition::Continue;
+        };
+
+        let mut events = vec![];
+        let mut stage = state_machine.hold_stage().unwrap_or(0) + 1;
+        while let Some(hold_stage) = config.hold_stages.get(stage - 1)
+            && elapsed_ms >= hold_stage.threshold_ms
+        {
+            let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
+            println!(
+                "[{}] 🔥 {} for {} (hold stage {} - {}ms elapsed >= {}ms threshold)",
+                timestamp, hold_stage.name, button_name, stage, elapsed_ms, hold_stage.threshold_ms
+            );
+            state_machine.set_hold_stage(stage);
+            events.push(ButtonEvent {
+                button_name: *button_name,
+                event_type: ButtonEventType::HELD,
+                hold_stage: Some(stage),
+                layer: state_machine.layer().map(str::to_string),
+            });
+            stage += 1;
+        }
+
+        if events.is_empty() {
+            StateTransition::Continue
+        } else {
+            StateTransition::EmitEvents(events)
+        }
+    }
+
+    /// Fire REPEAT for a held button whenever its next repeat is due
+    pub fn process_repeat(
+        &self,
+        state_machine: &mut ButtonStateMachine<ButtonState>,
+        button_name: &ButtonName,
+        now: Instant,
+    ) -> StateTransition<ButtonEvent> {
+        let config = self.get_button_config(button_name, state_machine.layer());
+        if !config.has_repeat_action
+            || state_machine
+                .next_repeat_time()
+                .is_some_and(|next_repeat| next_repeat > now)
+        {
+            return StateTransition::Continue;
+        }
+
+        let interval_ms = config.repeat_interval_after(state_machine.repeat_count());
+        state_machine.record_repeat(now + std::time::Duration::from_millis(interval_ms));
+
+        let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
+        println!(
+            "[{}] 🔁 REPEAT #{} for {} - next in {}ms",
+            timestamp,
+            state_machine.repeat_count(),
+            button_name,
+            interval_ms
+        );
+
+        StateTransition::EmitEvents(vec![ButtonEvent {
+            button_name: *button_name,
+            event_type: ButtonEventType::REPEAT,
+            hold_stage: None,
+            layer: state_machine.layer().map(str::to_string),
+        }])
+    }
+
+    /// Layer key binding of a physical button
+    pub fn get_layer_key(&self, button_name: PhysicalButtonName) -> Option<LayerKey> {
+        match self.config_parser.lock() {
+            Ok(config_parser) => config_parser.get_layer_key(&self.device, button_name),
+            Err(_) => None,
+        }
+    }
+
+    /// Chords configured for this device
+    pub fn get_chords(&self) -> Vec<ChordName> {
+        match self.config_parser.lock() {
+            Ok(config_parser) => config_parser.get_chords(&self.device),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// How long chord member presses wait for the rest of the chord
+    pub fn get_chord_window_ms(&self) -> u64 {
+        match self.config_parser.lock() {
+            Ok(config_parser) => {
+                config_parser.get_chord_window_ms(&self.device, self.global_default_chord_window_ms)
+            }
+            Err(_) => self.global_default_chord_window_ms,
+        }
+    }
+
+    fn has_releasing_action(&self, button_name: &ButtonName, layer: Option<&str>) -> bool {
+        match self.config_parser.lock() {
+            Ok(config_parser) => {
+                config_parser.has_releasing_actions(&self.device, *button_name, layer)
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// A quick tap was released on a button with DOUBLE_PRESSED/TRIPLE_PRESSED actions:
+    /// either wait for the next tap, or resolve right away if the maximum was reached
+    fn handle_tap_release(
+        &self,
+        state_machine: &mut ButtonStateMachine<ButtonState>,
+        input: &ButtonInput,
+        config: &ButtonConfig,
+        now: Instant,
+    ) -> StateTransition<ButtonEvent> {
+        let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
+        state_machine.record_release(now);
+        let tap_count = state_machine.signal_count();
+
+        if tap_count >= config.max_taps() {
+            println!(
+                "[{}] 👆 Tap #{} on {} - maximum taps reached, resolving immediately",
+                timestamp, tap_count, input.button_name
+            );
+            return self.resolve_taps(state_machine, &input.button_name);
+        }
+
+        println!(
+            "[{}] 👆 Tap #{} on {} - waiting {}ms for another tap (state: EVALUATING->AWAITING_TAP)",
+            timestamp, tap_count, input.button_name, config.multi_tap_window_ms
+        );
+        state_machine.transition_to(ButtonState::AWAITING_TAP);
+        StateTransition::Continue
+    }
+
+    /// Fire the event matching the number of taps collected so far, followed by RELEASING
+    fn resolve_taps(
+        &self,
+        state_machine: &mut ButtonStateMachine<ButtonState>,
+        button_name: &ButtonName,
+    ) -> StateTransition<ButtonEvent> {
+        let event_type = ButtonEventType::for_tap_count(state_machine.signal_count());
+        let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
+        println!(
+            "[{}] ⚡ {} for {} ({} tap(s))",
+            timestamp,
+            event_type.as_str(),
+            button_name,
+            state_machine.signal_count()
+        );
+        state_machine.mark_action_fired();
+
+        let mut events = vec![ButtonEvent {
+            button_name: *button_name,
+            event_type,
+            hold_stage: None,
+            layer: state_machine.layer().map(str::to_string),
+        }];
+        if self.has_releasing_action(button_name, state_machine.layer()) {
+            events.push(ButtonEvent {
+                button_name: *button_name,
+                event_type: ButtonEventType::RELEASING,
+                hold_stage: None,
+                layer: state_machine.layer().map(str::to_string),
+            });
+        }
+
+        StateTransition::EmitEventsAndReset(events)
+    }
+
+    /// Resolve a tap sequence once the multi-tap window has passed without another press
+    pub fn process_tap_timeout(
+        &self,
+        state_machine: &mut ButtonStateMachine<ButtonState>,
+        button_name: &ButtonName,
+        now: Instant,
+    ) -> StateTransition<ButtonEvent> {
+        let config = self.get_button_config(button_name, state_machine.layer());
+
+        match state_machine.time_since_last_release(now) {
+            Some(elapsed) if (elapsed.as_millis() as u64) >= config.multi_tap_window_ms => {
+                let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
+                println!(
+                    "[{}] ⏰ Multi-tap window closed for {} ({}ms elapsed >= {}ms window)",
+                    timestamp,
+                    button_name,
+                    elapsed.as_millis(),
+                    config.multi_tap_window_ms
+                );
+                self.resolve_taps(state_machine, button_name)
+            }
+            _ => StateTransition::Continue,
         }
     }
 
@@ -93,8 +402,7 @@ impl HoldIntentLogic {
         let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
         println!(
             "[{}] 🔄 Button {} signal detected - starting intent evaluation (state: IDLE->EVALUATING)",
-            timestamp,
-            input.button_name.as_str()
+            timestamp, input.button_name
         );
 
         state_machine.transition_to(ButtonState::EVALUATING);
@@ -103,11 +411,11 @@ impl HoldIntentLogic {
         println!(
             "[{}] 🔍 Button {} config: has_pressed={}, has_held={}, threshold={}ms, evaluation_window={}ms",
             timestamp,
-            input.button_name.as_str(),
+            input.button_name,
             config.has_pressed_action,
             config.has_held_action,
             config.threshold_ms,
-            self.get_evaluation_window_ms(&input.button_name)
+            self.get_evaluation_window_ms(config)
         );
 
         if config.threshold_ms > 0 {
@@ -121,17 +429,19 @@ impl HoldIntentLogic {
             );
         }
 
-        if config.has_pressed_action && !config.has_held_action {
+        if config.has_pressed_action && !config.has_hold_action() && !config.has_multi_tap_action()
+        {
             // PRESSED-only button: Fire immediately
             println!(
                 "[{}] ⚡ Immediate PRESSED for {} (PRESSED-only button)",
-                timestamp,
-                input.button_name.as_str()
+                timestamp, input.button_name
             );
             state_machine.mark_action_fired();
             StateTransition::EmitEvents(vec![ButtonEvent {
                 button_name: input.button_name,
                 event_type: ButtonEventType::PRESSED,
+                hold_stage: None,
+                layer: state_machine.layer().map(str::to_string),
             }])
         } else {
             // For HELD-only and PRESSED+HELD buttons, wait for threshold timing
@@ -152,7 +462,7 @@ impl HoldIntentLogic {
         println!(
             "[{}] 🔄 Button {} additional signal #{} detected",
             timestamp,
-            input.button_name.as_str(),
+            input.button_name,
             state_machine.signal_count()
         );
 
@@ -164,14 +474,15 @@ impl HoldIntentLogic {
         {
             println!(
                 "[{}] 🔥 HELD event for {} (multiple signals on PRESSED+HELD button)",
-                timestamp,
-                input.button_name.as_str()
+                timestamp, input.button_name
             );
             state_machine.mark_action_fired();
             // Don't change state - let HID data drive state transitions
             return StateTransition::EmitEvents(vec![ButtonEvent {
                 button_name: input.button_name,
                 event_type: ButtonEventType::HELD,
+                hold_stage: None,
+                layer: state_machine.layer().map(str::to_string),
             }]);
         }
 
@@ -186,7 +497,11 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
         input: ButtonInput,
         now: Instant,
     ) -> StateTransition<ButtonEvent> {
-        let config = self.get_button_config(&input.button_name);
+        if state_machine.state() == ButtonState::IDLE && input.is_pressed {
+            // Bindings are fixed for the whole press, even if the layer changes meanwhile
+            state_machine.set_layer(self.active_layer.clone());
+        }
+        let config = self.get_button_config(&input.button_name, state_machine.layer());
 
         match (state_machine.state(), input.is_pressed) {
             (ButtonState::IDLE, true) => {
@@ -200,14 +515,19 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
                     let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
                     println!(
                         "[{}] 🔄 Transitioning to HELD state for {} (threshold reached, button still pressed)",
-                        timestamp,
-                        input.button_name.as_str()
+                        timestamp, input.button_name
                     );
                     state_machine.transition_to(ButtonState::HELD);
                 }
 
                 self.handle_evaluating_with_signal(state_machine, &input, &config, now)
             }
+            (ButtonState::EVALUATING, false)
+                if config.has_multi_tap_action() && !state_machine.action_fired() =>
+            {
+                // Released before HELD fired on a multi-tap button: count it as a tap
+                self.handle_tap_release(state_machine, &input, &config, now)
+            }
             (ButtonState::EVALUATING, false) => {
                 // CRITICAL: Physical button release during EVALUATING - cancel hold threshold timer!
                 let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
@@ -217,10 +537,7 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
 
                     println!(
                         "[{}] 🛑 Physical button release detected during EVALUATING for {} ({}ms elapsed < {}ms threshold)",
-                        timestamp,
-                        input.button_name.as_str(),
-                        time_elapsed_ms,
-                        config.threshold_ms
+                        timestamp, input.button_name, time_elapsed_ms, config.threshold_ms
                     );
                     println!(
                         "[{timestamp}] ❌ Cancelling hold threshold timer - HELD state now impossible"
@@ -229,17 +546,16 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
                     // Handle different button configurations for early release
                     let mut events_to_emit = vec![];
 
-                    if config.has_pressed_action && config.has_held_action {
+                    if config.has_pressed_action && config.has_hold_action() {
                         // PRESSED+HELD button: Check for quick release
-                        let quick_release_threshold =
-                            self.get_quick_release_threshold_ms(&input.button_name);
+                        let quick_release_threshold = self.get_quick_release_threshold_ms(&config);
                         if time_elapsed_ms < quick_release_threshold
                             && !state_machine.action_fired()
                         {
                             println!(
                                 "[{}] ⚡ Quick release detected for {} ({}ms elapsed < {}ms quick-release threshold) - firing PRESSED",
                                 timestamp,
-                                input.button_name.as_str(),
+                                input.button_name,
                                 time_elapsed_ms,
                                 quick_release_threshold
                             );
@@ -247,42 +563,42 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
                             events_to_emit.push(ButtonEvent {
                                 button_name: input.button_name,
                                 event_type: ButtonEventType::PRESSED,
+                                hold_stage: None,
+                                layer: state_machine.layer().map(str::to_string),
                             });
                         } else if !state_machine.action_fired() {
                             // Released too late for PRESSED, too early for HELD - no action
                             println!(
                                 "[{}] 🔄 Button {} released too late for PRESSED ({}ms > {}ms), too early for HELD ({}ms < {}ms) - no action fired",
                                 timestamp,
-                                input.button_name.as_str(),
+                                input.button_name,
                                 time_elapsed_ms,
                                 quick_release_threshold,
                                 time_elapsed_ms,
                                 config.threshold_ms
                             );
                         }
-                    } else if config.has_held_action && !config.has_pressed_action {
+                    } else if config.has_hold_action() && !config.has_pressed_action {
                         // HELD-only button: No action since threshold wasn't reached
                         if !state_machine.action_fired() {
                             println!(
                                 "[{}] 🔄 HELD-only button {} released before threshold ({}ms < {}ms) - no action fired",
-                                timestamp,
-                                input.button_name.as_str(),
-                                time_elapsed_ms,
-                                config.threshold_ms
+                                timestamp, input.button_name, time_elapsed_ms, config.threshold_ms
                             );
                         }
-                    } else if config.has_pressed_action && !config.has_held_action {
+                    } else if config.has_pressed_action && !config.has_hold_action() {
                         // PRESSED-only button: Should have fired immediately on press, but handle edge case
                         if !state_machine.action_fired() {
                             println!(
                                 "[{}] ⚡ Late PRESSED action for {} (PRESSED-only button released)",
-                                timestamp,
-                                input.button_name.as_str()
+                                timestamp, input.button_name
                             );
                             state_machine.mark_action_fired();
                             events_to_emit.push(ButtonEvent {
                                 button_name: input.button_name,
                                 event_type: ButtonEventType::PRESSED,
+                                hold_stage: None,
+                                layer: state_machine.layer().map(str::to_string),
                             });
                         }
                     }
@@ -300,7 +616,12 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
                             }
                         };
                         let result = config_parser
-                            .get_actions_for_button_event(input.button_name, "RELEASING")
+                            .get_actions_for_button_event(
+                                &self.device,
+                                input.button_name,
+                                "RELEASING",
+                                state_machine.layer(),
+                            )
                             .is_some();
                         drop(config_parser);
                         result
@@ -311,7 +632,7 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
                         println!(
                             "[{}] 🔄 Transitioning {} to RELEASING state (action was fired: {}, RELEASING configured: {})",
                             timestamp,
-                            input.button_name.as_str(),
+                            input.button_name,
                             state_machine.action_fired(),
                             has_releasing_action
                         );
@@ -322,6 +643,8 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
                             events_to_emit.push(ButtonEvent {
                                 button_name: input.button_name,
                                 event_type: ButtonEventType::RELEASING,
+                                hold_stage: None,
+                                layer: state_machine.layer().map(str::to_string),
                             });
                             return StateTransition::EmitEvents(events_to_emit);
                         } else {
@@ -329,6 +652,8 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
                             return StateTransition::EmitEvents(vec![ButtonEvent {
                                 button_name: input.button_name,
                                 event_type: ButtonEventType::RELEASING,
+                                hold_stage: None,
+                                layer: state_machine.layer().map(str::to_string),
                             }]);
                         }
                     } else {
@@ -344,42 +669,31 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
                 // Always reset to IDLE when physically released during EVALUATING
                 println!(
                     "[{}] 🔄 Resetting button {} state: EVALUATING->IDLE (physical release, timer cancelled)",
-                    timestamp,
-                    input.button_name.as_str()
+                    timestamp, input.button_name
                 );
                 StateTransition::Reset
             }
             (ButtonState::HELD, false) => {
                 let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
 
-                let has_releasing_action = {
-                    let config_parser = match self.config_parser.lock() {
-                        Ok(parser) => parser,
-                        Err(_) => return StateTransition::Reset,
-                    };
-                    let result = config_parser
-                        .get_actions_for_button_event(input.button_name, "RELEASING")
-                        .is_some();
-                    drop(config_parser);
-                    result
-                };
-
-                if has_releasing_action {
+                if self.has_releasing_action(&input.button_name, state_machine.layer()) {
                     println!(
-                        "[{}] 🔄 Button {} released from HELD state - transitioning to RELEASING",
+                        "[{}] 🔄 Button {} released from HELD state (hold stage {:?}) - transitioning to RELEASING",
                         timestamp,
-                        input.button_name.as_str()
+                        input.button_name,
+                        state_machine.hold_stage()
                     );
                     state_machine.transition_to(ButtonState::RELEASING);
                     StateTransition::EmitEvents(vec![ButtonEvent {
                         button_name: input.button_name,
                         event_type: ButtonEventType::RELEASING,
+                        hold_stage: state_machine.hold_stage(),
+                        layer: state_machine.layer().map(str::to_string),
                     }])
                 } else {
                     println!(
                         "[{}] 🔄 Button {} released from HELD state - no RELEASING action, going to IDLE",
-                        timestamp,
-                        input.button_name.as_str()
+                        timestamp, input.button_name
                     );
                     StateTransition::Reset
                 }
@@ -389,8 +703,7 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
                 let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
                 println!(
                     "[{}] 🔄 Button {} fully released - transitioning to IDLE",
-                    timestamp,
-                    input.button_name.as_str()
+                    timestamp, input.button_name
                 );
                 StateTransition::Reset
             }
@@ -399,8 +712,7 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
                 let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
                 println!(
                     "[{}] 🔄 Button {} pressed again during release - transitioning to EVALUATING",
-                    timestamp,
-                    input.button_name.as_str()
+                    timestamp, input.button_name
                 );
                 state_machine.transition_to(ButtonState::EVALUATING);
                 state_machine.record_signal(now);
@@ -414,6 +726,23 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
                 // No action needed when idle and no signal
                 StateTransition::Continue
             }
+            (ButtonState::AWAITING_TAP, true) => {
+                // Next tap arrived within the multi-tap window
+                state_machine.transition_to(ButtonState::EVALUATING);
+                state_machine.record_signal(now);
+                let timestamp = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
+                println!(
+                    "[{}] 👆 Button {} pressed again within the multi-tap window - tap #{} (state: AWAITING_TAP->EVALUATING)",
+                    timestamp,
+                    input.button_name,
+                    state_machine.signal_count()
+                );
+                StateTransition::Continue
+            }
+            (ButtonState::AWAITING_TAP, false) => {
+                // Already released, waiting for the multi-tap window to close
+                StateTransition::Continue
+            }
         }
     }
 
@@ -421,3 +750,232 @@ impl StateMachineLogic<ButtonState, ButtonEvent, ButtonInput> for HoldIntentLogi
         ButtonState::IDLE
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token_based_config::TokenBasedConfig;
+    use std::time::Duration;
+
+    fn logic(config: serde_json::Value) -> HoldIntentLogic {
+        let config: TokenBasedConfig = serde_json::from_value(config).unwrap();
+        let device = DeviceIdentity {
+            path: "/dev/hidraw0".to_string(),
+            vendor_id: 0x0fd9,
+            product_id: 0x0086,
+            serial_number: None,
+        };
+        HoldIntentLogic::new(
+            &AppConfig::default(),
+            Arc::new(Mutex::new(TokenBasedParser::from_config(config))),
+            device,
+        )
+    }
+
+    fn button(index: usize) -> ButtonName {
+        ButtonName::Physical(PhysicalButtonName::from_index(index))
+    }
+
+    fn press(
+        logic: &HoldIntentLogic,
+        machine: &mut ButtonStateMachine<ButtonState>,
+        now: Instant,
+    ) -> StateTransition<ButtonEvent> {
+        let input = ButtonInput {
+            button_name: button(0),
+            is_pressed: true,
+        };
+        logic.process_input(machine, input, now)
+    }
+
+    fn release(
+        logic: &HoldIntentLogic,
+        machine: &mut ButtonStateMachine<ButtonState>,
+        now: Instant,
+    ) -> StateTransition<ButtonEvent> {
+        let input = ButtonInput {
+            button_name: button(0),
+            is_pressed: false,
+        };
+        logic.process_input(machine, input, now)
+    }
+
+    fn hold_stages_of(transition: &StateTransition<ButtonEvent>) -> Vec<Option<usize>> {
+        match transition {
+            StateTransition::EmitEvents(events) => {
+                events.iter().map(|event| event.hold_stage).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn with_button_0(settings: serde_json::Value, actions: serde_json::Value) -> HoldIntentLogic {
+        logic(serde_json::json!({
+            "version": 4,
+            "device": {
+                "button_count": 3,
+                "settings": settings,
+                "buttons": { "button_0": { "actions": actions } }
+            }
+        }))
+    }
+
+    fn event_names(transition: &StateTransition<ButtonEvent>) -> Vec<&str> {
+        match transition {
+            StateTransition::EmitEvents(events) | StateTransition::EmitEventsAndReset(events) => {
+                events
+                    .iter()
+                    .map(|event| event.event_type.as_str())
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn device_hold_stages_leave_pressed_only_buttons_alone() {
+        let logic = logic(serde_json::json!({
+            "version": 4,
+            "device": {
+                "button_count": 3,
+                "settings": {
+                    "hold_stages": [{ "name": "LONG_HELD", "threshold_ms": 2000 }]
+                },
+                "buttons": {
+                    "button_0": {
+                        "actions": { "PRESSED": [{ "type": "Text", "text": "a" }] }
+                    },
+                    "button_1": {
+                        "actions": { "LONG_HELD": [{ "type": "Text", "text": "b" }] }
+                    }
+                }
+            }
+        }));
+
+        let config = logic.get_button_config(&button(0), None);
+        assert!(config.hold_stages.is_empty());
+        assert!(!config.has_hold_action());
+        assert_eq!(
+            config.threshold_ms,
+            AppConfig::default().default_hold_threshold_ms
+        );
+
+        let mut machine = ButtonStateMachine::new(ButtonState::IDLE);
+        assert_eq!(
+            event_names(&press(&logic, &mut machine, Instant::now())),
+            ["PRESSED"]
+        );
+
+        let config = logic.get_button_config(&button(1), None);
+        assert_eq!(config.hold_stages.len(), 1);
+        assert_eq!(config.threshold_ms, 2000);
+    }
+
+    #[test]
+    fn hold_stages_fire_in_order_and_once_each() {
+        let text = serde_json::json!([{ "type": "Text", "text": "a" }]);
+        let logic = with_button_0(
+            serde_json::json!({
+                "hold_stages": [
+                    { "name": "LONG_HELD", "threshold_ms": 2000 },
+                    { "name": "VERY_LONG_HELD", "threshold_ms": 4000 }
+                ]
+            }),
+            serde_json::json!({ "HELD": text, "LONG_HELD": text, "VERY_LONG_HELD": text }),
+        );
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut machine = ButtonStateMachine::new(ButtonState::IDLE);
+        assert_eq!(
+            event_names(&press(&logic, &mut machine, start)),
+            Vec::<&str>::new()
+        );
+
+        let stages = |machine: &mut ButtonStateMachine<ButtonState>, ms| {
+            hold_stages_of(&logic.process_hold_stages(machine, &button(0), at(ms)))
+        };
+        assert_eq!(stages(&mut machine, 1999), []);
+        assert_eq!(stages(&mut machine, 2000), [Some(1)]);
+        assert_eq!(stages(&mut machine, 3000), []);
+        assert_eq!(stages(&mut machine, 4000), [Some(2)]);
+        assert_eq!(stages(&mut machine, 9000), []);
+        assert_eq!(machine.hold_stage(), Some(2));
+
+        // A late check still fires every stage it skipped past, in order
+        let mut machine = ButtonStateMachine::new(ButtonState::IDLE);
+        press(&logic, &mut machine, start);
+        assert_eq!(stages(&mut machine, 5000), [Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn quick_taps_resolve_to_multi_tap_events() {
+        let text = serde_json::json!([{ "type": "Text", "text": "a" }]);
+        let logic = with_button_0(
+            serde_json::json!({}),
+            serde_json::json!({ "PRESSED": text, "DOUBLE_PRESSED": text }),
+        );
+        let config = logic.get_button_config(&button(0), None);
+        assert_eq!(config.max_taps(), 2);
+        let window = config.multi_tap_window_ms;
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // Two taps: the second reaches the maximum and resolves right away
+        let mut machine = ButtonStateMachine::new(ButtonState::IDLE);
+        assert!(matches!(
+            press(&logic, &mut machine, at(0)),
+            StateTransition::Continue
+        ));
+        assert!(matches!(
+            release(&logic, &mut machine, at(50)),
+            StateTransition::Continue
+        ));
+        assert_eq!(machine.state(), ButtonState::AWAITING_TAP);
+        assert!(matches!(
+            logic.process_tap_timeout(&mut machine, &button(0), at(50 + window - 1)),
+            StateTransition::Continue
+        ));
+        press(&logic, &mut machine, at(50 + window - 1));
+        assert_eq!(machine.state(), ButtonState::EVALUATING);
+        let transition = release(&logic, &mut machine, at(window + 100));
+        assert!(matches!(transition, StateTransition::EmitEventsAndReset(_)));
+        assert_eq!(event_names(&transition), ["DOUBLE_PRESSED"]);
+
+        // A single tap resolves to PRESSED once the window closes
+        let mut machine = ButtonStateMachine::new(ButtonState::IDLE);
+        press(&logic, &mut machine, at(0));
+        release(&logic, &mut machine, at(50));
+        let transition = logic.process_tap_timeout(&mut machine, &button(0), at(50 + window));
+        assert_eq!(event_names(&transition), ["PRESSED"]);
+    }
+
+    #[test]
+    fn triple_taps_wait_for_the_third_tap() {
+        let text = serde_json::json!([{ "type": "Text", "text": "a" }]);
+        let logic = with_button_0(
+            serde_json::json!({ "multi_tap_window_ms": 200 }),
+            serde_json::json!({ "DOUBLE_PRESSED": text, "TRIPLE_PRESSED": text }),
+        );
+        assert_eq!(logic.get_button_config(&button(0), None).max_taps(), 3);
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let mut machine = ButtonStateMachine::new(ButtonState::IDLE);
+        for tap in 0..2 {
+            press(&logic, &mut machine, at(tap * 100));
+            release(&logic, &mut machine, at(tap * 100 + 50));
+            assert_eq!(machine.state(), ButtonState::AWAITING_TAP);
+        }
+        let transition = logic.process_tap_timeout(&mut machine, &button(0), at(350));
+        assert_eq!(event_names(&transition), ["DOUBLE_PRESSED"]);
+
+        let mut machine = ButtonStateMachine::new(ButtonState::IDLE);
+        for tap in 0..2 {
+            press(&logic, &mut machine, at(tap * 100));
+            release(&logic, &mut machine, at(tap * 100 + 50));
+        }
+        press(&logic, &mut machine, at(200));
+        let transition = release(&logic, &mut machine, at(250));
+        assert_eq!(event_names(&transition), ["TRIPLE_PRESSED"]);
+    }
+}
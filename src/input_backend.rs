@@ -0,0 +1,239 @@
+use crate::token_based_config::InputBackendKind;
+use crate::uinput_device::UinputDevice;
+#[cfg(feature = "wayland")]
+use crate::wayland_device::WaylandDevice;
+use anyhow::{Context, Result, bail};
+use enigo::{Axis, Button, Coordinate, Direction, Enigo, Key, Keyboard, Mouse, Settings};
+use std::collections::HashSet;
+use std::time::Instant;
+
+/// Something that turns actions into keyboard and mouse input
+pub trait InputBackend {
+    fn kind(&self) -> InputBackendKind;
+    fn key(&mut self, key: Key, direction: Direction) -> Result<()>;
+    fn text(&mut self, text: &str) -> Result<()>;
+    fn button(&mut self, button: Button, direction: Direction) -> Result<()>;
+    fn move_mouse(&mut self, x: i32, y: i32, coordinate: Coordinate) -> Result<()>;
+    fn scroll(&mut self, amount: i32, axis: Axis) -> Result<()>;
+
+    /// Release keys and mouse buttons that are still held down
+    fn release_all(&mut self, keys: &[Key], buttons: &[Button]) -> Result<()> {
+        for key in keys {
+            self.key(*key, Direction::Release)?;
+        }
+        for button in buttons {
+            self.button(*button, Direction::Release)?;
+        }
+        Ok(())
+    }
+}
+
+/// Start the first backend that works: `preferred` if given, then the ones that suit
+/// the current session best.
+pub fn start_backend(preferred: Option<InputBackendKind>) -> Result<Box<dyn InputBackend>> {
+    let mut order = session_order();
+    if let Some(preferred) = preferred {
+        order.retain(|kind| *kind != preferred);
+        order.insert(0, preferred);
+    }
+
+    for kind in order {
+        match open_backend(kind) {
+            Ok(backend) => return Ok(backend),
+            Err(e) => eprintln!(
+                "⚠️  The {} input backend could not be started: {:#}",
+                kind, e
+            ),
+        }
+    }
+    bail!("No input backend could be started.")
+}
+
+/// Backends to try for the current session, most suitable first
+fn session_order() -> Vec<InputBackendKind> {
+    let session_type = std::env::var("XDG_SESSION_TYPE").unwrap_or_default();
+    let on_wayland = session_type == "wayland" || std::env::var("WAYLAND_DISPLAY").is_ok();
+    let on_x11 = session_type == "x11" || std::env::var("DISPLAY").is_ok();
+
+    if on_wayland {
+        let mut order = vec![InputBackendKind::Uinput, InputBackendKind::Enigo];
+        if cfg!(feature = "wayland") {
+            order.insert(0, InputBackendKind::Wayland);
+        }
+        order
+    } else if on_x11 {
+        vec![InputBackendKind::Enigo, InputBackendKind::Uinput]
+    } else {
+        // TTY or a service started outside the graphical session
+        vec![InputBackendKind::Uinput, InputBackendKind::Enigo]
+    }
+}
+
+fn open_backend(kind: InputBackendKind) -> Result<Box<dyn InputBackend>> {
+    Ok(match kind {
+        InputBackendKind::Enigo => Box::new(EnigoBackend::new()?),
+        InputBackendKind::Uinput => {
+            Box::new(UinputDevice::new().context("Failed to create the uinput devices.")?)
+        }
+        #[cfg(feature = "wayland")]
+        InputBackendKind::Wayland => Box::new(
+            WaylandDevice::new()
+                .context("Failed to create the Wayland virtual keyboard and pointer.")?,
+        ),
+        #[cfg(not(feature = "wayland"))]
+        InputBackendKind::Wayland => {
+            bail!("This build does not include it, rebuild with `--features wayland`.")
+        }
+        InputBackendKind::Recorder => Box::new(RecordingBackend::new()),
+    })
+}
+
+/// Input through Enigo, which talks to the X11 or Wayland session
+pub struct EnigoBackend(Enigo);
+
+impl EnigoBackend {
+    pub fn new() -> Result<Self> {
+        let enigo = Enigo::new(&Settings::default()).context("Failed to create Enigo instance.")?;
+        Ok(EnigoBackend(enigo))
+    }
+}
+
+impl InputBackend for EnigoBackend {
+    fn kind(&self) -> InputBackendKind {
+        InputBackendKind::Enigo
+    }
+
+    fn key(&mut self, key: Key, direction: Direction) -> Result<()> {
+        Ok(self.0.key(key, direction)?)
+    }
+
+    fn text(&mut self, text: &str) -> Result<()> {
+        Ok(self.0.text(text)?)
+    }
+
+    fn button(&mut self, button: Button, direction: Direction) -> Result<()> {
+        Ok(self.0.button(button, direction)?)
+    }
+
+    fn move_mouse(&mut self, x: i32, y: i32, coordinate: Coordinate) -> Result<()> {
+        Ok(self.0.move_mouse(x, y, coordinate)?)
+    }
+
+    fn scroll(&mut self, amount: i32, axis: Axis) -> Result<()> {
+        Ok(self.0.scroll(amount, axis)?)
+    }
+}
+
+/// Backend that sends nothing and only logs a timestamped trace of the input it would
+/// have sent, keeping track of what is held so that releases can be checked
+pub struct RecordingBackend {
+    started: Instant,
+    pressed_keys: HashSet<Key>,
+    pressed_buttons: HashSet<Button>,
+}
+
+impl RecordingBackend {
+    pub fn new() -> Self {
+        RecordingBackend {
+            started: Instant::now(),
+            pressed_keys: HashSet::new(),
+            pressed_buttons: HashSet::new(),
+        }
+    }
+
+    fn record(&self, event: String) {
+        let held = self
+            .pressed_keys
+            .iter()
+            .map(|key| format!("{:?}", key))
+            .chain(
+                self.pressed_buttons
+                    .iter()
+                    .map(|button| format!("Mouse {:?}", button)),
+            )
+            .collect::<Vec<_>>();
+        let held = if held.is_empty() {
+            "nothing".to_string()
+        } else {
+            held.join(", ")
+        };
+        println!(
+            "📝 [{:>9.3}s] {:<40} held: {}",
+            self.started.elapsed().as_secs_f64(),
+            event,
+            held
+        );
+    }
+}
+
+impl InputBackend for RecordingBackend {
+    fn kind(&self) -> InputBackendKind {
+        InputBackendKind::Recorder
+    }
+
+    fn key(&mut self, key: Key, direction: Direction) -> Result<()> {
+        let was_held = match direction {
+            Direction::Press => !self.pressed_keys.insert(key),
+            Direction::Release => self.pressed_keys.remove(&key),
+            Direction::Click => self.pressed_keys.contains(&key),
+        };
+        let note = match (direction, was_held) {
+            (Direction::Press, true) => " (already held)",
+            (Direction::Release, false) => " (was not held)",
+            _ => "",
+        };
+        self.record(format!("Key {:?}: {:?}{}", direction, key, note));
+        Ok(())
+    }
+
+    fn text(&mut self, text: &str) -> Result<()> {
+        self.record(format!("Text: \"{}\"", text));
+        Ok(())
+    }
+
+    fn button(&mut self, button: Button, direction: Direction) -> Result<()> {
+        let was_held = match direction {
+            Direction::Press => !self.pressed_buttons.insert(button),
+            Direction::Release => self.pressed_buttons.remove(&button),
+            Direction::Click => self.pressed_buttons.contains(&button),
+        };
+        let note = match (direction, was_held) {
+            (Direction::Press, true) => " (already held)",
+            (Direction::Release, false) => " (was not held)",
+            _ => "",
+        };
+        self.record(format!(
+            "Mouse Button {:?}: {:?}{}",
+            direction, button, note
+        ));
+        Ok(())
+    }
+
+    fn move_mouse(&mut self, x: i32, y: i32, coordinate: Coordinate) -> Result<()> {
+        self.record(format!("Move Mouse ({:?}): ({}, {})", coordinate, x, y));
+        Ok(())
+    }
+
+    fn scroll(&mut self, amount: i32, axis: Axis) -> Result<()> {
+        self.record(format!("Scroll {:?}: {}", axis, amount));
+        Ok(())
+    }
+
+    fn release_all(&mut self, keys: &[Key], buttons: &[Button]) -> Result<()> {
+        self.record(format!(
+            "Release All: {} keys, {} mouse buttons",
+            keys.len(),
+            buttons.len()
+        ));
+        for key in keys {
+            self.key(*key, Direction::Release)?;
+        }
+        for button in buttons {
+            self.button(*button, Direction::Release)?;
+        }
+        if !self.pressed_keys.is_empty() || !self.pressed_buttons.is_empty() {
+            self.record("⚠️  Still held after Release All".to_string());
+        }
+        Ok(())
+    }
+}
@@ -1,21 +1,33 @@
-use crate::token_based_config::ExecutableAction;
+use crate::config_manager::expand_home;
+use crate::input_backend::{self, InputBackend};
+use crate::token_based_config::{ExecutableAction, InputBackendKind};
 use anyhow::{Context, Result};
-use enigo::Keyboard;
-use enigo::{
-    Direction, Enigo, Key, Settings,
-    agent::{Agent, Token},
-};
+use enigo::{Axis, Button, Coordinate, Direction, Key};
 use std::collections::HashSet;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
 use std::time::{Duration, Instant};
 
+/// A key or mouse button that is held down until released
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HeldInput {
+    Key(Key),
+    Mouse(Button),
+}
+
 pub struct InputSimulator {
-    enigo: Enigo,
+    backend: Box<dyn InputBackend>,
     pressed_keys: HashSet<Key>,
-    scheduled_releases: Vec<(Instant, Key)>,
+    pressed_buttons: HashSet<Button>,
+    scheduled_releases: Vec<(Instant, HeldInput)>,
+    /// Detached `Exec` programs that have not been reaped yet
+    running_commands: Vec<(String, Child)>,
 }
 
 impl InputSimulator {
-    pub fn new() -> Result<Self> {
+    /// Start the `preferred` input backend, or the one best suited to the session when
+    /// it is `None` or cannot be started
+    pub fn new(preferred: Option<InputBackendKind>) -> Result<Self> {
         println!("Initializing Input Simulation System");
         println!("{}", "=".repeat(80));
 
@@ -25,6 +37,12 @@ impl InputSimulator {
         let desktop =
             std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_else(|_| "unknown".to_string());
 
+        let backend = input_backend::start_backend(preferred)?;
+        let backend_kind = backend.kind();
+        if preferred.is_some_and(|preferred| preferred != backend_kind) {
+            eprintln!("⚠️  Falling back to the {} input backend", backend_kind);
+        }
+
         println!("| {:<20} | {:<50} |", "Property", "Value");
         println!("{}", "-".repeat(80));
         println!("| {:<20} | {:<50} |", "Session Type", session_type);
@@ -34,9 +52,21 @@ impl InputSimulator {
         }
 
         println!("| {:<20} | {:<50} |", "Desktop Environment", desktop);
+        println!("| {:<20} | {:<50} |", "Input Backend", backend_kind);
 
-        let (compatibility_status, notes) = match session_type.as_str() {
-            "wayland" => {
+        let (compatibility_status, notes) = match (backend_kind, session_type.as_str()) {
+            (InputBackendKind::Uinput, _) => (
+                "Full",
+                "Virtual uinput devices work in any session".to_string(),
+            ),
+            (InputBackendKind::Wayland, _) => (
+                "Full",
+                "Virtual keyboard and pointer of the compositor".to_string(),
+            ),
+            (InputBackendKind::Recorder, _) => {
+                ("None", "Input is only logged, not sent".to_string())
+            }
+            (InputBackendKind::Enigo, "wayland") => {
                 let desktop_notes = match desktop.to_lowercase().as_str() {
                     "gnome" => "May require accessibility permissions",
                     "kde" => "Generally compatible",
@@ -48,7 +78,7 @@ impl InputSimulator {
                     format!("Wayland restrictions apply. {}", desktop_notes),
                 )
             }
-            "x11" => (
+            (InputBackendKind::Enigo, "x11") => (
                 "Full",
                 "X11 provides complete input simulation support".to_string(),
             ),
@@ -58,111 +88,112 @@ impl InputSimulator {
         println!("| {:<20} | {:<50} |", "Compatibility", compatibility_status);
         println!("| {:<20} | {:<50} |", "Notes", notes);
 
-        if session_type == "wayland" {
+        if session_type == "wayland" && backend_kind == InputBackendKind::Enigo {
             println!("{}", "-".repeat(80));
             println!("| Troubleshooting Options (if input simulation fails):");
-            println!("| - Run with elevated permissions: sudo cargo run");
+            println!("| - Use the uinput backend, see \"Input Backends\" in the README");
             println!("| - Switch to X11 session temporarily");
             println!("| - Check compositor-specific input permissions");
         }
 
         println!("{}", "=".repeat(80));
-
-        let enigo = Enigo::new(&Settings::default()).context("Failed to create Enigo instance.")?;
-
-        let _test_token = Token::Key(Key::Escape, Direction::Press);
         println!("Input simulation system initialized successfully");
 
         Ok(InputSimulator {
-            enigo,
+            backend,
             pressed_keys: HashSet::new(),
+            pressed_buttons: HashSet::new(),
             scheduled_releases: Vec::new(),
+            running_commands: Vec::new(),
         })
     }
 
-    pub fn execute_actions(&mut self, actions: &[ExecutableAction]) -> Result<()> {
-        if actions.is_empty() {
-            return Ok(());
-        }
-
-        println!("Executing Action Sequence");
-        println!("{}", "-".repeat(60));
-        println!("| {:<3} | {:<50} |", "No.", "Action");
-        println!("{}", "-".repeat(60));
-
-        for (i, action) in actions.iter().enumerate() {
-            let action_desc = match action {
-                ExecutableAction::KeyPress { key, auto_release } => {
-                    if *auto_release {
-                        format!("Key Press + Auto Release: {:?}", key)
-                    } else {
-                        format!("Key Press: {:?}", key)
-                    }
-                }
-                ExecutableAction::KeyRelease { key } => format!("Key Release: {:?}", key),
-                ExecutableAction::Text { text } => format!("Text Input: \"{}\"", text),
-                ExecutableAction::Sleep { duration_ms } => format!("Sleep: {}ms", duration_ms),
-                ExecutableAction::ReleaseAfter { duration_ms } => {
-                    format!("Release After: {}ms", duration_ms)
-                }
-                ExecutableAction::ReleaseAll => "Release All Keys".to_string(),
-                ExecutableAction::ReleaseAllAfter { duration_ms } => {
-                    format!("Release All After: {}ms", duration_ms)
-                }
-            };
-
-            println!("| {:<3} | {:<50} |", i + 1, action_desc);
-
-            match action {
-                ExecutableAction::KeyPress { key, auto_release } => {
-                    self.execute_key_press(*key, *auto_release)
-                        .context(format!("Failed to execute key press for {:?}", key))?;
-                }
-                ExecutableAction::KeyRelease { key } => {
-                    self.execute_key_release(*key)
-                        .context("Failed to execute key release")?;
-                }
-                ExecutableAction::Text { text } => {
-                    self.execute_text(text.clone())
-                        .context("Failed to execute text input")?;
-                }
-                ExecutableAction::Sleep { duration_ms } => {
-                    self.execute_sleep(*duration_ms)
-                        .context("Failed to execute sleep.")?;
-                }
-                ExecutableAction::ReleaseAfter { duration_ms } => {
-                    self.schedule_release_all_after(*duration_ms);
-                }
-                ExecutableAction::ReleaseAll => {
-                    self.schedule_release_all_after(0);
-                }
-                ExecutableAction::ReleaseAllAfter { duration_ms } => {
-                    self.schedule_release_all_after(*duration_ms);
+    /// Run a single action. Sleeps are timed by the caller and do nothing here, and an
+    /// `Exec` that waits returns its running program for the caller to poll.
+    pub fn execute_action(&mut self, action: &ExecutableAction) -> Result<Option<WaitingCommand>> {
+        match action {
+            ExecutableAction::KeyPress { key, auto_release } => {
+                self.execute_key_press(*key, *auto_release)
+                    .context(format!("Failed to execute key press for {:?}", key))?;
+            }
+            ExecutableAction::KeyRelease { key } => {
+                self.execute_key_release(*key)
+                    .context("Failed to execute key release")?;
+            }
+            ExecutableAction::Text { text } => {
+                self.execute_text(text.clone())
+                    .context("Failed to execute text input")?;
+            }
+            ExecutableAction::Sleep { .. } => {
+                // Timed by the action executor
+            }
+            ExecutableAction::ReleaseAfter { duration_ms } => {
+                self.schedule_release_all_after(*duration_ms);
+            }
+            ExecutableAction::ReleaseAll => {
+                self.schedule_release_all_after(0);
+            }
+            ExecutableAction::ReleaseAllAfter { duration_ms } => {
+                self.schedule_release_all_after(*duration_ms);
+            }
+            ExecutableAction::MouseButton { button, direction } => {
+                self.execute_mouse_button(*button, *direction)
+                    .context(format!("Failed to execute mouse button {:?}", button))?;
+            }
+            ExecutableAction::MoveRelative { x, y } => {
+                self.execute_mouse_move(*x, *y, Coordinate::Rel)
+                    .context("Failed to execute relative mouse move")?;
+            }
+            ExecutableAction::MoveAbsolute { x, y } => {
+                self.execute_mouse_move(*x, *y, Coordinate::Abs)
+                    .context("Failed to execute absolute mouse move")?;
+            }
+            ExecutableAction::Scroll { amount, axis } => {
+                self.execute_scroll(*amount, *axis)
+                    .context("Failed to execute scroll")?;
+            }
+            ExecutableAction::Exec { program, .. }
+                if self.backend.kind() == InputBackendKind::Recorder =>
+            {
+                println!("📝 Not starting \"{}\" without real input", program);
+            }
+            ExecutableAction::Exec {
+                program,
+                args,
+                cwd,
+                env,
+                wait,
+                timeout_ms,
+            } => {
+                let mut command = Command::new(expand_home(program));
+                command.args(args).envs(env).stdin(Stdio::null());
+                if let Some(cwd) = cwd {
+                    command.current_dir(expand_home(cwd));
                 }
+                let timeout = wait.then(|| Duration::from_millis(*timeout_ms));
+                return self
+                    .execute_command(program, command, timeout)
+                    .context(format!("Failed to execute \"{}\"", program));
+            }
+            ExecutableAction::Macro { name } => {
+                // Macros are expanded when the config is loaded
+                eprintln!("⚠️  Skipping unresolved macro \"{}\"", name);
             }
-
-            std::thread::sleep(Duration::from_millis(10));
         }
 
-        println!("{}", "-".repeat(60));
-        println!("Action sequence completed successfully");
-
-        Ok(())
+        Ok(None)
     }
 
     fn execute_key_press(&mut self, key: Key, auto_release: bool) -> Result<()> {
-        let press_token = Token::Key(key, Direction::Press);
-
-        self.enigo
-            .execute(&press_token)
+        self.backend
+            .key(key, Direction::Press)
             .context("Failed to execute key press.")?;
 
         self.pressed_keys.insert(key);
 
         if auto_release {
-            let release_token = Token::Key(key, Direction::Release);
-            self.enigo
-                .execute(&release_token)
+            self.backend
+                .key(key, Direction::Release)
                 .context("Failed to auto-release key.")?;
             self.pressed_keys.remove(&key);
         }
@@ -172,10 +203,8 @@ impl InputSimulator {
 
     fn execute_key_release(&mut self, key: Key) -> Result<()> {
         if self.pressed_keys.contains(&key) {
-            let release_token = Token::Key(key, Direction::Release);
-
-            self.enigo
-                .execute(&release_token)
+            self.backend
+                .key(key, Direction::Release)
                 .context("Failed to execute key release.")?;
 
             self.pressed_keys.remove(&key);
@@ -184,15 +213,89 @@ impl InputSimulator {
         Ok(())
     }
 
-    fn execute_text(&mut self, text: String) -> Result<()> {
-        self.enigo
-            .text(&text)
-            .context("Failed to execute text input.")?;
+    fn execute_mouse_button(&mut self, button: Button, direction: Direction) -> Result<()> {
+        if direction == Direction::Release && !self.pressed_buttons.contains(&button) {
+            return Ok(());
+        }
+
+        self.backend
+            .button(button, direction)
+            .context("Failed to execute mouse button.")?;
+
+        match direction {
+            Direction::Press => {
+                self.pressed_buttons.insert(button);
+            }
+            Direction::Release => {
+                self.pressed_buttons.remove(&button);
+            }
+            Direction::Click => {}
+        }
+
+        Ok(())
+    }
+
+    fn execute_mouse_move(&mut self, x: i32, y: i32, coordinate: Coordinate) -> Result<()> {
+        self.backend
+            .move_mouse(x, y, coordinate)
+            .context("Failed to move the mouse.")?;
         Ok(())
     }
 
-    fn execute_sleep(&self, duration_ms: u64) -> Result<()> {
-        std::thread::sleep(Duration::from_millis(duration_ms));
+    fn execute_scroll(&mut self, amount: i32, axis: Axis) -> Result<()> {
+        self.backend
+            .scroll(amount, axis)
+            .context("Failed to scroll.")?;
+        Ok(())
+    }
+
+    /// Start a program in its own process group. Without a timeout it runs detached and
+    /// is reaped later; otherwise it is returned to be waited for.
+    fn execute_command(
+        &mut self,
+        program: &str,
+        mut command: Command,
+        timeout: Option<Duration>,
+    ) -> Result<Option<WaitingCommand>> {
+        let child = command
+            .process_group(0)
+            .spawn()
+            .context("Failed to start program.")?;
+        let Some(timeout) = timeout else {
+            self.running_commands.push((program.to_string(), child));
+            return Ok(None);
+        };
+
+        Ok(Some(WaitingCommand {
+            program: program.to_string(),
+            child,
+            timeout,
+            deadline: Instant::now() + timeout,
+        }))
+    }
+
+    /// Reap detached programs that have exited, so they do not linger as zombies
+    pub fn reap_finished_commands(&mut self) {
+        self.running_commands
+            .retain_mut(|(program, child)| match child.try_wait() {
+                Ok(Some(status)) => {
+                    if !status.success() {
+                        eprintln!("⚠️  \"{}\" exited with {}", program, status);
+                    }
+                    false
+                }
+                Ok(None) => true,
+                Err(e) => {
+                    eprintln!("⚠️  Failed to check on \"{}\": {}", program, e);
+                    false
+                }
+            });
+    }
+
+    fn execute_text(&mut self, text: String) -> Result<()> {
+        self.backend
+            .text(&text)
+            .context("Failed to execute text input.")?;
         Ok(())
     }
 
@@ -203,52 +306,202 @@ impl InputSimulator {
             Instant::now()
         };
 
-        for key in &self.pressed_keys {
-            self.scheduled_releases.push((release_time, *key));
+        let held = self.held_inputs();
+        for input in &held {
+            self.scheduled_releases.push((release_time, *input));
         }
 
-        if !self.pressed_keys.is_empty() {
+        if !held.is_empty() {
             println!(
-                "Scheduled {} keys for delayed release ({}ms)",
-                self.pressed_keys.len(),
+                "Scheduled {} keys and mouse buttons for delayed release ({}ms)",
+                held.len(),
                 duration_ms
             );
         }
     }
 
+    /// Every key and mouse button that is currently held down
+    fn held_inputs(&self) -> Vec<HeldInput> {
+        self.pressed_keys
+            .iter()
+            .map(|key| HeldInput::Key(*key))
+            .chain(
+                self.pressed_buttons
+                    .iter()
+                    .map(|button| HeldInput::Mouse(*button)),
+            )
+            .collect()
+    }
+
+    /// Release the given keys and mouse buttons, skipping those that are not held
+    pub fn release_inputs(&mut self, inputs: impl IntoIterator<Item = HeldInput>) -> Result<()> {
+        for input in inputs {
+            self.release_held_input(input)?;
+        }
+        Ok(())
+    }
+
+    fn release_held_input(&mut self, input: HeldInput) -> Result<()> {
+        match input {
+            HeldInput::Key(key) => self.execute_key_release(key),
+            HeldInput::Mouse(button) => self.execute_mouse_button(button, Direction::Release),
+        }
+    }
+
+    /// Immediately release every key and mouse button that is still held, dropping
+    /// any pending scheduled releases. Used when the pedal disappears mid-press.
+    pub fn release_all_keys(&mut self) -> Result<()> {
+        self.scheduled_releases.clear();
+
+        let keys: Vec<Key> = self.pressed_keys.drain().collect();
+        let buttons: Vec<Button> = self.pressed_buttons.drain().collect();
+        if !keys.is_empty() || !buttons.is_empty() {
+            println!(
+                "Releasing {} held keys and mouse buttons",
+                keys.len() + buttons.len()
+            );
+        }
+
+        self.backend
+            .release_all(&keys, &buttons)
+            .context("Failed to release held keys and mouse buttons.")
+    }
+
+    /// When the earliest scheduled release is due
+    pub fn next_scheduled_release(&self) -> Option<Instant> {
+        self.scheduled_releases
+            .iter()
+            .map(|(release_time, _)| *release_time)
+            .min()
+    }
+
     pub fn process_scheduled_releases(&mut self) -> Result<()> {
         let now = Instant::now();
         let mut releases_to_process = Vec::new();
 
-        self.scheduled_releases.retain(|(release_time, key)| {
+        self.scheduled_releases.retain(|(release_time, input)| {
             if *release_time <= now {
-                releases_to_process.push(*key);
+                releases_to_process.push(*input);
                 false
             } else {
                 true
             }
         });
 
-        if !releases_to_process.is_empty() {
-            for key in releases_to_process {
-                if self.pressed_keys.contains(&key) {
-                    let release_token = Token::Key(key, Direction::Release);
-
-                    match self.enigo.execute(&release_token) {
-                        Ok(_) => {
-                            self.pressed_keys.remove(&key);
-                        }
-                        Err(e) => {
-                            eprintln!(
-                                "Error: Failed to execute scheduled release for {:?}: {}",
-                                key, e
-                            );
-                        }
-                    }
-                }
+        for input in releases_to_process {
+            if let Err(e) = self.release_held_input(input) {
+                eprintln!(
+                    "Error: Failed to execute scheduled release for {:?}: {}",
+                    input, e
+                );
             }
         }
 
         Ok(())
     }
 }
+
+/// A program started by an `Exec` action that waits for it to finish
+pub struct WaitingCommand {
+    program: String,
+    child: Child,
+    timeout: Duration,
+    deadline: Instant,
+}
+
+impl WaitingCommand {
+    /// When the program is killed if it has not finished
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Whether the program has finished, killing it once the timeout has expired
+    pub fn poll(&mut self) -> Result<bool> {
+        if let Some(status) = self
+            .child
+            .try_wait()
+            .context("Failed to wait for program.")?
+        {
+            if !status.success() {
+                eprintln!("⚠️  \"{}\" exited with {}", self.program, status);
+            }
+            return Ok(true);
+        }
+        if Instant::now() >= self.deadline {
+            eprintln!(
+                "⚠️  \"{}\" did not finish within {}ms, killing it",
+                self.program,
+                self.timeout.as_millis()
+            );
+            self.kill()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Stop the program and everything it started, e.g. because its action sequence
+    /// was stopped
+    pub fn kill(&mut self) -> Result<()> {
+        // The program leads its own process group, see `execute_command`
+        let group = self.child.id() as libc::pid_t;
+        // SAFETY: killpg only sends a signal, the group is ours until the child is reaped
+        if unsafe { libc::killpg(group, libc::SIGKILL) } == -1 {
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() != Some(libc::ESRCH) {
+                return Err(error).context("Failed to kill program.");
+            }
+        }
+        self.child.wait().context("Failed to wait for program.")?;
+        Ok(())
+    }
+}
+
+/// One-line description of an action for the log
+pub fn describe_action(action: &ExecutableAction) -> String {
+    match action {
+        ExecutableAction::KeyPress { key, auto_release } => {
+            if *auto_release {
+                format!("Key Press + Auto Release: {:?}", key)
+            } else {
+                format!("Key Press: {:?}", key)
+            }
+        }
+        ExecutableAction::KeyRelease { key } => format!("Key Release: {:?}", key),
+        ExecutableAction::Text { text } => format!("Text Input: \"{}\"", text),
+        ExecutableAction::Sleep { duration_ms } => format!("Sleep: {}ms", duration_ms),
+        ExecutableAction::ReleaseAfter { duration_ms } => {
+            format!("Release After: {}ms", duration_ms)
+        }
+        ExecutableAction::ReleaseAll => "Release All Keys".to_string(),
+        ExecutableAction::ReleaseAllAfter { duration_ms } => {
+            format!("Release All After: {}ms", duration_ms)
+        }
+        ExecutableAction::MouseButton { button, direction } => {
+            format!("Mouse Button {:?}: {:?}", direction, button)
+        }
+        ExecutableAction::MoveRelative { x, y } => format!("Move Mouse By: ({}, {})", x, y),
+        ExecutableAction::MoveAbsolute { x, y } => format!("Move Mouse To: ({}, {})", x, y),
+        ExecutableAction::Scroll { amount, axis } => {
+            format!("Scroll {:?}: {}", axis, amount)
+        }
+        ExecutableAction::Exec {
+            program,
+            args,
+            wait,
+            timeout_ms,
+            ..
+        } => {
+            let command_line = std::iter::once(program)
+                .chain(args)
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(" ");
+            if *wait {
+                format!("Exec (wait {}ms): {}", timeout_ms, command_line)
+            } else {
+                format!("Exec: {}", command_line)
+            }
+        }
+        ExecutableAction::Macro { name } => format!("Macro: {}", name),
+    }
+}
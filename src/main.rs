@@ -1,7 +1,13 @@
+use crate::config_manager::{ConfigFormat, ConfigManager};
+use crate::device_discovery::{DeviceIdentity, DeviceMessage};
 use crate::hold_intent_input_action_manager::HoldIntentInputActionManager;
+use crate::token_based_config::{FocusedWindow, InputBackendKind, TokenBasedConfig};
 use anyhow::anyhow;
 use clap::{Parser, Subcommand};
 use hidapi::HidApi;
+use std::collections::HashSet;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
 
 /// Elgato Stream Deck Pedal Controller for Linux
 #[derive(Parser)]
@@ -9,6 +15,10 @@ use hidapi::HidApi;
 #[command(about = "A Linux controller for Elgato Stream Deck Pedal with systemd service support")]
 #[command(version)]
 struct Cli {
+    /// Configuration file to use instead of the one found in the XDG config directories
+    /// (can also be set with ELGATO_PEDAL_CONFIG)
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<std::path::PathBuf>,
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -28,45 +38,108 @@ enum Commands {
         system: bool,
     },
     /// Edit the configuration file
-    Config,
+    Config {
+        #[command(subcommand)]
+        command: Option<ConfigCommands>,
+    },
+    /// Check a configuration file and report every problem found
+    Validate {
+        /// Config file to check (defaults to the one used by the service)
+        path: Option<std::path::PathBuf>,
+    },
+    /// Rewrite the configuration file in the current format
+    Migrate,
+    /// Print the JSON Schema of the configuration file
+    Schema {
+        /// Write the schema to this file instead of standard output
+        #[arg(long, short)]
+        output: Option<std::path::PathBuf>,
+    },
     /// Start the pedal controller (default if no command specified)
-    Run,
+    Run {
+        /// Backend to try first, overriding `input_backend` in the device settings and the
+        /// choice by session
+        #[arg(long, value_enum)]
+        input_backend: Option<InputBackendKind>,
+        /// Log a trace of the actions instead of sending input
+        #[arg(long, conflicts_with = "input_backend")]
+        dry_run: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Convert a configuration file between JSON, TOML and YAML
+    Convert {
+        /// Format to convert to
+        #[arg(value_enum)]
+        to: ConfigFormat,
+        /// File to convert (defaults to the active configuration file)
+        #[arg(long)]
+        input: Option<std::path::PathBuf>,
+        /// Where to write the result (defaults to the input with the new extension)
+        #[arg(long, short)]
+        output: Option<std::path::PathBuf>,
+    },
 }
 
 /// Configuration for the application
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AppConfig {
-    pub button_count: usize,
     pub companion_signature: String,
     pub default_hold_threshold_ms: u64,
+    pub default_multi_tap_window_ms: u64,
+    pub default_chord_window_ms: u64,
+    pub default_repeat_interval_ms: u64,
+    pub reconnect_poll_interval_ms: u64,
 }
 
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
-            button_count: 3,
             companion_signature: "--x-elgato-pedal-companion-notification".to_string(),
             default_hold_threshold_ms: 666,
+            default_multi_tap_window_ms: 300,
+            default_chord_window_ms: 50,
+            default_repeat_interval_ms: 100,
+            reconnect_poll_interval_ms: 1000,
         }
     }
 }
 
+mod action_executor;
 mod button_state_machine;
 mod button_types;
 mod config_manager;
+mod config_migration;
+mod config_validation;
+mod config_watcher;
+mod device_discovery;
+mod focus_watcher;
 mod hold_intent_input_action_manager;
 mod hold_intent_parser;
 mod hold_intent_state_machine;
+mod input_backend;
 mod input_simulator;
+mod report_decoder;
 mod service_manager;
 mod token_based_config;
+mod uinput_device;
+#[cfg(feature = "wayland")]
+mod wayland_device;
 
 use service_manager::ServiceManager;
 
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    if let Some(config_path) = cli.config {
+        ConfigManager::set_config_path(std::path::absolute(&config_path)?);
+    }
 
-    match cli.command.unwrap_or(Commands::Run) {
+    match cli.command.unwrap_or(Commands::Run {
+        input_backend: None,
+        dry_run: false,
+    }) {
         Commands::Install { system } => {
             println!("Installing Elgato Pedal Controller as systemd service...");
             let service_manager = ServiceManager::new();
@@ -83,22 +156,67 @@ fn main() -> anyhow::Result<()> {
                 std::process::exit(1);
             }
         }
-        Commands::Config => {
+        Commands::Config { command: None } => {
             println!("Opening configuration...");
             open_config_editor();
         }
-        Commands::Run => {
-            run_pedal_controller()?;
+        Commands::Config {
+            command: Some(ConfigCommands::Convert { to, input, output }),
+        } => {
+            if let Err(e) = ConfigManager::convert_config(to, input, output) {
+                eprintln!("❌ Failed to convert the configuration: {e}");
+                std::process::exit(1);
+            }
+        }
+        Commands::Validate { path } => {
+            let path = path.unwrap_or_else(ConfigManager::get_config_path);
+            if !config_validation::run_validate(&path) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Migrate => {
+            if let Err(e) = ConfigManager::migrate_config() {
+                eprintln!("❌ Failed to migrate the configuration: {e}");
+                std::process::exit(1);
+            }
+        }
+        Commands::Schema { output } => {
+            let schema = serde_json::to_string_pretty(&TokenBasedConfig::json_schema())?;
+            match output {
+                Some(path) => {
+                    std::fs::write(&path, schema)?;
+                    println!("✅ Wrote the config schema to \"{}\"", path.display());
+                }
+                None => println!("{schema}"),
+            }
+        }
+        Commands::Run {
+            input_backend,
+            dry_run,
+        } => {
+            let input_backend = if dry_run {
+                println!("🧪 Dry run: actions are logged and no input is sent");
+                Some(InputBackendKind::Recorder)
+            } else {
+                input_backend
+            };
+            run_pedal_controller(input_backend)?;
         }
     }
     Ok(())
 }
 
 fn open_config_editor() {
-    let home = std::env::var("HOME").unwrap_or_else(|_| "/tmp".to_string());
-    let config_path = format!("{}/.config/elgato_pedal_controller.config.json", home);
+    // A system-wide default is not edited in place
+    let config_path = match ConfigManager::config_path_for_writing() {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("❌ Failed to copy the system-wide configuration: {e}");
+            return;
+        }
+    };
 
-    println!("Configuration file location: {}", config_path);
+    println!("Configuration file location: {}", config_path.display());
 
     let editors = ["code", "nano", "vim", "gedit", "xdg-open"];
 
@@ -111,19 +229,15 @@ fn open_config_editor() {
     }
 
     println!("No suitable editor found. Please edit the file manually:");
-    println!("  {}", config_path);
+    println!("  {}", config_path.display());
 }
 
-fn run_pedal_controller() -> anyhow::Result<()> {
+fn run_pedal_controller(input_backend: Option<InputBackendKind>) -> anyhow::Result<()> {
     let app_config = AppConfig::default();
 
-    println!(
-        "\nAttempting to initialize controller for Elgato Pedal {} button(s)...\n",
-        app_config.button_count
-    );
+    println!("\nAttempting to initialize pedal controller...\n");
 
-    let mut manager = match HoldIntentInputActionManager::new(app_config.default_hold_threshold_ms)
-    {
+    let mut manager = match HoldIntentInputActionManager::new(&app_config, input_backend) {
         Ok(mgr) => mgr,
         Err(e) => {
             eprintln!("Failed to create input action manager: {e}");
@@ -131,90 +245,227 @@ fn run_pedal_controller() -> anyhow::Result<()> {
         }
     };
 
-    let api = HidApi::new().expect("Failed to create HID API instance");
-
-    let target_manufacturer = "Elgato";
-    let target_product = "Stream Deck Pedal";
-
-    println!("\nSearching for Elgato Stream Deck Pedal...");
-
-    let device_info = api
-        .device_list()
-        .filter(|device| {
-            device
-                .manufacturer_string()
-                .is_some_and(|m| m.contains(target_manufacturer))
-        })
-        .find(|device| {
-            device
-                .product_string()
-                .is_some_and(|p| p.contains(target_product))
-        });
-
-    match device_info {
-        Some(device) => {
-            println!(
-                "\nSuccess:\n✅ Found target device: Vendor ID: {}, Product ID: {}, Manufacturer: '{}', Product: '{}'",
-                device.vendor_id(),
-                device.product_id(),
-                device
-                    .manufacturer_string()
-                    .expect("Could not find manufacturer_string"),
-                device
-                    .product_string()
-                    .expect("Could not find product_string")
-            );
-
-            let device = match api.open(device.vendor_id(), device.product_id()) {
-                Ok(device) => device,
-                Err(error) => {
-                    eprintln!("❌ Failed to open the target device: {error}");
-                    eprintln!(
-                        "Make sure you have the correct permissions (try adding your user to the 'input' group)"
-                    );
-                    return Err(anyhow!("Failed to open the target device: {}", error));
-                }
-            };
+    let mut api = HidApi::new().expect("Failed to create HID API instance");
 
-            println!("Listening to device events. Press Ctrl+C to exit...\n\n");
+    println!("\nSearching for pedals...");
 
-            loop {
-                let mut buf = [0u8; 8];
+    let config_parser = ConfigManager::global().get_parser();
+    let (sender, receiver) = mpsc::channel();
+    let poll_interval = Duration::from_millis(app_config.reconnect_poll_interval_ms);
 
-                match device.read_timeout(&mut buf, 142) {
-                    Ok(len) if len > 0 => {
-                        println!("Received {} bytes from HID device: {:?}", len, &buf[..len]);
-                        if let Err(e) = manager.process_hid_data(&buf) {
-                            eprintln!("Error handling data: {e}");
-                        }
-                    }
-                    Ok(_) => {
-                        if let Err(e) = manager.process_timers() {
-                            eprintln!("Error processing timers: {e}");
-                        }
-                        if let Err(e) = manager.process_button_timeouts() {
-                            eprintln!("Error processing button timeouts: {e}");
-                        }
-                    }
-                    Err(err) => {
-                        eprintln!("Error reading from device: {err}");
-                        return Err(anyhow!("Error reading from device: {}", err));
+    let (has_profiles, included_files) = {
+        let parser = config_parser
+            .lock()
+            .map_err(|e| anyhow!("Failed to lock config: {}", e))?;
+        let config = parser.config();
+        (!config.profiles.is_empty(), config.included_files.clone())
+    };
+    let mut focus_watcher_started = false;
+    if has_profiles {
+        focus_watcher::spawn_focus_watcher(sender.clone());
+        focus_watcher_started = true;
+    }
+    let mut config_watcher = match config_watcher::spawn_config_watcher(
+        ConfigManager::get_config_path(),
+        &included_files,
+        sender.clone(),
+    ) {
+        Ok(watcher) => Some(watcher),
+        Err(e) => {
+            eprintln!("⚠️  Could not watch the config file, hot reload is disabled: {e}");
+            None
+        }
+    };
+
+    // Profile switches and config reloads wait until no button is in the middle of a
+    // press, so every press keeps the bindings it started with until it is released
+    let mut pending_profile: Option<Option<String>> = None;
+    let mut pending_config: Option<TokenBasedConfig> = None;
+    let mut focused_window: Option<FocusedWindow> = None;
+    let mut connected: HashSet<String> = HashSet::new();
+    let mut last_scan: Option<Instant> = None;
+    let mut reported_missing = false;
+
+    loop {
+        // Periodically rescan so that pedals plugged in later, or re-enumerated
+        // after a USB hub reset, are picked up without restarting
+        if last_scan.is_none_or(|scanned| scanned.elapsed() >= poll_interval) {
+            let config = config_parser
+                .lock()
+                .map_err(|e| anyhow!("Failed to lock config: {}", e))?
+                .config()
+                .clone();
+
+            connect_new_pedals(&mut api, &config, &mut manager, &mut connected, &sender);
+            last_scan = Some(Instant::now());
+
+            if connected.is_empty() && !reported_missing {
+                device_discovery::report_no_pedals_found(&api, &config);
+                reported_missing = true;
+            } else if !connected.is_empty() {
+                reported_missing = false;
+            }
+        }
+
+        match receiver.recv_timeout(manager.timer_poll_interval()) {
+            Ok(DeviceMessage::Report { path, data }) => {
+                if let Err(e) = manager.process_hid_data(&path, &data) {
+                    eprintln!("Error handling data: {e}");
+                }
+            }
+            Ok(DeviceMessage::Disconnected { path, error }) => {
+                eprintln!("⚠️  Lost connection to the pedal at {path}: {error}");
+                connected.remove(&path);
+
+                if let Err(e) = manager.handle_device_disconnected(&path) {
+                    eprintln!("Error cleaning up after disconnect: {e}");
+                }
+
+                println!(
+                    "\nWaiting for the pedal to be reconnected (polling every {}ms)...",
+                    app_config.reconnect_poll_interval_ms
+                );
+            }
+            Ok(DeviceMessage::FocusChanged(window)) => {
+                let parser = config_parser
+                    .lock()
+                    .map_err(|e| anyhow!("Failed to lock config: {}", e))?;
+                let profile = parser.config().profile_for(&window).map(str::to_string);
+                if profile.as_deref() != parser.active_profile() {
+                    println!(
+                        "🪟 Focus changed to \"{}\" ({}) - switching to profile {}",
+                        window.title,
+                        window.class,
+                        profile.as_deref().unwrap_or("<none>")
+                    );
+                    pending_profile = Some(profile);
+                } else {
+                    pending_profile = None;
+                }
+                focused_window = Some(window);
+            }
+            Ok(DeviceMessage::ConfigChanged) => match ConfigManager::read_config_for_reload() {
+                Ok(config) => {
+                    println!(
+                        "🔄 Config file changed - new configuration is valid and will be applied once all buttons are released"
+                    );
+                    // Includes may have been added or removed, and symlinks repointed
+                    if let Some(watcher) = &mut config_watcher
+                        && let Err(e) = watcher.update(&config.included_files)
+                    {
+                        eprintln!("⚠️  Could not watch every included file: {e}");
                     }
+                    pending_config = Some(config);
+                }
+                Err(e) => {
+                    eprintln!("❌ Config file changed but could not be loaded: {e}");
+                    eprintln!("   Keeping the last good configuration");
                 }
+            },
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(anyhow!("Device event channel closed unexpectedly"));
             }
         }
-        None => {
-            eprintln!("\nError:");
-            eprintln!("❌ Elgato Stream Deck Pedal not found.");
-            eprintln!("Please ensure:");
-            eprintln!("   - The device is connected via USB");
-            eprintln!("   - Your user has the correct permissions (input group)");
-            eprintln!("   - The device is not being used by another application");
-            Err(anyhow!(
-                "Device not found for target manufacturer {} and target product {}.",
-                target_manufacturer,
-                target_product,
-            ))
+
+        if let Err(e) = manager.process_button_timeouts() {
+            eprintln!("Error processing button timeouts: {e}");
+        }
+
+        if manager.is_idle()
+            && let Some(config) = pending_config.take()
+        {
+            let profile = focused_window
+                .as_ref()
+                .and_then(|window| config.profile_for(window))
+                .map(str::to_string);
+            let needs_focus_watcher = !config.profiles.is_empty() && !focus_watcher_started;
+            {
+                let mut parser = config_parser
+                    .lock()
+                    .map_err(|e| anyhow!("Failed to lock config: {}", e))?;
+                parser.replace_config(config);
+                parser.set_active_profile(profile);
+            }
+            pending_profile = None;
+
+            if let Err(e) = manager.reload_device_configs() {
+                eprintln!("Error applying reloaded config: {e}");
+            }
+            if needs_focus_watcher {
+                focus_watcher::spawn_focus_watcher(sender.clone());
+                focus_watcher_started = true;
+            }
+            println!("✅ Configuration reloaded");
+        }
+
+        if pending_profile.is_some() && manager.is_idle() {
+            let profile = pending_profile.take().flatten();
+            config_parser
+                .lock()
+                .map_err(|e| anyhow!("Failed to lock config: {}", e))?
+                .set_active_profile(profile);
+        }
+    }
+}
+
+/// Open every pedal that is present but not yet connected, and start a reader
+/// thread for each of them.
+fn connect_new_pedals(
+    api: &mut HidApi,
+    config: &TokenBasedConfig,
+    manager: &mut HoldIntentInputActionManager,
+    connected: &mut HashSet<String>,
+    sender: &Sender<DeviceMessage>,
+) {
+    if let Err(e) = api.refresh_devices() {
+        eprintln!("Failed to refresh HID device list: {e}");
+        return;
+    }
+
+    for device_info in device_discovery::find_pedals(api, config) {
+        let identity = DeviceIdentity::from_device_info(device_info);
+        if connected.contains(&identity.path) {
+            continue;
+        }
+
+        println!(
+            "\nSuccess:\n✅ Found target device: Vendor ID: {}, Product ID: {}, Manufacturer: '{}', Product: '{}', Serial: '{}'",
+            device_info.vendor_id(),
+            device_info.product_id(),
+            device_info.manufacturer_string().unwrap_or_default(),
+            device_info.product_string().unwrap_or_default(),
+            identity.serial_number.as_deref().unwrap_or("unknown")
+        );
+
+        let device = match api.open_path(device_info.path()) {
+            Ok(device) => device,
+            Err(error) => {
+                eprintln!("❌ Failed to open the target device: {error}");
+                eprintln!(
+                    "Make sure you have the correct permissions (try adding your user to the 'input' group)"
+                );
+                continue;
+            }
+        };
+
+        if let Err(e) = manager.add_device(identity.clone()) {
+            eprintln!("Failed to set up pedal {}: {e}", identity.label());
+            continue;
+        }
+
+        match device_discovery::spawn_reader(device, identity.clone(), sender.clone()) {
+            Ok(_) => {
+                println!(
+                    "Listening to events from pedal {}. Press Ctrl+C to exit...\n\n",
+                    identity.label()
+                );
+                connected.insert(identity.path);
+            }
+            Err(e) => {
+                eprintln!("Failed to start reader for pedal {}: {e}", identity.label());
+                let _ = manager.handle_device_disconnected(&identity.path);
+            }
         }
     }
 }
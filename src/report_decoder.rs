@@ -0,0 +1,142 @@
+use crate::button_types::ButtonInput;
+use crate::token_based_config::{ButtonName, PhysicalButtonName};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Turns raw HID input reports into the current state of each pedal button.
+///
+/// Decoders report the full state of every button they know about; the
+/// `HoldIntentParser` takes care of detecting press/release transitions.
+pub trait ReportDecoder: Send {
+    /// Name used in log output
+    fn name(&self) -> &'static str;
+
+    /// Decode a report, returning `None` for reports that carry no button data
+    fn decode(&self, report: &[u8]) -> Option<Vec<ButtonInput>>;
+}
+
+/// Decoder selection in the device configuration, e.g. `{"type": "infinity_in_usb_2"}`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DecoderConfig {
+    /// Elgato Stream Deck Pedal: one byte per button at offsets 4, 5 and 6
+    #[default]
+    StreamDeckPedal,
+    /// Infinity IN-USB-2: little-endian bitmask, 0x1 left, 0x2 middle, 0x4 right
+    InfinityInUsb2,
+    /// PCsensor/iKKEGOL FootSwitch in keyboard mode: buttons are recognised by
+    /// the HID usage codes they are programmed to send (defaults to a, b, c)
+    PcsensorFootswitch {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        keycodes: Option<Vec<u8>>,
+    },
+    /// Any pedal reporting one bit per button: `masks[i]` is tested against
+    /// the byte at `byte_offset` for button i
+    Bitmask { byte_offset: usize, masks: Vec<u8> },
+}
+
+impl DecoderConfig {
+    /// USB vendor/product ID used when the device block does not set one. `Bitmask`
+    /// fits any pedal, so it has none and the device block has to set both.
+    pub fn default_ids(&self) -> Option<(u16, u16)> {
+        match self {
+            DecoderConfig::StreamDeckPedal => Some((
+                crate::device_discovery::ELGATO_VENDOR_ID,
+                crate::device_discovery::STREAM_DECK_PEDAL_PRODUCT_ID,
+            )),
+            DecoderConfig::InfinityInUsb2 => Some((0x05f3, 0x00ff)),
+            DecoderConfig::PcsensorFootswitch { .. } => Some((0x0c45, 0x7403)),
+            DecoderConfig::Bitmask { .. } => None,
+        }
+    }
+
+    pub fn build(&self) -> Box<dyn ReportDecoder> {
+        match self {
+            DecoderConfig::StreamDeckPedal => Box::new(StreamDeckPedalDecoder),
+            DecoderConfig::InfinityInUsb2 => Box::new(InfinityInUsb2Decoder),
+            DecoderConfig::PcsensorFootswitch { keycodes } => Box::new(KeyboardReportDecoder {
+                // HID usage codes for 'a', 'b' and 'c', the factory programming
+                keycodes: keycodes.clone().unwrap_or_else(|| vec![0x04, 0x05, 0x06]),
+            }),
+            DecoderConfig::Bitmask { byte_offset, masks } => Box::new(BitmaskDecoder {
+                byte_offset: *byte_offset,
+                masks: masks.clone(),
+            }),
+        }
+    }
+}
+
+fn button_inputs(states: impl IntoIterator<Item = bool>) -> Vec<ButtonInput> {
+    states
+        .into_iter()
+        .enumerate()
+        .map(|(index, is_pressed)| ButtonInput {
+            button_name: ButtonName::Physical(PhysicalButtonName::from_index(index)),
+            is_pressed,
+        })
+        .collect()
+}
+
+pub struct StreamDeckPedalDecoder;
+
+impl ReportDecoder for StreamDeckPedalDecoder {
+    fn name(&self) -> &'static str {
+        "Stream Deck Pedal"
+    }
+
+    fn decode(&self, report: &[u8]) -> Option<Vec<ButtonInput>> {
+        let buttons = report.get(4..7)?;
+        Some(button_inputs(buttons.iter().map(|byte| byte & 0x01 != 0)))
+    }
+}
+
+pub struct InfinityInUsb2Decoder;
+
+impl ReportDecoder for InfinityInUsb2Decoder {
+    fn name(&self) -> &'static str {
+        "Infinity IN-USB-2"
+    }
+
+    fn decode(&self, report: &[u8]) -> Option<Vec<ButtonInput>> {
+        let mask = *report.first()?;
+        Some(button_inputs([0x01, 0x02, 0x04].map(|bit| mask & bit != 0)))
+    }
+}
+
+/// Boot-protocol keyboard report: modifiers, reserved, then up to six usage codes
+pub struct KeyboardReportDecoder {
+    keycodes: Vec<u8>,
+}
+
+impl ReportDecoder for KeyboardReportDecoder {
+    fn name(&self) -> &'static str {
+        "PCsensor FootSwitch"
+    }
+
+    fn decode(&self, report: &[u8]) -> Option<Vec<ButtonInput>> {
+        let pressed = report.get(2..)?;
+        Some(button_inputs(
+            self.keycodes
+                .iter()
+                .map(|keycode| pressed.contains(keycode)),
+        ))
+    }
+}
+
+pub struct BitmaskDecoder {
+    byte_offset: usize,
+    masks: Vec<u8>,
+}
+
+impl ReportDecoder for BitmaskDecoder {
+    fn name(&self) -> &'static str {
+        "Bitmask"
+    }
+
+    fn decode(&self, report: &[u8]) -> Option<Vec<ButtonInput>> {
+        let byte = *report.get(self.byte_offset)?;
+        Some(button_inputs(
+            self.masks.iter().map(|mask| byte & mask != 0),
+        ))
+    }
+}
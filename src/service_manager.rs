@@ -1,3 +1,4 @@
+use crate::config_manager::ConfigManager;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
@@ -124,6 +125,16 @@ impl ServiceManager {
 
     /// Generate the systemd service file content
     fn generate_service_file(&self, binary_path: &str) -> String {
+        // A config chosen with --config or ELGATO_PEDAL_CONFIG is passed on to the service
+        let mut exec_start = format!("{binary_path} run");
+        let mut writable_dirs = vec![ConfigManager::user_config_dir().display().to_string()];
+        if let Some(config_path) = ConfigManager::explicit_config_path() {
+            exec_start.push_str(&format!(" --config \"{}\"", config_path.display()));
+            if let Some(dir) = config_path.parent() {
+                writable_dirs.push(dir.display().to_string());
+            }
+        }
+
         format!(
             r#"[Unit]
 Description=Elgato Stream Deck Pedal Controller
@@ -133,7 +144,7 @@ Wants=graphical-session.target
 
 [Service]
 Type=simple
-ExecStart={} run
+ExecStart={}
 Environment=DISPLAY=:0
 
 # Security settings
@@ -141,13 +152,13 @@ NoNewPrivileges=true
 PrivateTmp=true
 ProtectSystem=strict
 ProtectHome=false
-ReadWritePaths={}/.config
+ReadWritePaths={}
 
 [Install]
 WantedBy=graphical-session.target
 "#,
-            binary_path,
-            std::env::var("HOME").unwrap_or_else(|_| "/home/user".to_string())
+            exec_start,
+            writable_dirs.join(" ")
         )
     }
 
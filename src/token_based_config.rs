@@ -1,257 +1,2485 @@
-use enigo::Key;
+use crate::config_manager::{ConfigFormat, ConfigManager, expand_home};
+use crate::device_discovery::DeviceIdentity;
+use crate::report_decoder::DecoderConfig;
+use enigo::{Axis, Button, Direction, Key};
+use schemars::{JsonSchema, Schema, SchemaGenerator, json_schema};
+use serde::de::value::{EnumAccessDeserializer, StringDeserializer};
+use serde::de::{self, IgnoredAny, IntoDeserializer, MapAccess, Visitor};
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
+/// Identity of a physical button: its position in the pedal's HID report.
+/// Buttons are configured under `button_<index>` keys and may carry a `label`.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub enum PhysicalButtonName {
-    Button0,
-    Button1,
-    Button2,
+pub struct PhysicalButtonName {
+    pub index: usize,
 }
 
 impl PhysicalButtonName {
-    pub fn as_str(&self) -> &str {
+    pub fn from_index(index: usize) -> Self {
+        PhysicalButtonName { index }
+    }
+
+    /// Parse a config key such as "button_2"
+    pub fn from_key(key: &str) -> Option<Self> {
+        key.strip_prefix("button_")?
+            .parse()
+            .ok()
+            .map(Self::from_index)
+    }
+
+    /// Config key of this button, e.g. "button_2"
+    pub fn key(&self) -> String {
+        format!("button_{}", self.index)
+    }
+}
+
+impl std::fmt::Display for PhysicalButtonName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "button_{}", self.index)
+    }
+}
+
+/// Several physical buttons pressed together, configured under keys such as
+/// "button_0+button_2". Members are stored as a bitmask of button indices.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ChordName {
+    members: u64,
+}
+
+impl ChordName {
+    /// Parse a config key such as "button_0+button_2"; a chord needs at least two
+    /// distinct buttons, each with an index below 64 and named only once
+    pub fn from_key(key: &str) -> Option<Self> {
+        let mut members = 0u64;
+        for part in key.split('+') {
+            let button = PhysicalButtonName::from_key(part.trim())?;
+            if button.index >= 64 || members & (1 << button.index) != 0 {
+                return None;
+            }
+            members |= 1 << button.index;
+        }
+
+        (members.count_ones() >= 2).then_some(ChordName { members })
+    }
+
+    pub fn contains(&self, button_name: PhysicalButtonName) -> bool {
+        button_name.index < 64 && self.members & (1 << button_name.index) != 0
+    }
+
+    pub fn members(&self) -> impl Iterator<Item = PhysicalButtonName> + '_ {
+        (0..64)
+            .filter(|index| self.members & (1 << index) != 0)
+            .map(PhysicalButtonName::from_index)
+    }
+
+    pub fn member_count(&self) -> u32 {
+        self.members.count_ones()
+    }
+}
+
+impl std::fmt::Display for ChordName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let members: Vec<String> = self.members().map(|button| button.key()).collect();
+        write!(f, "{}", members.join("+"))
+    }
+}
+
+/// Anything the hold-intent engine tracks as one button: a physical button or a chord
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ButtonName {
+    Physical(PhysicalButtonName),
+    Chord(ChordName),
+}
+
+impl std::fmt::Display for ButtonName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            PhysicalButtonName::Button0 => "button_0",
-            PhysicalButtonName::Button1 => "button_1",
-            PhysicalButtonName::Button2 => "button_2",
+            ButtonName::Physical(button) => button.fmt(f),
+            ButtonName::Chord(chord) => chord.fmt(f),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct DeviceConfig {
+    /// USB vendor ID to match, as a number or hex string (defaults to the decoder's vendor)
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "usb_id")]
+    #[schemars(schema_with = "usb_id::schema")]
+    pub vendor_id: Option<u16>,
+    /// USB product ID to match, as a number or hex string (defaults to the decoder's product)
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "usb_id")]
+    #[schemars(schema_with = "usb_id::schema")]
+    pub product_id: Option<u16>,
+    /// Serial number of the pedal this block applies to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
+    /// hidraw device node of the pedal this block applies to, e.g. "/dev/hidraw3"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hidraw: Option<String>,
+    /// HID report layout of the pedal (defaults to the Stream Deck Pedal)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decoder: Option<DecoderConfig>,
     pub button_count: usize,
     pub buttons: HashMap<String, ButtonConfig>,
+    /// Actions bound to pressing several buttons together, e.g. "button_0+button_2"
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub chords: HashMap<String, ButtonConfig>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub settings: Option<DeviceSettings>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl DeviceConfig {
+    /// Find the configuration of a physical button or a chord
+    pub fn button_config(&self, button_name: ButtonName) -> Option<&ButtonConfig> {
+        find_button_config(&self.buttons, &self.chords, button_name)
+    }
+
+    /// Whether the pedal has every button of the button or chord `key`
+    pub fn has_buttons_of(&self, key: &str) -> bool {
+        match (ChordName::from_key(key), PhysicalButtonName::from_key(key)) {
+            (Some(chord), _) => chord
+                .members()
+                .all(|button| button.index < self.button_count),
+            (None, Some(button)) => button.index < self.button_count,
+            (None, None) => false,
+        }
+    }
+
+    pub fn decoder(&self) -> DecoderConfig {
+        self.decoder.clone().unwrap_or_default()
+    }
+
+    /// Vendor ID to match, `None` when neither the block nor its decoder has one
+    pub fn vendor_id(&self) -> Option<u16> {
+        self.vendor_id
+            .or_else(|| Some(self.decoder().default_ids()?.0))
+    }
+
+    /// Product ID to match, `None` when neither the block nor its decoder has one
+    pub fn product_id(&self) -> Option<u16> {
+        self.product_id
+            .or_else(|| Some(self.decoder().default_ids()?.1))
+    }
+
+    /// Check whether a connected HID device is selected by this block.
+    /// Every matcher that is set must agree; VID/PID fall back to the decoder's defaults.
+    pub fn matches(&self, device: &DeviceIdentity) -> bool {
+        self.vendor_id() == Some(device.vendor_id)
+            && self.product_id() == Some(device.product_id)
+            && self
+                .serial_number
+                .as_deref()
+                .is_none_or(|serial| device.serial_number.as_deref() == Some(serial))
+            && self
+                .hidraw
+                .as_deref()
+                .is_none_or(|hidraw| device.path == hidraw)
+    }
+}
+
+/// Serde helpers for USB IDs, accepting either a number or a hex string ("0x0fd9" or "0fd9")
+mod usb_id {
+    use schemars::{Schema, SchemaGenerator, json_schema};
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn schema(_: &mut SchemaGenerator) -> Schema {
+        json_schema!({
+            "anyOf": [
+                {"type": "integer", "minimum": 0, "maximum": 65535},
+                {"type": "string", "pattern": "^(0[xX])?[0-9a-fA-F]{1,4}$"}
+            ]
+        })
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawUsbId {
+        Number(u16),
+        Hex(String),
+    }
+
+    pub fn serialize<S: Serializer>(id: &Option<u16>, serializer: S) -> Result<S::Ok, S::Error> {
+        match id {
+            Some(id) => serializer.serialize_str(&format!("0x{id:04x}")),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u16>, D::Error> {
+        match Option::<RawUsbId>::deserialize(deserializer)? {
+            None => Ok(None),
+            Some(RawUsbId::Number(id)) => Ok(Some(id)),
+            Some(RawUsbId::Hex(text)) => {
+                let digits = text
+                    .strip_prefix("0x")
+                    .or_else(|| text.strip_prefix("0X"))
+                    .unwrap_or(&text);
+                // from_str_radix would also take a sign
+                digits
+                    .bytes()
+                    .all(|digit| digit.is_ascii_hexdigit())
+                    .then(|| u16::from_str_radix(digits, 16).ok())
+                    .flatten()
+                    .map(Some)
+                    .ok_or_else(|| D::Error::custom(format!("invalid USB ID \"{text}\"")))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct DeviceSettings {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hold_threshold_time_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multi_tap_window_ms: Option<u64>,
+    /// How long presses of chord members wait for the rest of the chord
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chord_window_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_interval_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_min_interval_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_acceleration: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold_stages: Option<Vec<HoldStage>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when_busy: Option<WhenBusy>,
+    /// How key and mouse input is simulated instead of picking by session, only read
+    /// from the `device` block
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_backend: Option<InputBackendKind>,
+}
+
+/// Ways of simulating input. Without a choice, the one best suited to the session is
+/// used, falling back to the others when it cannot be started.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema, clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum InputBackendKind {
+    /// Enigo, through the X11 or Wayland session
+    Enigo,
+    /// Virtual keyboard and mouse created through /dev/uinput
+    Uinput,
+    /// Virtual keyboard and pointer protocols of wlroots compositors (`wayland` feature)
+    Wayland,
+    /// Sends nothing and only logs the input
+    Recorder,
+}
+
+impl std::fmt::Display for InputBackendKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputBackendKind::Enigo => f.pad("enigo"),
+            InputBackendKind::Uinput => f.pad("uinput"),
+            InputBackendKind::Wayland => f.pad("wayland"),
+            InputBackendKind::Recorder => f.pad("recorder"),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ButtonConfig {
-    pub actions: HashMap<String, Vec<ActionItem>>, // "PRESSED", "HELD", etc.
+    /// Optional human readable name shown in log output
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default)]
+    pub actions: HashMap<String, Vec<ExecutableAction>>, // "PRESSED", "HELD", etc.
+    /// Actions used instead of `actions` while the named layer is active
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub layers: HashMap<String, HashMap<String, Vec<ExecutableAction>>>,
+    /// Turns this button into a layer key; it then has no actions of its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub layer_key: Option<LayerKey>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub settings: Option<ButtonSettings>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl ButtonConfig {
+    /// Action map for the active layer, falling back to the base map when this
+    /// button has no bindings for that layer
+    pub fn actions_for_layer(
+        &self,
+        layer: Option<&str>,
+    ) -> &HashMap<String, Vec<ExecutableAction>> {
+        layer
+            .and_then(|layer| self.layers.get(layer))
+            .unwrap_or(&self.actions)
+    }
+}
+
+/// Layer key binding, e.g. `{"layer": "edit", "mode": "toggle"}`
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct LayerKey {
+    pub layer: String,
+    #[serde(default)]
+    pub mode: LayerMode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerMode {
+    /// Layer is active while the key is held down
+    #[default]
+    Momentary,
+    /// Each press switches the layer on or off
+    Toggle,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct ButtonSettings {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hold_threshold_time_ms: Option<u64>,
+    /// Maximum gap between a release and the next press for them to count as one multi-tap
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub multi_tap_window_ms: Option<u64>,
+    /// Time between REPEAT events while the button is held
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_interval_ms: Option<u64>,
+    /// Shortest interval that acceleration can bring the repeat rate down to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_min_interval_ms: Option<u64>,
+    /// Factor applied to the interval after every REPEAT, e.g. 0.8 to speed up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat_acceleration: Option<f64>,
+    /// Further hold stages after HELD, in the order they fire
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold_stages: Option<Vec<HoldStage>>,
+    /// What this button's actions do while earlier actions are still running
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when_busy: Option<WhenBusy>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ActionItem {
-    #[serde(rename = "type")]
-    pub action_type: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub direction: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub value: Option<ActionValue>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub auto_release: Option<bool>,
+/// What happens when a binding fires while an earlier action sequence is still running
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WhenBusy {
+    /// Start once the running and queued sequences have finished
+    #[default]
+    Queue,
+    /// Stop the running sequence, drop the queued ones of the same button and start right away
+    Preempt,
+}
+
+/// A hold stage beyond HELD, e.g. `{"name": "LONG_HELD", "threshold_ms": 2000}`.
+/// Its actions are configured under `name` in the button's `actions`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct HoldStage {
+    pub name: String,
+    /// Time from the press until this stage fires
+    pub threshold_ms: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(untagged)]
-pub enum ActionValue {
-    Key(Key),      // Try Key first - Enigo can deserialize "MicMute", "Meta", etc.
-    Unicode(char), // Handle {"Unicode": "f"} pattern
-    Other(u32),    // Handle {"Other": 13} pattern
-    Number(u64),   // Then try Number for durations, etc.
-    Text(String),  // Finally try Text as fallback for actual text input
+fn find_button_config<'a>(
+    buttons: &'a HashMap<String, ButtonConfig>,
+    chords: &'a HashMap<String, ButtonConfig>,
+    button_name: ButtonName,
+) -> Option<&'a ButtonConfig> {
+    match button_name {
+        ButtonName::Physical(button) => buttons.get(&button.key()),
+        ButtonName::Chord(chord) => chords
+            .iter()
+            .find(|(key, _)| ChordName::from_key(key) == Some(chord))
+            .map(|(_, config)| config),
+    }
+}
+
+/// Window that currently has keyboard focus, as reported by the focus watcher
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FocusedWindow {
+    /// X11 WM_CLASS class or Wayland app_id
+    pub class: String,
+    pub title: String,
+}
+
+/// Button bindings that replace the device's own while a matching window is focused
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Profile {
+    pub name: String,
+    /// The profile applies when any of these rules matches the focused window
+    #[serde(rename = "match")]
+    pub rules: Vec<WindowRule>,
+    #[serde(default)]
+    pub buttons: HashMap<String, ButtonConfig>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub chords: HashMap<String, ButtonConfig>,
+}
+
+impl Profile {
+    pub fn button_config(&self, button_name: ButtonName) -> Option<&ButtonConfig> {
+        find_button_config(&self.buttons, &self.chords, button_name)
+    }
+
+    pub fn matches(&self, window: &FocusedWindow) -> bool {
+        self.rules.iter().any(|rule| rule.matches(window))
+    }
+}
+
+/// Case-insensitive substring match on the window class and/or title; every field set must match
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct WindowRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+impl WindowRule {
+    pub fn matches(&self, window: &FocusedWindow) -> bool {
+        let contains = |haystack: &str, needle: &Option<String>| {
+            needle
+                .as_ref()
+                .is_none_or(|needle| haystack.to_lowercase().contains(&needle.to_lowercase()))
+        };
+        (self.class.is_some() || self.title.is_some())
+            && contains(&window.class, &self.class)
+            && contains(&window.title, &self.title)
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// One step in the location of a config value: an object key or an array index
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+/// Location of a value in the config file, displayed as e.g. `devices[0].buttons.button_1`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConfigPath(Vec<PathSegment>);
+
+impl ConfigPath {
+    pub fn key(&self, key: &str) -> Self {
+        let mut path = self.clone();
+        path.0.push(PathSegment::Key(key.to_string()));
+        path
+    }
+
+    pub fn index(&self, index: usize) -> Self {
+        let mut path = self.clone();
+        path.0.push(PathSegment::Index(index));
+        path
+    }
+
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for ConfigPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                PathSegment::Key(key) if i == 0 => write!(f, "{key}")?,
+                PathSegment::Key(key) => write!(f, ".{key}")?,
+                PathSegment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A problem found while validating the config
+#[derive(Debug, Clone)]
+pub struct ConfigProblem {
+    pub path: ConfigPath,
+    pub message: String,
+}
+
+impl ConfigProblem {
+    pub fn new(path: ConfigPath, message: impl Into<String>) -> Self {
+        Self {
+            path,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.segments().is_empty() {
+            return write!(f, "{}", self.message);
+        }
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+impl std::error::Error for ConfigProblem {}
+
+/// Events a button can bind actions to, besides its hold stage names
+const BUTTON_EVENTS: [&str; 6] = [
+    "PRESSED",
+    "DOUBLE_PRESSED",
+    "TRIPLE_PRESSED",
+    "HELD",
+    "REPEAT",
+    "RELEASING",
+];
+
+/// Check the event names of a button, in its base map and every layer, and that its
+/// hold stages come after a plain HELD
+fn check_button_events(
+    path: &ConfigPath,
+    button: &ButtonConfig,
+    device_settings: Option<&DeviceSettings>,
+    problems: &mut Vec<ConfigProblem>,
+) {
+    let button_settings = button.settings.as_ref();
+    let stages = button_settings
+        .and_then(|settings| settings.hold_stages.as_ref())
+        .or(device_settings.and_then(|settings| settings.hold_stages.as_ref()));
+
+    // Without a plain HELD the first stage replaces the hold threshold instead
+    let binds_held = std::iter::once(&button.actions)
+        .chain(button.layers.values())
+        .any(|actions| actions.contains_key("HELD"));
+    if binds_held && let Some(first_stage) = stages.and_then(|stages| stages.first()) {
+        let threshold_ms = button_settings
+            .and_then(|settings| settings.hold_threshold_time_ms)
+            .or(device_settings.and_then(|settings| settings.hold_threshold_time_ms))
+            .unwrap_or(crate::AppConfig::default().default_hold_threshold_ms);
+        if first_stage.threshold_ms <= threshold_ms {
+            problems.push(ConfigProblem::new(
+                path.key("settings").key("hold_stages"),
+                format!(
+                    "hold stage \"{}\" at {}ms must come after HELD at {}ms",
+                    first_stage.name, first_stage.threshold_ms, threshold_ms
+                ),
+            ));
+        }
+    }
+
+    let stage_names: Vec<&str> = std::iter::once("HELD")
+        .chain(
+            stages
+                .into_iter()
+                .flatten()
+                .map(|stage| stage.name.as_str()),
+        )
+        .collect();
+    let is_known_event = |event: &str| {
+        BUTTON_EVENTS.contains(&event)
+            || stage_names.contains(&event)
+            || event
+                .strip_prefix("RELEASING_")
+                .is_some_and(|stage| stage_names.contains(&stage))
+    };
+
+    let maps = std::iter::once((path.key("actions"), &button.actions)).chain(
+        button
+            .layers
+            .iter()
+            .map(|(layer, actions)| (path.key("layers").key(layer), actions)),
+    );
+    for (map_path, actions) in maps {
+        for event in actions.keys().filter(|event| !is_known_event(event)) {
+            problems.push(ConfigProblem::new(
+                map_path.key(event),
+                format!(
+                    "unknown event \"{event}\", expected one of {}, a hold stage name or RELEASING_<stage>",
+                    BUTTON_EVENTS.join(", ")
+                ),
+            ));
+        }
+    }
+}
+
+/// Combine the problems of a profile binding checked with each device block it applies
+/// to: those found with every block are reported once, the others name their block
+fn merge_block_problems(per_block: Vec<(ConfigPath, Vec<ConfigProblem>)>) -> Vec<ConfigProblem> {
+    let same = |a: &ConfigProblem, b: &ConfigProblem| a.path == b.path && a.message == b.message;
+    let mut merged: Vec<ConfigProblem> = Vec::new();
+    for (block, problems) in &per_block {
+        for problem in problems {
+            let everywhere = per_block
+                .iter()
+                .all(|(_, others)| others.iter().any(|other| same(other, problem)));
+            let problem = if everywhere {
+                problem.clone()
+            } else {
+                ConfigProblem::new(
+                    problem.path.clone(),
+                    format!("{} (on pedals configured by {block})", problem.message),
+                )
+            };
+            if !merged.iter().any(|other| same(other, &problem)) {
+                merged.push(problem);
+            }
+        }
+    }
+    merged
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[schemars(title = "Elgato Pedal Controller configuration")]
 pub struct TokenBasedConfig {
+    /// JSON Schema used by editors for completion and validation
+    #[serde(rename = "$schema", default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<String>,
+    /// Format version of the file, see `CONFIG_VERSION`
+    #[serde(default = "legacy_config_version")]
+    pub version: u32,
+    /// Files with shared `macros`, relative to this file or starting with `~/`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    /// Named action lists that buttons use with `{"type": "Macro", "name": ...}`
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub macros: HashMap<String, Vec<ExecutableAction>>,
+    /// Configuration used by any pedal without a matching entry in `devices`
     pub device: DeviceConfig,
+    /// Per-pedal configurations, the first block whose matchers agree wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<DeviceConfig>,
+    /// Per-application bindings, the first profile matching the focused window wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub profiles: Vec<Profile>,
+    /// Files read through `include`, filled in by [`TokenBasedParser::resolve_config`]
+    #[serde(skip)]
+    pub included_files: Vec<PathBuf>,
 }
 
-/// Enhanced action for execution with state management
-#[derive(Debug, Clone)]
+impl TokenBasedConfig {
+    /// JSON Schema of the config file, in draft 7 which most editors understand
+    pub fn json_schema() -> Schema {
+        schemars::generate::SchemaSettings::draft07()
+            .into_generator()
+            .into_root_schema_for::<Self>()
+    }
+
+    /// Pick the configuration block for a connected pedal
+    pub fn device_config(&self, device: &DeviceIdentity) -> &DeviceConfig {
+        self.devices
+            .iter()
+            .find(|config| config.matches(device))
+            .unwrap_or(&self.device)
+    }
+
+    /// Reject button keys that are malformed or refer to buttons beyond `button_count`
+    pub fn validate(&self) -> Result<(), String> {
+        match self.structural_problems().into_iter().next() {
+            Some(problem) => Err(problem.to_string()),
+            None => Ok(()),
+        }
+    }
+
+    /// Every problem in the config, including event names no button event can match.
+    /// Used by the `validate` command.
+    pub fn problems(&self) -> Vec<ConfigProblem> {
+        let mut problems = self.structural_problems();
+
+        for (path, device_config) in self.device_blocks() {
+            for (map, buttons) in [
+                ("buttons", &device_config.buttons),
+                ("chords", &device_config.chords),
+            ] {
+                for (key, button) in buttons {
+                    check_button_events(
+                        &path.key(map).key(key),
+                        button,
+                        device_config.settings.as_ref(),
+                        &mut problems,
+                    );
+                }
+            }
+        }
+
+        // Profile bindings take their settings from the block of the pedal they are used on
+        for (i, profile) in self.profiles.iter().enumerate() {
+            let path = ConfigPath::default().key("profiles").index(i);
+            for (map, buttons) in [("buttons", &profile.buttons), ("chords", &profile.chords)] {
+                for (key, button) in buttons {
+                    let per_block = self
+                        .blocks_with_buttons(key)
+                        .into_iter()
+                        .map(|(block, device_config)| {
+                            let mut found = Vec::new();
+                            check_button_events(
+                                &path.key(map).key(key),
+                                button,
+                                device_config.settings.as_ref(),
+                                &mut found,
+                            );
+                            (block, found)
+                        })
+                        .collect();
+                    problems.extend(merge_block_problems(per_block));
+                }
+            }
+        }
+
+        problems.extend(self.layer_problems());
+        problems
+    }
+
+    /// Layers that bindings refer to but no layer key switches on, and layer keys for
+    /// layers that no button has bindings for. Layers are per pedal, and profiles can
+    /// apply to any pedal, so profile bindings are checked with each block they apply to.
+    fn layer_problems(&self) -> Vec<ConfigProblem> {
+        let layer_keys = |buttons: &mut dyn Iterator<Item = &ButtonConfig>| -> Vec<String> {
+            buttons
+                .filter_map(|button| button.layer_key.as_ref())
+                .map(|layer_key| layer_key.layer.clone())
+                .collect()
+        };
+        let bound_layers = |buttons: &mut dyn Iterator<Item = &ButtonConfig>| -> Vec<String> {
+            buttons
+                .flat_map(|button| button.layers.keys().cloned())
+                .collect()
+        };
+        let check = |button_path: &ConfigPath,
+                     button: &ButtonConfig,
+                     switchable: &[String],
+                     bound: &[String]| {
+            let mut problems = Vec::new();
+            for layer in button
+                .layers
+                .keys()
+                .filter(|layer| !switchable.contains(layer))
+            {
+                problems.push(ConfigProblem::new(
+                    button_path.key("layers").key(layer),
+                    format!("no layer key switches to layer \"{layer}\""),
+                ));
+            }
+            if let Some(layer_key) = &button.layer_key
+                && !bound.contains(&layer_key.layer)
+            {
+                problems.push(ConfigProblem::new(
+                    button_path.key("layer_key").key("layer"),
+                    format!("no button has bindings for layer \"{}\"", layer_key.layer),
+                ));
+            }
+            problems
+        };
+
+        let mut problems = Vec::new();
+        let all_profile_buttons = || {
+            self.profiles
+                .iter()
+                .flat_map(|profile| profile.buttons.values().chain(profile.chords.values()))
+        };
+        let profile_layer_keys = layer_keys(&mut all_profile_buttons());
+        let profile_bound_layers = bound_layers(&mut all_profile_buttons());
+        for (block, device_config) in self.device_blocks() {
+            let block_buttons = || {
+                device_config
+                    .buttons
+                    .values()
+                    .chain(device_config.chords.values())
+            };
+            let mut switchable = layer_keys(&mut block_buttons());
+            switchable.extend(profile_layer_keys.iter().cloned());
+            let mut bound = bound_layers(&mut block_buttons());
+            bound.extend(profile_bound_layers.iter().cloned());
+            for (map, buttons) in [
+                ("buttons", &device_config.buttons),
+                ("chords", &device_config.chords),
+            ] {
+                for (key, button) in buttons {
+                    problems.extend(check(&block.key(map).key(key), button, &switchable, &bound));
+                }
+            }
+        }
+
+        for (i, profile) in self.profiles.iter().enumerate() {
+            let path = ConfigPath::default().key("profiles").index(i);
+            let buttons: Vec<_> = (profile.buttons.iter().map(|entry| ("buttons", entry)))
+                .chain(profile.chords.iter().map(|entry| ("chords", entry)))
+                .collect();
+            for (map, (key, button)) in &buttons {
+                let per_block = self
+                    .blocks_with_buttons(key)
+                    .into_iter()
+                    .map(|(block, device_config)| {
+                        // The profile's bindings for buttons this pedal has, and the block's
+                        let on_pedal = || {
+                            buttons
+                                .iter()
+                                .filter(|(_, (key, _))| device_config.has_buttons_of(key))
+                                .map(|(_, (_, button))| *button)
+                                .chain(device_config.buttons.values())
+                                .chain(device_config.chords.values())
+                        };
+                        let switchable = layer_keys(&mut on_pedal());
+                        let bound = bound_layers(&mut on_pedal());
+                        let found = check(&path.key(map).key(key), button, &switchable, &bound);
+                        (block, found)
+                    })
+                    .collect();
+                problems.extend(merge_block_problems(per_block));
+            }
+        }
+        problems
+    }
+
+    /// The device blocks a profile binding for the button or chord `key` can apply to:
+    /// those whose pedals have all of its buttons
+    fn blocks_with_buttons(&self, key: &str) -> Vec<(ConfigPath, &DeviceConfig)> {
+        self.device_blocks()
+            .filter(|(_, device_config)| device_config.has_buttons_of(key))
+            .collect()
+    }
+
+    /// The `device` block followed by every entry of `devices`, with their paths
+    fn device_blocks(&self) -> impl Iterator<Item = (ConfigPath, &DeviceConfig)> {
+        std::iter::once((ConfigPath::default().key("device"), &self.device)).chain(
+            self.devices
+                .iter()
+                .enumerate()
+                .map(|(i, config)| (ConfigPath::default().key("devices").index(i), config)),
+        )
+    }
+
+    /// Problems that make the config unusable, checked every time it is loaded
+    fn structural_problems(&self) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+
+        for (block, device_config) in self.device_blocks() {
+            for (key, id) in [
+                ("vendor_id", device_config.vendor_id()),
+                ("product_id", device_config.product_id()),
+            ] {
+                if id.is_none() {
+                    problems.push(ConfigProblem::new(
+                        block.key(key),
+                        format!("the bitmask decoder fits any pedal and has no default {key}, set it here"),
+                    ));
+                }
+            }
+
+            for key in device_config.buttons.keys() {
+                let path = block.key("buttons").key(key);
+                match PhysicalButtonName::from_key(key) {
+                    Some(button) if button.index < device_config.button_count => {}
+                    Some(_) => problems.push(ConfigProblem::new(
+                        path,
+                        format!(
+                            "button does not exist, this device declares button_count = {} (button_0 to button_{})",
+                            device_config.button_count,
+                            device_config.button_count.saturating_sub(1)
+                        ),
+                    )),
+                    None => problems.push(ConfigProblem::new(
+                        path,
+                        "invalid button key, expected \"button_<index>\"",
+                    )),
+                }
+            }
+
+            let button_stages = (device_config.buttons.iter().map(|entry| ("buttons", entry)))
+                .chain(device_config.chords.iter().map(|entry| ("chords", entry)))
+                .map(|(map, (key, button))| {
+                    (
+                        block.key(map).key(key).key("settings").key("hold_stages"),
+                        button
+                            .settings
+                            .as_ref()
+                            .and_then(|s| s.hold_stages.as_ref()),
+                    )
+                });
+            let device_stages = std::iter::once((
+                block.key("settings").key("hold_stages"),
+                device_config
+                    .settings
+                    .as_ref()
+                    .and_then(|s| s.hold_stages.as_ref()),
+            ));
+            for (path, stages) in device_stages.chain(button_stages) {
+                let Some(stages) = stages else {
+                    continue;
+                };
+                if stages
+                    .windows(2)
+                    .any(|pair| pair[1].threshold_ms <= pair[0].threshold_ms)
+                {
+                    problems.push(ConfigProblem::new(
+                        path.clone(),
+                        "thresholds must increase from one stage to the next",
+                    ));
+                }
+                for (i, stage) in stages.iter().enumerate() {
+                    if BUTTON_EVENTS.contains(&stage.name.as_str())
+                        || stage.name.starts_with("RELEASING_")
+                    {
+                        problems.push(ConfigProblem::new(
+                            path.index(i).key("name"),
+                            format!(
+                                "\"{}\" is an event name, hold stages need a name of their own",
+                                stage.name
+                            ),
+                        ));
+                    } else if stages[..i].iter().any(|other| other.name == stage.name) {
+                        problems.push(ConfigProblem::new(
+                            path.index(i).key("name"),
+                            format!("hold stage \"{}\" is defined twice", stage.name),
+                        ));
+                    }
+                }
+            }
+
+            for key in device_config.chords.keys() {
+                let path = block.key("chords").key(key);
+                let Some(chord) = ChordName::from_key(key) else {
+                    problems.push(ConfigProblem::new(
+                        path,
+                        "invalid chord key, expected at least two different buttons such as \"button_0+button_2\"",
+                    ));
+                    continue;
+                };
+                if let Some(button) = chord
+                    .members()
+                    .find(|button| button.index >= device_config.button_count)
+                {
+                    problems.push(ConfigProblem::new(
+                        path,
+                        format!(
+                            "{button} does not exist, this device declares button_count = {}",
+                            device_config.button_count
+                        ),
+                    ));
+                }
+            }
+        }
+
+        // Profiles apply to whichever pedal is pressed, so their buttons have to exist
+        // on at least one of them
+        let max_button_count = self
+            .device_blocks()
+            .map(|(_, device_config)| device_config.button_count)
+            .max()
+            .unwrap_or_default();
+        let missing_button = |button: &PhysicalButtonName| {
+            format!(
+                "{button} does not exist, the largest button_count of any device is {max_button_count}"
+            )
+        };
+        for (i, profile) in self.profiles.iter().enumerate() {
+            let path = ConfigPath::default().key("profiles").index(i);
+            for key in profile.buttons.keys() {
+                let path = path.key("buttons").key(key);
+                match PhysicalButtonName::from_key(key) {
+                    Some(button) if button.index < max_button_count => {}
+                    Some(button) => {
+                        problems.push(ConfigProblem::new(path, missing_button(&button)))
+                    }
+                    None => problems.push(ConfigProblem::new(
+                        path,
+                        "invalid button key, expected \"button_<index>\"",
+                    )),
+                }
+            }
+            for key in profile.chords.keys() {
+                let path = path.key("chords").key(key);
+                let Some(chord) = ChordName::from_key(key) else {
+                    problems.push(ConfigProblem::new(
+                        path,
+                        "invalid chord key, expected at least two different buttons such as \"button_0+button_2\"",
+                    ));
+                    continue;
+                };
+                if let Some(button) = chord
+                    .members()
+                    .find(|button| button.index >= max_button_count)
+                {
+                    problems.push(ConfigProblem::new(path, missing_button(&button)));
+                }
+            }
+            if profile.rules.is_empty() {
+                problems.push(ConfigProblem::new(
+                    path.key("match"),
+                    format!("profile \"{}\" needs at least one rule", profile.name),
+                ));
+            }
+        }
+
+        problems
+    }
+
+    /// Every action list with its path: the base and layer actions of every button
+    /// and chord, in the device blocks and the profiles
+    fn action_lists_mut(&mut self) -> Vec<(ConfigPath, &mut Vec<ExecutableAction>)> {
+        let root = ConfigPath::default();
+        let blocks = std::iter::once((
+            root.key("device"),
+            &mut self.device.buttons,
+            &mut self.device.chords,
+        ))
+        .chain(self.devices.iter_mut().enumerate().map(|(i, config)| {
+            (
+                root.key("devices").index(i),
+                &mut config.buttons,
+                &mut config.chords,
+            )
+        }))
+        .chain(self.profiles.iter_mut().enumerate().map(|(i, profile)| {
+            (
+                root.key("profiles").index(i),
+                &mut profile.buttons,
+                &mut profile.chords,
+            )
+        }));
+
+        let mut lists = Vec::new();
+        for (block, buttons, chords) in blocks {
+            for (map, buttons) in [("buttons", buttons), ("chords", chords)] {
+                for (key, button) in buttons.iter_mut() {
+                    let button_path = block.key(map).key(key);
+                    for (event, actions) in button.actions.iter_mut() {
+                        lists.push((button_path.key("actions").key(event), actions));
+                    }
+                    for (layer, layer_actions) in button.layers.iter_mut() {
+                        for (event, actions) in layer_actions.iter_mut() {
+                            lists.push((button_path.key("layers").key(layer).key(event), actions));
+                        }
+                    }
+                }
+            }
+        }
+        lists
+    }
+
+    /// Name of the first profile matching the focused window
+    pub fn profile_for(&self, window: &FocusedWindow) -> Option<&str> {
+        self.profiles
+            .iter()
+            .find(|profile| profile.matches(window))
+            .map(|profile| profile.name.as_str())
+    }
+
+    /// Check whether a connected HID device should be opened at all
+    pub fn matches_any_device(&self, device: &DeviceIdentity) -> bool {
+        self.device.matches(device) || self.devices.iter().any(|config| config.matches(device))
+    }
+}
+
+/// Current version of the config format, written into new and migrated files
+pub const CONFIG_VERSION: u32 = 4;
+
+/// Files without a `version` field predate it and use the v3 action format
+fn legacy_config_version() -> u32 {
+    3
+}
+
+/// Just the `version` of a config file, read before the rest to know how to read its actions
+#[derive(Deserialize)]
+pub struct DocumentVersion {
+    #[serde(default = "legacy_config_version")]
+    pub version: u32,
+}
+
+thread_local! {
+    /// Whether the document being read uses the v3 format, see [`with_document_version`]
+    static READING_V3: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Run `read` with actions read as a document of `version` needs them: in v3 documents
+/// the action types v3 had ignore unknown keys, as v3 did. Everywhere else they are rejected.
+pub fn with_document_version<T>(version: u32, read: impl FnOnce() -> T) -> T {
+    let previous = READING_V3.replace(version <= legacy_config_version());
+    let result = read();
+    READING_V3.set(previous);
+    result
+}
+
+/// Action bound to a button event, e.g. `{"type": "KeyPress", "key": "F5"}`
+// Deserialized through `AnyAction` so that v3 actions are still accepted
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", from = "AnyAction")]
+#[schemars(from = "ActionEntry")]
 pub enum ExecutableAction {
-    KeyPress { key: Key, auto_release: bool },
-    KeyRelease { key: Key },
-    Text { text: String },
-    Sleep { duration_ms: u64 },
-    ReleaseAfter { duration_ms: u64 },
+    KeyPress {
+        #[schemars(schema_with = "key_schema")]
+        key: Key,
+        auto_release: bool,
+    },
+    KeyRelease {
+        #[schemars(schema_with = "key_schema")]
+        key: Key,
+    },
+    Text {
+        text: String,
+    },
+    Sleep {
+        duration_ms: u64,
+    },
+    ReleaseAfter {
+        duration_ms: u64,
+    },
     ReleaseAll,
-    ReleaseAllAfter { duration_ms: u64 },
+    ReleaseAllAfter {
+        duration_ms: u64,
+    },
+    MouseButton {
+        #[schemars(schema_with = "mouse_button_schema")]
+        button: Button,
+        #[schemars(schema_with = "direction_schema")]
+        direction: Direction,
+    },
+    MoveRelative {
+        x: i32,
+        y: i32,
+    },
+    MoveAbsolute {
+        x: i32,
+        y: i32,
+    },
+    Scroll {
+        amount: i32,
+        #[schemars(schema_with = "axis_schema")]
+        axis: Axis,
+    },
+    Exec {
+        program: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        args: Vec<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cwd: Option<String>,
+        #[serde(skip_serializing_if = "HashMap::is_empty")]
+        env: HashMap<String, String>,
+        wait: bool,
+        timeout_ms: u64,
+    },
+    /// Actions of a named entry in `macros`, replaced by them when the config is loaded
+    Macro {
+        name: String,
+    },
+}
+
+/// Action in the current format, unknown keys are rejected. Read by [`AnyAction`], which
+/// picks the variant from `type`, so the tag is only declared for the schema.
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+#[schemars(tag = "type")]
+enum ActionEntry {
+    KeyPress {
+        #[schemars(schema_with = "key_schema")]
+        key: Key,
+        #[serde(default = "default_auto_release")]
+        auto_release: bool,
+    },
+    KeyRelease {
+        #[schemars(schema_with = "key_schema")]
+        key: Key,
+    },
+    Text {
+        text: String,
+    },
+    Sleep {
+        duration_ms: u64,
+    },
+    ReleaseAfter {
+        duration_ms: u64,
+    },
+    ReleaseAll,
+    ReleaseAllAfter {
+        duration_ms: u64,
+    },
+    /// Press, release or click (the default) a mouse button
+    MouseButton {
+        #[schemars(schema_with = "mouse_button_schema")]
+        button: Button,
+        #[serde(default)]
+        #[schemars(schema_with = "direction_schema")]
+        direction: Direction,
+    },
+    /// Move the pointer by `x`/`y` pixels
+    MoveRelative {
+        #[serde(default)]
+        x: i32,
+        #[serde(default)]
+        y: i32,
+    },
+    /// Move the pointer to screen coordinates
+    MoveAbsolute {
+        x: i32,
+        y: i32,
+    },
+    /// Scroll by `amount` steps, positive is down or right
+    Scroll {
+        amount: i32,
+        #[serde(default)]
+        #[schemars(schema_with = "axis_schema")]
+        axis: Axis,
+    },
+    /// Start `program` (looked up in `PATH`, `~/` is expanded) with `args`. By default the program runs detached; with `wait`
+    /// the remaining actions wait for it, and it is killed after `timeout_ms`.
+    Exec {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+        /// Working directory, `~/` is expanded to the home directory
+        #[serde(default)]
+        cwd: Option<String>,
+        /// Variables added to the environment of the service
+        #[serde(default)]
+        env: HashMap<String, String>,
+        #[serde(default)]
+        wait: bool,
+        #[serde(default = "default_exec_timeout_ms")]
+        timeout_ms: u64,
+    },
+    Macro {
+        name: String,
+    },
+}
+
+/// The action types of v3: `Key`/`Unicode`, whose press or release is chosen by
+/// `direction`, and those that kept their argument in `value`, accepted as an alias.
+/// v3 ignored keys it did not know, so these types still do in v3 documents, and
+/// `Key`/`Unicode` everywhere; `validate` warns about them.
+#[derive(Deserialize)]
+enum LegacyAction {
+    /// `{"type": "Key", "direction": "Press", "value": "F5"}`
+    Key {
+        value: Key,
+        #[serde(default)]
+        direction: KeyDirection,
+        #[serde(default = "default_auto_release")]
+        auto_release: bool,
+    },
+    /// `{"type": "Unicode", "value": "s"}`
+    Unicode {
+        value: char,
+        #[serde(default)]
+        direction: KeyDirection,
+        #[serde(default = "default_auto_release")]
+        auto_release: bool,
+    },
+    Text {
+        #[serde(alias = "value")]
+        text: String,
+    },
+    Sleep {
+        #[serde(alias = "value")]
+        duration_ms: u64,
+    },
+    ReleaseAfter {
+        #[serde(alias = "value")]
+        duration_ms: u64,
+    },
+    ReleaseAll,
+    ReleaseAllAfter {
+        #[serde(alias = "value")]
+        duration_ms: u64,
+    },
+}
+
+/// Keys each [`LegacyAction`] type reads, besides `type`
+const LEGACY_ACTION_KEYS: [(&str, &[&str]); 7] = [
+    ("Key", &["value", "direction", "auto_release"]),
+    ("Unicode", &["value", "direction", "auto_release"]),
+    ("Text", &["text", "value"]),
+    ("Sleep", &["duration_ms", "value"]),
+    ("ReleaseAfter", &["duration_ms", "value"]),
+    ("ReleaseAll", &[]),
+    ("ReleaseAllAfter", &["duration_ms", "value"]),
+];
+
+/// An action as written in the file, read as a [`LegacyAction`] when it is read leniently,
+/// see [`reads_leniently`]
+enum AnyAction {
+    Current(ActionEntry),
+    Legacy(LegacyAction),
+}
+
+impl<'de> Deserialize<'de> for AnyAction {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(AnyActionVisitor)
+    }
+}
+
+/// Reads the `type` of an action and then the rest of its keys straight from the file,
+/// so that errors point at the key or value they are about
+struct AnyActionVisitor;
+
+impl<'de> Visitor<'de> for AnyActionVisitor {
+    type Value = AnyAction;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("an action with a \"type\"")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<AnyAction, A::Error> {
+        // Keys before `type` have to be kept until it is known which ones are valid
+        let mut buffered = Vec::new();
+        let kind = loop {
+            match map.next_key::<String>()? {
+                Some(key) if key == "type" => break map.next_value::<String>()?,
+                Some(key) => buffered.push((key, map.next_value::<serde_json::Value>()?)),
+                None => return Err(de::Error::missing_field("type")),
+            }
+        };
+        let lenient = reads_leniently(&kind);
+        let action = TaggedAction {
+            kind,
+            fields: ActionFields {
+                buffered: buffered.into_iter(),
+                value: None,
+                rest: map,
+                lenient,
+            },
+        };
+        if lenient {
+            LegacyAction::deserialize(EnumAccessDeserializer::new(action)).map(AnyAction::Legacy)
+        } else {
+            ActionEntry::deserialize(EnumAccessDeserializer::new(action)).map(AnyAction::Current)
+        }
+    }
+}
+
+/// An action whose `type` has been read, as the enum the variant is picked from
+struct TaggedAction<A> {
+    kind: String,
+    fields: ActionFields<A>,
+}
+
+impl<'de, A: MapAccess<'de>> de::EnumAccess<'de> for TaggedAction<A> {
+    type Error = A::Error;
+    type Variant = ActionFields<A>;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, ActionFields<A>), A::Error> {
+        let kind: StringDeserializer<A::Error> = self.kind.into_deserializer();
+        Ok((seed.deserialize(kind)?, self.fields))
+    }
+}
+
+/// The keys of an action besides `type`: those read before it, then the rest of the map
+struct ActionFields<A> {
+    buffered: std::vec::IntoIter<(String, serde_json::Value)>,
+    /// Value of the buffered key returned last
+    value: Option<serde_json::Value>,
+    rest: A,
+    /// Whether unknown keys are ignored
+    lenient: bool,
+}
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for ActionFields<A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, A::Error> {
+        match self.buffered.next() {
+            Some((key, value)) => {
+                self.value = Some(value);
+                let key: StringDeserializer<A::Error> = key.into_deserializer();
+                seed.deserialize(key).map(Some)
+            }
+            None => self.rest.next_key_seed(seed),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, A::Error> {
+        match self.value.take() {
+            Some(value) => seed.deserialize(value).map_err(de::Error::custom),
+            None => self.rest.next_value_seed(seed),
+        }
+    }
+}
+
+impl<'de, A: MapAccess<'de>> de::VariantAccess<'de> for ActionFields<A> {
+    type Error = A::Error;
+
+    /// `ReleaseAll`, which has no keys besides `type`
+    fn unit_variant(mut self) -> Result<(), A::Error> {
+        while let Some(key) = self.next_key::<String>()? {
+            if !self.lenient {
+                return Err(de::Error::unknown_field(&key, &[]));
+            }
+            self.next_value::<IgnoredAny>()?;
+        }
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        _seed: T,
+    ) -> Result<T::Value, A::Error> {
+        Err(de::Error::invalid_type(
+            de::Unexpected::Map,
+            &"a newtype variant",
+        ))
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        _visitor: V,
+    ) -> Result<V::Value, A::Error> {
+        Err(de::Error::invalid_type(
+            de::Unexpected::Map,
+            &"a tuple variant",
+        ))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, A::Error> {
+        visitor.visit_map(self)
+    }
+}
+
+/// Whether an action of type `kind` is read as a [`LegacyAction`]: `Key` and `Unicode`
+/// always, as only v3 had them, the other v3 types only in v3 documents
+fn reads_leniently(kind: &str) -> bool {
+    matches!(kind, "Key" | "Unicode") || (READING_V3.get() && legacy_action_keys(kind).is_some())
+}
+
+/// Keys a v3 action type reads, `None` for the other actions
+fn legacy_action_keys(kind: &str) -> Option<&'static [&'static str]> {
+    LEGACY_ACTION_KEYS
+        .iter()
+        .find(|(name, _)| *name == kind)
+        .map(|(_, keys)| *keys)
+}
+
+/// Current form of an action of a v3 type in a v3 document, `None` for the other actions.
+/// Used by `migrate`, which leaves those untouched.
+pub fn migrated_action(action: &serde_json::Value) -> Option<serde_json::Value> {
+    let kind = action.get("type")?.as_str()?;
+    legacy_action_keys(kind)?;
+    let action = with_document_version(legacy_config_version(), || {
+        ExecutableAction::deserialize(action)
+    })
+    .ok()?;
+    serde_json::to_value(action).ok()
+}
+
+/// Keys of an action read leniently that it does not read. They used to be ignored
+/// silently and still are when loading, so `validate` lists them as warnings.
+pub fn unknown_action_keys(action: &serde_json::Value) -> Vec<&str> {
+    let Some(kind) = action.get("type").and_then(serde_json::Value::as_str) else {
+        return Vec::new();
+    };
+    let (true, Some(keys), Some(object)) = (
+        reads_leniently(kind),
+        legacy_action_keys(kind),
+        action.as_object(),
+    ) else {
+        return Vec::new();
+    };
+    object
+        .keys()
+        .map(String::as_str)
+        .filter(|key| *key != "type" && !keys.contains(key))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+enum KeyDirection {
+    #[default]
+    Press,
+    Release,
+}
+
+fn default_auto_release() -> bool {
+    true
+}
+
+fn default_exec_timeout_ms() -> u64 {
+    5000
+}
+
+fn mouse_button_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "enum": ["Left", "Middle", "Right", "Back", "Forward", "ScrollUp", "ScrollDown", "ScrollLeft", "ScrollRight"]
+    })
+}
+
+fn direction_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({"enum": ["Press", "Release", "Click"], "default": "Click"})
+}
+
+fn axis_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({"enum": ["Vertical", "Horizontal"], "default": "Vertical"})
+}
+
+/// Enigo has no schema for `Key`, so describe the three shapes it deserializes from
+fn key_schema(_: &mut SchemaGenerator) -> Schema {
+    json_schema!({
+        "description": "Key name such as \"F5\" or \"Control\", {\"Unicode\": \"a\"} for a character or {\"Other\": 13} for a platform key code",
+        "anyOf": [
+            {"type": "string"},
+            {
+                "type": "object",
+                "properties": {"Unicode": {"type": "string", "minLength": 1, "maxLength": 1}},
+                "required": ["Unicode"],
+                "additionalProperties": false
+            },
+            {
+                "type": "object",
+                "properties": {"Other": {"type": "integer", "minimum": 0}},
+                "required": ["Other"],
+                "additionalProperties": false
+            }
+        ]
+    })
+}
+
+impl From<AnyAction> for ExecutableAction {
+    fn from(action: AnyAction) -> Self {
+        match action {
+            AnyAction::Current(entry) => entry.into(),
+            AnyAction::Legacy(legacy) => legacy.into(),
+        }
+    }
+}
+
+impl From<ActionEntry> for ExecutableAction {
+    fn from(entry: ActionEntry) -> Self {
+        match entry {
+            ActionEntry::KeyPress { key, auto_release } => {
+                ExecutableAction::KeyPress { key, auto_release }
+            }
+            ActionEntry::KeyRelease { key } => ExecutableAction::KeyRelease { key },
+            ActionEntry::Text { text } => ExecutableAction::Text { text },
+            ActionEntry::Sleep { duration_ms } => ExecutableAction::Sleep { duration_ms },
+            ActionEntry::ReleaseAfter { duration_ms } => {
+                ExecutableAction::ReleaseAfter { duration_ms }
+            }
+            ActionEntry::ReleaseAll => ExecutableAction::ReleaseAll,
+            ActionEntry::ReleaseAllAfter { duration_ms } => {
+                ExecutableAction::ReleaseAllAfter { duration_ms }
+            }
+            ActionEntry::MouseButton { button, direction } => {
+                ExecutableAction::MouseButton { button, direction }
+            }
+            ActionEntry::MoveRelative { x, y } => ExecutableAction::MoveRelative { x, y },
+            ActionEntry::MoveAbsolute { x, y } => ExecutableAction::MoveAbsolute { x, y },
+            ActionEntry::Scroll { amount, axis } => ExecutableAction::Scroll { amount, axis },
+            ActionEntry::Exec {
+                program,
+                args,
+                cwd,
+                env,
+                wait,
+                timeout_ms,
+            } => ExecutableAction::Exec {
+                program,
+                args,
+                cwd,
+                env,
+                wait,
+                timeout_ms,
+            },
+            ActionEntry::Macro { name } => ExecutableAction::Macro { name },
+        }
+    }
+}
+
+impl From<LegacyAction> for ExecutableAction {
+    fn from(action: LegacyAction) -> Self {
+        let key_action = |key, direction, auto_release| match direction {
+            KeyDirection::Press => ExecutableAction::KeyPress { key, auto_release },
+            KeyDirection::Release => ExecutableAction::KeyRelease { key },
+        };
+        match action {
+            LegacyAction::Key {
+                value,
+                direction,
+                auto_release,
+            } => key_action(value, direction, auto_release),
+            LegacyAction::Unicode {
+                value,
+                direction,
+                auto_release,
+            } => key_action(Key::Unicode(value), direction, auto_release),
+            LegacyAction::Text { text } => ExecutableAction::Text { text },
+            LegacyAction::Sleep { duration_ms } => ExecutableAction::Sleep { duration_ms },
+            LegacyAction::ReleaseAfter { duration_ms } => {
+                ExecutableAction::ReleaseAfter { duration_ms }
+            }
+            LegacyAction::ReleaseAll => ExecutableAction::ReleaseAll,
+            LegacyAction::ReleaseAllAfter { duration_ms } => {
+                ExecutableAction::ReleaseAllAfter { duration_ms }
+            }
+        }
+    }
+}
+
+/// Contents of a file listed in `include`: shared macros and further includes
+#[derive(Debug, Default, Deserialize)]
+struct ConfigLibrary {
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    macros: HashMap<String, Vec<ExecutableAction>>,
+}
+
+/// Macros collected from the included files and the config itself
+#[derive(Default)]
+struct MacroTable {
+    macros: HashMap<String, Vec<ExecutableAction>>,
+    /// Included file each macro was last defined in, absent for the config's own macros
+    origins: HashMap<String, PathBuf>,
+    /// Every included file, as written and with symlinks resolved
+    files: Vec<PathBuf>,
+}
+
+impl MacroTable {
+    /// Read an included file and its own includes, depth first. `stack` holds the
+    /// files currently being read, to catch include cycles.
+    fn include(
+        &mut self,
+        entry: &str,
+        from: &Path,
+        stack: &mut Vec<PathBuf>,
+    ) -> Result<(), String> {
+        let path = from
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join(expand_home(entry));
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| format!("cannot read \"{}\": {e}", path.display()))?;
+        self.files.push(path.clone());
+        if canonical != path {
+            self.files.push(canonical.clone());
+        }
+        if stack.contains(&canonical) {
+            let chain: Vec<String> = stack
+                .iter()
+                .chain([&canonical])
+                .map(|file| format!("\"{}\"", file.display()))
+                .collect();
+            return Err(format!("include cycle: {}", chain.join(" -> ")));
+        }
+
+        let library: ConfigLibrary = std::fs::read_to_string(&canonical)
+            .map_err(|e| e.to_string())
+            .and_then(|content| {
+                ConfigFormat::from_path(&canonical)?
+                    .parse(&content)
+                    .map_err(|e| e.to_string())
+            })
+            .map_err(|e| format!("cannot read \"{}\": {e}", path.display()))?;
+
+        stack.push(canonical.clone());
+        for nested in &library.include {
+            self.include(nested, &canonical, stack)?;
+        }
+        stack.pop();
+
+        for (name, actions) in library.macros {
+            self.origins.insert(name.clone(), canonical.clone());
+            self.macros.insert(name, actions);
+        }
+        Ok(())
+    }
+
+    /// Replace every `Macro` action by the actions of the macro, recursively.
+    /// `stack` holds the macros being expanded, to catch cycles.
+    fn expand(
+        &self,
+        actions: &[ExecutableAction],
+        stack: &mut Vec<String>,
+    ) -> Result<Vec<ExecutableAction>, String> {
+        let mut expanded = Vec::with_capacity(actions.len());
+        for action in actions {
+            let ExecutableAction::Macro { name } = action else {
+                expanded.push(action.clone());
+                continue;
+            };
+            if stack.contains(name) {
+                return Err(format!("macro cycle: {} -> {name}", stack.join(" -> ")));
+            }
+            let body = self
+                .macros
+                .get(name)
+                .ok_or_else(|| format!("unknown macro \"{name}\""))?;
+            stack.push(name.clone());
+            expanded.extend(self.expand(body, stack)?);
+            stack.pop();
+        }
+        Ok(expanded)
+    }
 }
 
 /// Parser that uses the modern event-based configuration
 pub struct TokenBasedParser {
     config: TokenBasedConfig,
+    active_profile: Option<String>,
 }
 
 impl TokenBasedParser {
     pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
-        let config = crate::config_manager::ConfigManager::load_config()?;
-        Ok(TokenBasedParser { config })
+        let config = Self::resolve_config(
+            ConfigManager::load_config()?,
+            &ConfigManager::get_config_path(),
+        )?;
+        config
+            .validate()
+            .map_err(|e| format!("Invalid configuration: {e}"))?;
+        Ok(TokenBasedParser {
+            config,
+            active_profile: None,
+        })
+    }
+
+    /// Load the files in `include` and replace every `Macro` action by the actions it
+    /// names. Missing files, unknown macros and cycles are reported with their path;
+    /// every macro is checked, even when no button uses it.
+    pub fn resolve_config(
+        mut config: TokenBasedConfig,
+        config_path: &Path,
+    ) -> Result<TokenBasedConfig, ConfigProblem> {
+        let root = ConfigPath::default();
+        let mut table = MacroTable::default();
+        let mut stack = vec![
+            config_path
+                .canonicalize()
+                .unwrap_or_else(|_| config_path.to_path_buf()),
+        ];
+        for (i, entry) in config.include.iter().enumerate() {
+            table
+                .include(entry, config_path, &mut stack)
+                .map_err(|e| ConfigProblem::new(root.key("include").index(i), e))?;
+        }
+        for (name, actions) in &config.macros {
+            table.origins.remove(name);
+            table.macros.insert(name.clone(), actions.clone());
+        }
+
+        let mut names: Vec<&String> = table.macros.keys().collect();
+        names.sort();
+        for name in names {
+            let definition = [ExecutableAction::Macro { name: name.clone() }];
+            if let Err(e) = table.expand(&definition, &mut Vec::new()) {
+                return Err(match table.origins.get(name) {
+                    Some(file) => ConfigProblem::new(
+                        root.key("include"),
+                        format!("macro \"{name}\" in \"{}\": {e}", file.display()),
+                    ),
+                    None => ConfigProblem::new(root.key("macros").key(name), e),
+                });
+            }
+        }
+
+        for (path, actions) in config.action_lists_mut() {
+            let mut expanded = Vec::with_capacity(actions.len());
+            for (i, action) in actions.iter().enumerate() {
+                let action = std::slice::from_ref(action);
+                expanded.extend(
+                    table
+                        .expand(action, &mut Vec::new())
+                        .map_err(|e| ConfigProblem::new(path.index(i), e))?,
+                );
+            }
+            *actions = expanded;
+        }
+        config.included_files = table.files;
+        Ok(config)
+    }
+
+    /// Parser for an already resolved configuration, without touching the config file
+    #[cfg(test)]
+    pub fn from_config(config: TokenBasedConfig) -> Self {
+        TokenBasedParser {
+            config,
+            active_profile: None,
+        }
+    }
+
+    pub fn config(&self) -> &TokenBasedConfig {
+        &self.config
+    }
+
+    /// Swap in a reloaded configuration; it must already have been validated
+    pub fn replace_config(&mut self, config: TokenBasedConfig) {
+        self.config = config;
+    }
+
+    pub fn active_profile(&self) -> Option<&str> {
+        self.active_profile.as_deref()
+    }
+
+    /// Switch the per-application profile whose bindings take precedence
+    pub fn set_active_profile(&mut self, profile: Option<String>) {
+        self.active_profile = profile;
+    }
+
+    fn active_profile_config(&self) -> Option<&Profile> {
+        let name = self.active_profile.as_deref()?;
+        self.config
+            .profiles
+            .iter()
+            .find(|profile| profile.name == name)
+    }
+
+    /// Find a button or chord, preferring the active profile over the device block
+    fn button_config(
+        &self,
+        device: &DeviceIdentity,
+        button_name: ButtonName,
+    ) -> Option<&ButtonConfig> {
+        self.active_profile_config()
+            .and_then(|profile| profile.button_config(button_name))
+            .or_else(|| self.config.device_config(device).button_config(button_name))
     }
 
-    /// Get the hold threshold for a specific button, using hierarchical configuration:
+    /// Resolve a timing setting for a specific button, using hierarchical configuration:
     /// 1. Per-button setting (highest priority)
     /// 2. Device-level setting
     /// 3. Global default from AppConfig (lowest priority)
-    pub fn get_hold_threshold_ms(
+    fn resolve_setting<T>(
         &self,
-        button_name: PhysicalButtonName,
-        global_default: u64,
-    ) -> u64 {
-        let button_key = button_name.as_str();
+        device: &DeviceIdentity,
+        button_name: ButtonName,
+        button_setting: impl Fn(&ButtonSettings) -> Option<T>,
+        device_setting: impl Fn(&DeviceSettings) -> Option<T>,
+        global_default: T,
+    ) -> T {
+        let device_config = self.config.device_config(device);
 
         // Check for per-button setting first (highest priority)
-        if let Some(button_config) = self.config.device.buttons.get(button_key)
+        if let Some(button_config) = self.button_config(device, button_name)
             && let Some(button_settings) = &button_config.settings
-            && let Some(threshold) = button_settings.hold_threshold_time_ms
+            && let Some(value) = button_setting(button_settings)
         {
-            return threshold;
+            return value;
         }
 
         // Check for device-level setting (medium priority)
-        if let Some(device_settings) = &self.config.device.settings
-            && let Some(threshold) = device_settings.hold_threshold_time_ms
+        if let Some(device_settings) = &device_config.settings
+            && let Some(value) = device_setting(device_settings)
         {
-            return threshold;
+            return value;
         }
 
         // Fall back to global default (lowest priority)
         global_default
     }
 
-    pub fn get_actions_for_button_event(
+    /// Get the hold threshold for a specific button (per-button > device > global default)
+    pub fn get_hold_threshold_ms(
+        &self,
+        device: &DeviceIdentity,
+        button_name: ButtonName,
+        global_default: u64,
+    ) -> u64 {
+        self.resolve_setting(
+            device,
+            button_name,
+            |button| button.hold_threshold_time_ms,
+            |device| device.hold_threshold_time_ms,
+            global_default,
+        )
+    }
+
+    /// Get the multi-tap window for a specific button (per-button > device > global default)
+    pub fn get_multi_tap_window_ms(
+        &self,
+        device: &DeviceIdentity,
+        button_name: ButtonName,
+        global_default: u64,
+    ) -> u64 {
+        self.resolve_setting(
+            device,
+            button_name,
+            |button| button.multi_tap_window_ms,
+            |device| device.multi_tap_window_ms,
+            global_default,
+        )
+    }
+
+    /// Get the auto-repeat interval for a specific button (per-button > device > global default)
+    pub fn get_repeat_interval_ms(
+        &self,
+        device: &DeviceIdentity,
+        button_name: ButtonName,
+        global_default: u64,
+    ) -> u64 {
+        self.resolve_setting(
+            device,
+            button_name,
+            |button| button.repeat_interval_ms,
+            |device| device.repeat_interval_ms,
+            global_default,
+        )
+    }
+
+    /// Get the fastest auto-repeat interval for a specific button (per-button > device > global default)
+    pub fn get_repeat_min_interval_ms(
         &self,
+        device: &DeviceIdentity,
+        button_name: ButtonName,
+        global_default: u64,
+    ) -> u64 {
+        self.resolve_setting(
+            device,
+            button_name,
+            |button| button.repeat_min_interval_ms,
+            |device| device.repeat_min_interval_ms,
+            global_default,
+        )
+    }
+
+    /// Get the auto-repeat acceleration factor for a specific button (1.0 means a constant rate)
+    pub fn get_repeat_acceleration(&self, device: &DeviceIdentity, button_name: ButtonName) -> f64 {
+        self.resolve_setting(
+            device,
+            button_name,
+            |button| button.repeat_acceleration,
+            |device| device.repeat_acceleration,
+            1.0,
+        )
+    }
+
+    /// Get what a button's actions do while others are running (per-button > device > queue)
+    pub fn get_when_busy(&self, device: &DeviceIdentity, button_name: ButtonName) -> WhenBusy {
+        self.resolve_setting(
+            device,
+            button_name,
+            |button| button.when_busy,
+            |device| device.when_busy,
+            WhenBusy::default(),
+        )
+    }
+
+    /// Get the hold stages that follow HELD for a specific button (per-button > device)
+    pub fn get_hold_stages(
+        &self,
+        device: &DeviceIdentity,
+        button_name: ButtonName,
+    ) -> Vec<HoldStage> {
+        self.resolve_setting(
+            device,
+            button_name,
+            |button| button.hold_stages.clone(),
+            |device| device.hold_stages.clone(),
+            Vec::new(),
+        )
+    }
+
+    /// Name of the action list for a hold stage: "HELD" for stage 0, then the configured names
+    pub fn get_hold_stage_name(
+        &self,
+        device: &DeviceIdentity,
+        button_name: ButtonName,
+        stage: usize,
+    ) -> String {
+        match stage.checked_sub(1) {
+            None => "HELD".to_string(),
+            Some(index) => self
+                .get_hold_stages(device, button_name)
+                .get(index)
+                .map(|stage| stage.name.clone())
+                .unwrap_or_else(|| "HELD".to_string()),
+        }
+    }
+
+    /// Get the RELEASING actions for a button, preferring `RELEASING_<stage>` when
+    /// the button was released after reaching a hold stage
+    pub fn get_releasing_actions(
+        &self,
+        device: &DeviceIdentity,
+        button_name: ButtonName,
+        hold_stage: Option<usize>,
+        layer: Option<&str>,
+    ) -> Option<Vec<ExecutableAction>> {
+        hold_stage
+            .and_then(|stage| {
+                let stage_name = self.get_hold_stage_name(device, button_name, stage);
+                self.get_actions_for_button_event(
+                    device,
+                    button_name,
+                    &format!("RELEASING_{stage_name}"),
+                    layer,
+                )
+            })
+            .or_else(|| self.get_actions_for_button_event(device, button_name, "RELEASING", layer))
+    }
+
+    /// Whether a button has any RELEASING action, stage specific or not
+    pub fn has_releasing_actions(
+        &self,
+        device: &DeviceIdentity,
+        button_name: ButtonName,
+        layer: Option<&str>,
+    ) -> bool {
+        self.button_config(device, button_name)
+            .is_some_and(|button_config| {
+                button_config
+                    .actions_for_layer(layer)
+                    .keys()
+                    .any(|event| event == "RELEASING" || event.starts_with("RELEASING_"))
+            })
+    }
+
+    /// Layer key binding of a physical button, if it is configured as one
+    pub fn get_layer_key(
+        &self,
+        device: &DeviceIdentity,
         button_name: PhysicalButtonName,
+    ) -> Option<LayerKey> {
+        self.button_config(device, ButtonName::Physical(button_name))
+            .and_then(|button_config| button_config.layer_key.clone())
+    }
+
+    /// Get the chord coincidence window for a device (device setting > global default)
+    pub fn get_chord_window_ms(&self, device: &DeviceIdentity, global_default: u64) -> u64 {
+        self.config
+            .device_config(device)
+            .settings
+            .as_ref()
+            .and_then(|settings| settings.chord_window_ms)
+            .unwrap_or(global_default)
+    }
+
+    /// All chords configured for a device
+    pub fn get_chords(&self, device: &DeviceIdentity) -> Vec<ChordName> {
+        let mut chords: Vec<ChordName> = self
+            .config
+            .device_config(device)
+            .chords
+            .keys()
+            .chain(
+                self.active_profile_config()
+                    .into_iter()
+                    .flat_map(|profile| profile.chords.keys()),
+            )
+            .filter_map(|key| ChordName::from_key(key))
+            .collect();
+        chords.sort_by_key(|chord| chord.members);
+        chords.dedup();
+        chords
+    }
+
+    /// Display name of a button: its label if configured, otherwise its key
+    pub fn get_button_label(&self, device: &DeviceIdentity, button_name: ButtonName) -> String {
+        self.button_config(device, button_name)
+            .and_then(|button_config| button_config.label.clone())
+            .map(|label| format!("{button_name} ({label})"))
+            .unwrap_or_else(|| button_name.to_string())
+    }
+
+    /// Get the actions bound to an event, taken from the layer map when `layer` is
+    /// active and the button has bindings for it, otherwise from the base map
+    pub fn get_actions_for_button_event(
+        &self,
+        device: &DeviceIdentity,
+        button_name: ButtonName,
         event_type: &str,
+        layer: Option<&str>,
     ) -> Option<Vec<ExecutableAction>> {
-        let button_key = button_name.as_str();
-        let button_config = self.config.device.buttons.get(button_key)?;
-        let action_items = button_config.actions.get(event_type)?;
+        let button_config = self.button_config(device, button_name)?;
+        button_config
+            .actions_for_layer(layer)
+            .get(event_type)
+            .cloned()
+    }
+}
 
-        let mut executable_actions = Vec::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        for item in action_items {
-            match self.convert_action_item(item) {
-                Ok(action) => executable_actions.push(action),
-                Err(e) => eprintln!("Error converting action item: {e}"),
-            }
-        }
+    fn pressed_actions(config: &TokenBasedConfig) -> serde_json::Value {
+        serde_json::to_value(&config.device.buttons["button_0"].actions["PRESSED"]).unwrap()
+    }
 
-        Some(executable_actions)
+    fn with_actions(version: Option<u32>, actions: &str) -> String {
+        let version = version.map_or(String::new(), |version| format!("\"version\": {version},"));
+        format!(
+            "{{{version} \"device\": {{\"button_count\": 3, \"buttons\": {{\"button_0\": {{\"actions\": {{\"PRESSED\": [\n{actions}\n]}}}}}}}}}}"
+        )
     }
 
-    fn convert_action_item(
-        &self,
-        item: &ActionItem,
-    ) -> Result<ExecutableAction, Box<dyn std::error::Error>> {
-        match item.action_type.as_str() {
-            "Key" => {
-                let direction = item.direction.as_deref().unwrap_or("Press");
-                let auto_release = item.auto_release.unwrap_or(true);
-
-                let enigo_key = match &item.value {
-                    Some(ActionValue::Key(key)) => *key,
-                    Some(ActionValue::Other(code)) => {
-                        // Handle Key::Other for platform-specific key codes
-                        println!("===========================================================");
-                        println!(
-                            "Key::Other is used for platform-specific key codes, ensure you handle this correctly!"
-                        );
-                        println!("                        {code}");
-                        println!("===========================================================");
-                        Key::Other(*code)
-                    }
-                    _ => {
-                        return Err("Key action requires a Key value or numeric key code. Use 'Text' action type for text input.".into());
-                    }
-                };
+    #[test]
+    fn button_keys_name_an_index() {
+        assert_eq!(
+            PhysicalButtonName::from_key("button_0"),
+            Some(PhysicalButtonName::from_index(0))
+        );
+        assert_eq!(
+            PhysicalButtonName::from_key("button_12"),
+            Some(PhysicalButtonName::from_index(12))
+        );
+        for key in ["button_", "button_x", "button_-1", "Button0", "pedal_1", ""] {
+            assert_eq!(PhysicalButtonName::from_key(key), None, "{key}");
+        }
+        assert_eq!(PhysicalButtonName::from_index(7).key(), "button_7");
+    }
 
-                match direction {
-                    "Press" => Ok(ExecutableAction::KeyPress {
-                        key: enigo_key,
-                        auto_release,
-                    }),
-                    "Release" => Ok(ExecutableAction::KeyRelease { key: enigo_key }),
-                    _ => Err(format!("Unknown key direction: {direction}").into()),
-                }
-            }
-            "Unicode" => {
-                let direction = item.direction.as_deref().unwrap_or("Press");
-                let auto_release = item.auto_release.unwrap_or(true);
-
-                let unicode_char = match &item.value {
-                    Some(ActionValue::Unicode(ch)) => *ch,
-                    Some(ActionValue::Text(text)) => {
-                        if text.chars().count() == 1 {
-                            match text.chars().next() {
-                                Some(ch) => ch,
-                                None => {
-                                    return Err("Unicode action requires a single character".into());
-                                }
-                            }
-                        } else {
-                            return Err("Unicode action requires a single character".into());
-                        }
-                    }
-                    _ => {
-                        return Err("Unicode action requires a single character".into());
-                    }
-                };
+    #[test]
+    fn chord_keys_are_sets_of_at_least_two_buttons() {
+        let chord = ChordName::from_key("button_2 + button_0").unwrap();
+        assert_eq!(chord, ChordName::from_key("button_0+button_2").unwrap());
+        assert_eq!(chord.to_string(), "button_0+button_2");
+        assert_eq!(chord.member_count(), 2);
+        assert!(chord.contains(PhysicalButtonName::from_index(2)));
+        assert!(!chord.contains(PhysicalButtonName::from_index(1)));
+        assert!(!chord.contains(PhysicalButtonName::from_index(64)));
 
-                let enigo_key = Key::Unicode(unicode_char);
+        let widest = ChordName::from_key("button_0+button_63").unwrap();
+        let members: Vec<usize> = widest.members().map(|button| button.index).collect();
+        assert_eq!(members, [0, 63]);
 
-                match direction {
-                    "Press" => Ok(ExecutableAction::KeyPress {
-                        key: enigo_key,
-                        auto_release,
-                    }),
-                    "Release" => Ok(ExecutableAction::KeyRelease { key: enigo_key }),
-                    _ => Err(format!("Unknown key direction: {direction}").into()),
-                }
-            }
-            "Text" => {
-                if let Some(ActionValue::Text(text)) = &item.value {
-                    Ok(ExecutableAction::Text { text: text.clone() })
-                } else {
-                    Err("Text action missing text value".into())
-                }
-            }
-            "Sleep" => {
-                if let Some(ActionValue::Number(duration)) = &item.value {
-                    Ok(ExecutableAction::Sleep {
-                        duration_ms: *duration,
-                    })
-                } else {
-                    Err("Sleep action missing duration value".into())
-                }
-            }
-            "ReleaseAfter" => {
-                if let Some(ActionValue::Number(duration)) = &item.value {
-                    Ok(ExecutableAction::ReleaseAfter {
-                        duration_ms: *duration,
-                    })
-                } else {
-                    Err("ReleaseAfter action missing duration value".into())
-                }
-            }
-            "ReleaseAll" => Ok(ExecutableAction::ReleaseAll),
-            "ReleaseAllAfter" => {
-                if let Some(ActionValue::Number(duration)) = &item.value {
-                    Ok(ExecutableAction::ReleaseAllAfter {
-                        duration_ms: *duration,
-                    })
-                } else {
-                    Err("ReleaseAllAfter action missing duration value".into())
+        for key in [
+            "button_0",
+            "button_0+button_0",
+            "button_0+button_1+button_0",
+            "button_0+button_64",
+            "button_0+pedal_1",
+            "button_0+",
+        ] {
+            assert_eq!(ChordName::from_key(key), None, "{key}");
+        }
+    }
+
+    #[test]
+    fn chords_need_buttons_the_device_has() {
+        let config: TokenBasedConfig = serde_json::from_value(serde_json::json!({
+            "version": 4,
+            "device": {
+                "button_count": 3,
+                "buttons": {},
+                "chords": {
+                    "button_0+button_2": {"actions": {"PRESSED": []}},
+                    "button_1+button_3": {"actions": {"PRESSED": []}},
+                    "button_1+button_1": {"actions": {"PRESSED": []}}
                 }
             }
-            _ => Err(format!("Unknown action type: {}", item.action_type).into()),
+        }))
+        .unwrap();
+
+        let mut problems: Vec<String> = config.problems().iter().map(ToString::to_string).collect();
+        problems.sort();
+        assert_eq!(
+            problems,
+            [
+                "device.chords.button_1+button_1: invalid chord key, expected at least two different buttons such as \"button_0+button_2\"",
+                "device.chords.button_1+button_3: button_3 does not exist, this device declares button_count = 3",
+            ]
+        );
+    }
+
+    #[test]
+    fn usb_ids_are_numbers_or_hex_strings() {
+        #[derive(Deserialize)]
+        struct Ids {
+            #[serde(default, with = "usb_id")]
+            id: Option<u16>,
+        }
+        let parse = |value: serde_json::Value| {
+            serde_json::from_value::<Ids>(serde_json::json!({ "id": value })).map(|ids| ids.id)
+        };
+
+        assert_eq!(parse(serde_json::json!(4057)).unwrap(), Some(0x0fd9));
+        for text in ["0x0fd9", "0X0FD9", "0fd9", "fd9"] {
+            assert_eq!(parse(text.into()).unwrap(), Some(0x0fd9), "{text}");
+        }
+        assert_eq!(parse(serde_json::Value::Null).unwrap(), None);
+        assert_eq!(serde_json::from_str::<Ids>("{}").unwrap().id, None);
+        for value in [
+            serde_json::json!(65536),
+            serde_json::json!(-1),
+            serde_json::json!("0x10000"),
+            serde_json::json!("0x"),
+            serde_json::json!("+fd9"),
+            serde_json::json!("0xfdg"),
+        ] {
+            assert!(parse(value.clone()).is_err(), "{value}");
+        }
+
+        let mut serializer = serde_json::Serializer::new(Vec::new());
+        usb_id::serialize(&Some(0x0fd9), &mut serializer).unwrap();
+        assert_eq!(serializer.into_inner(), br#""0x0fd9""#);
+    }
+
+    /// Fresh directory under the system temp dir, holding the given files
+    fn config_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            std::fs::write(dir.join(file), content).unwrap();
+        }
+        dir
+    }
+
+    fn resolve(json: &str, dir: &Path) -> Result<TokenBasedConfig, ConfigProblem> {
+        let config: TokenBasedConfig = serde_json::from_str(json).unwrap();
+        TokenBasedParser::resolve_config(config, &dir.join("config.json"))
+    }
+
+    fn macro_config(macros: &str, actions: &str) -> String {
+        format!(
+            r#"{{"include": ["a.json"], "macros": {{{macros}}}, "device": {{"button_count": 3, "buttons": {{"button_0": {{"actions": {{"PRESSED": [{actions}]}}}}}}}}}}"#
+        )
+    }
+
+    #[test]
+    fn macros_expand_across_included_files() {
+        let dir = config_dir(
+            "macro-expand",
+            &[
+                (
+                    "a.json",
+                    r#"{"include": ["b.json"], "macros": {"outer": [{"type": "Macro", "name": "inner"}, {"type": "Sleep", "duration_ms": 2}]}}"#,
+                ),
+                (
+                    "b.json",
+                    r#"{"macros": {"inner": [{"type": "Sleep", "duration_ms": 1}]}}"#,
+                ),
+            ],
+        );
+        let config = resolve(
+            &macro_config("", r#"{"type": "Macro", "name": "outer"}"#),
+            &dir,
+        )
+        .unwrap();
+        assert_eq!(
+            pressed_actions(&config),
+            serde_json::json!([
+                {"type": "Sleep", "duration_ms": 1},
+                {"type": "Sleep", "duration_ms": 2}
+            ])
+        );
+        assert_eq!(
+            config.included_files,
+            [dir.join("a.json"), dir.join("b.json")]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn include_cycles_are_rejected() {
+        let dir = config_dir(
+            "include-cycle",
+            &[
+                ("a.json", r#"{"include": ["b.json"]}"#),
+                ("b.json", r#"{"include": ["./a.json"]}"#),
+            ],
+        );
+        let problem = resolve(&macro_config("", ""), &dir).unwrap_err();
+        assert_eq!(problem.path.to_string(), "include[0]");
+        assert!(
+            problem.message.starts_with("include cycle: ") && problem.message.ends_with("a.json\""),
+            "{}",
+            problem.message
+        );
+
+        let missing = config_dir("include-missing", &[]);
+        let problem = resolve(&macro_config("", ""), &missing).unwrap_err();
+        assert_eq!(problem.path.to_string(), "include[0]");
+        assert!(
+            problem.message.starts_with("cannot read"),
+            "{}",
+            problem.message
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(missing).unwrap();
+    }
+
+    #[test]
+    fn macro_cycles_and_unknown_macros_are_rejected() {
+        let dir = config_dir("macro-cycle", &[("a.json", "{}")]);
+        let problem = resolve(
+            &macro_config(
+                r#""a": [{"type": "Macro", "name": "b"}], "b": [{"type": "Macro", "name": "a"}]"#,
+                "",
+            ),
+            &dir,
+        )
+        .unwrap_err();
+        assert_eq!(problem.path.to_string(), "macros.a");
+        assert_eq!(problem.message, "macro cycle: a -> b -> a");
+
+        let problem = resolve(
+            &macro_config("", r#"{"type": "Macro", "name": "missing"}"#),
+            &dir,
+        )
+        .unwrap_err();
+        assert_eq!(
+            problem.path.to_string(),
+            "device.buttons.button_0.actions.PRESSED[0]"
+        );
+        assert_eq!(problem.message, "unknown macro \"missing\"");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn window_rules_match_case_insensitive_substrings() {
+        let window = FocusedWindow {
+            class: "org.gnome.Nautilus".to_string(),
+            title: "Downloads - Files".to_string(),
+        };
+        let rule = |class: Option<&str>, title: Option<&str>| WindowRule {
+            class: class.map(str::to_string),
+            title: title.map(str::to_string),
+        };
+
+        assert!(rule(Some("nautilus"), None).matches(&window));
+        assert!(rule(None, Some("DOWNLOADS")).matches(&window));
+        assert!(rule(Some("Nautilus"), Some("files")).matches(&window));
+        assert!(!rule(Some("Nautilus"), Some("Terminal")).matches(&window));
+        assert!(!rule(Some("firefox"), None).matches(&window));
+        assert!(!rule(None, None).matches(&window));
+        assert!(!rule(Some("nautilus"), None).matches(&FocusedWindow::default()));
+    }
+
+    #[test]
+    fn the_first_matching_profile_applies() {
+        let config: TokenBasedConfig = serde_json::from_str(
+            r#"{"device": {"button_count": 3, "buttons": {}}, "profiles": [
+                {"name": "browser", "match": [{"class": "firefox"}, {"class": "chromium"}]},
+                {"name": "docs", "match": [{"title": "Google Docs"}]}
+            ]}"#,
+        )
+        .unwrap();
+        let window = |class: &str, title: &str| FocusedWindow {
+            class: class.to_string(),
+            title: title.to_string(),
+        };
+
+        assert_eq!(
+            config.profile_for(&window("Chromium", "Inbox")),
+            Some("browser")
+        );
+        assert_eq!(
+            config.profile_for(&window("firefox", "Report - Google Docs")),
+            Some("browser")
+        );
+        assert_eq!(
+            config.profile_for(&window("epiphany", "Report - Google Docs")),
+            Some("docs")
+        );
+        assert_eq!(
+            config.profile_for(&window("libreoffice", "report.odt")),
+            None
+        );
+    }
+
+    #[test]
+    fn legacy_actions_convert_to_current_ones() {
+        let source = with_actions(
+            None,
+            r#"{"type": "Key", "value": "F5", "direction": "Release"},
+               {"type": "Unicode", "value": "s", "auto_release": false},
+               {"type": "Text", "value": "hi", "comment": "ignored in v3"},
+               {"type": "Sleep", "value": 20},
+               {"value": 100, "type": "ReleaseAllAfter"},
+               {"type": "ReleaseAll", "value": 1}"#,
+        );
+        let config = ConfigFormat::Json.parse_config(&source).unwrap();
+        assert_eq!(config.version, 3);
+        assert_eq!(
+            pressed_actions(&config),
+            serde_json::json!([
+                {"type": "KeyRelease", "key": "F5"},
+                {"type": "KeyPress", "key": {"Unicode": "s"}, "auto_release": false},
+                {"type": "Text", "text": "hi"},
+                {"type": "Sleep", "duration_ms": 20},
+                {"type": "ReleaseAllAfter", "duration_ms": 100},
+                {"type": "ReleaseAll"}
+            ])
+        );
+    }
+
+    #[test]
+    fn v3_config_round_trips_through_v4() {
+        let source = with_actions(
+            None,
+            r#"{"type": "Key", "value": "Control"},
+               {"type": "Text", "value": "copy", "delay": 5},
+               {"type": "ReleaseAfter", "value": 50}"#,
+        );
+        let mut config = ConfigFormat::Json.parse_config(&source).unwrap();
+        config.version = CONFIG_VERSION;
+
+        let migrated = ConfigFormat::Json.serialize(&config).unwrap();
+        let reread = ConfigFormat::Json.parse_config(&migrated).unwrap();
+        assert_eq!(reread.version, CONFIG_VERSION);
+        assert_eq!(pressed_actions(&reread), pressed_actions(&config));
+    }
+
+    #[test]
+    fn current_actions_reject_unknown_keys() {
+        for version in [None, Some(CONFIG_VERSION)] {
+            let source = with_actions(version, r#"{"type": "KeyPress", "key": "F5", "kye": 1}"#);
+            assert!(ConfigFormat::Json.parse_config(&source).is_err());
         }
+        for action in [
+            r#"{"type": "Text", "text": "a", "txet": "b"}"#,
+            r#"{"type": "ReleaseAll", "value": 1}"#,
+        ] {
+            let source = with_actions(Some(CONFIG_VERSION), action);
+            assert!(
+                ConfigFormat::Json.parse_config(&source).is_err(),
+                "{action}"
+            );
+        }
+    }
+
+    #[test]
+    fn profiles_are_checked_with_every_block_they_apply_to() {
+        let config: TokenBasedConfig = serde_json::from_value(serde_json::json!({
+            "version": 4,
+            "device": {"button_count": 3, "buttons": {}},
+            "devices": [{
+                "vendor_id": 4057,
+                "product_id": 134,
+                "button_count": 4,
+                "settings": {"hold_stages": [{"name": "LONG", "threshold_ms": 2000}]},
+                "buttons": {"button_1": {"layer_key": {"layer": "nav"}}}
+            }],
+            "profiles": [{
+                "name": "editor",
+                "match": [{"class": "code"}],
+                "buttons": {
+                    "button_0": {
+                        "actions": {"LONG": [], "BOGUS": []},
+                        "layers": {"nav": {"PRESSED": []}}
+                    },
+                    "button_3": {"actions": {"LONG": []}}
+                }
+            }]
+        }))
+        .unwrap();
+
+        let mut problems: Vec<String> = config.problems().iter().map(ToString::to_string).collect();
+        problems.sort();
+        assert_eq!(problems.len(), 3, "{problems:#?}");
+        assert!(
+            problems[0]
+                .starts_with("profiles[0].buttons.button_0.actions.BOGUS: unknown event \"BOGUS\"")
+        );
+        assert!(problems[0].ends_with("RELEASING_<stage>"));
+        assert!(
+            problems[1]
+                .starts_with("profiles[0].buttons.button_0.actions.LONG: unknown event \"LONG\"")
+        );
+        assert!(problems[1].ends_with("(on pedals configured by device)"));
+        assert_eq!(
+            problems[2],
+            "profiles[0].buttons.button_0.layers.nav: no layer key switches to layer \"nav\" (on pedals configured by device)"
+        );
+    }
+
+    #[test]
+    fn action_errors_point_at_the_offending_key() {
+        let source = with_actions(
+            Some(CONFIG_VERSION),
+            "{\"type\": \"Text\",\n \"text\": \"a\",\n \"txet\": \"b\"\n}",
+        );
+        let error = serde_json::from_str::<TokenBasedConfig>(&source).unwrap_err();
+        assert_eq!(error.line(), 4);
     }
 }
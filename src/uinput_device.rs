@@ -0,0 +1,338 @@
+use crate::input_backend::InputBackend;
+use crate::token_based_config::InputBackendKind;
+use anyhow::{Context, Result, anyhow, bail};
+use enigo::{Axis, Button, Coordinate, Direction, Key};
+use evdev::uinput::VirtualDevice;
+use evdev::{AttributeSet, InputEvent, KeyCode, KeyEvent, RelativeAxisCode, RelativeAxisEvent};
+
+/// Virtual keyboard and mouse created through `/dev/uinput`.
+///
+/// The kernel treats their events like those of real devices, so they reach every
+/// compositor, the TTY and games. Key codes are translated by the active keyboard
+/// layout, so characters typed with `Text` assume a US layout.
+pub struct UinputDevice {
+    keyboard: VirtualDevice,
+    mouse: VirtualDevice,
+}
+
+impl UinputDevice {
+    pub fn new() -> Result<Self> {
+        // Every key code below the mouse buttons plus the extended keys after them
+        let keys: AttributeSet<KeyCode> = (1..KeyCode::BTN_0.code())
+            .chain(KeyCode::KEY_OK.code()..KeyCode::BTN_TRIGGER_HAPPY1.code())
+            .map(KeyCode::new)
+            .collect();
+        let keyboard = VirtualDevice::builder()
+            .context(
+                "Failed to open /dev/uinput. Is the uinput module loaded and the device writable?",
+            )?
+            .name("Elgato Pedal Controller Keyboard")
+            .with_keys(&keys)?
+            .build()
+            .context("Failed to create the virtual keyboard.")?;
+
+        let buttons: AttributeSet<KeyCode> = [
+            KeyCode::BTN_LEFT,
+            KeyCode::BTN_RIGHT,
+            KeyCode::BTN_MIDDLE,
+            KeyCode::BTN_SIDE,
+            KeyCode::BTN_EXTRA,
+        ]
+        .into_iter()
+        .collect();
+        let axes: AttributeSet<RelativeAxisCode> = [
+            RelativeAxisCode::REL_X,
+            RelativeAxisCode::REL_Y,
+            RelativeAxisCode::REL_WHEEL,
+            RelativeAxisCode::REL_HWHEEL,
+        ]
+        .into_iter()
+        .collect();
+        let mouse = VirtualDevice::builder()
+            .context("Failed to open /dev/uinput.")?
+            .name("Elgato Pedal Controller Mouse")
+            .with_keys(&buttons)?
+            .with_relative_axes(&axes)?
+            .build()
+            .context("Failed to create the virtual mouse.")?;
+
+        Ok(UinputDevice { keyboard, mouse })
+    }
+
+    /// Send a key, wrapped in Shift for characters that need it
+    fn send_key(&mut self, code: KeyCode, shifted: bool, direction: Direction) -> Result<()> {
+        let shift = |value| InputEvent::from(KeyEvent::new(KeyCode::KEY_LEFTSHIFT, value));
+        let key = |value| InputEvent::from(KeyEvent::new(code, value));
+
+        let mut events = Vec::new();
+        if matches!(direction, Direction::Press | Direction::Click) {
+            events.extend(shifted.then(|| shift(1)));
+            events.push(key(1));
+        }
+        if matches!(direction, Direction::Release | Direction::Click) {
+            events.push(key(0));
+            events.extend(shifted.then(|| shift(0)));
+        }
+
+        // One report per event, so that applications see the keys in order
+        for event in events {
+            self.keyboard.emit(&[event])?;
+        }
+        Ok(())
+    }
+}
+
+impl InputBackend for UinputDevice {
+    fn kind(&self) -> InputBackendKind {
+        InputBackendKind::Uinput
+    }
+
+    fn key(&mut self, key: Key, direction: Direction) -> Result<()> {
+        let (code, shifted) =
+            key_code(key).ok_or_else(|| anyhow!("{:?} has no uinput key code", key))?;
+        self.send_key(code, shifted, direction)
+    }
+
+    fn text(&mut self, text: &str) -> Result<()> {
+        for c in text.chars() {
+            let (code, shifted) = key_code(Key::Unicode(c)).ok_or_else(|| {
+                anyhow!("{:?} cannot be typed, only US keyboard characters can", c)
+            })?;
+            self.send_key(code, shifted, Direction::Click)?;
+        }
+        Ok(())
+    }
+
+    fn button(&mut self, button: Button, direction: Direction) -> Result<()> {
+        let code = match button {
+            Button::Left => KeyCode::BTN_LEFT,
+            Button::Middle => KeyCode::BTN_MIDDLE,
+            Button::Right => KeyCode::BTN_RIGHT,
+            Button::Back => KeyCode::BTN_SIDE,
+            Button::Forward => KeyCode::BTN_EXTRA,
+            Button::ScrollUp => return self.scroll(-1, Axis::Vertical),
+            Button::ScrollDown => return self.scroll(1, Axis::Vertical),
+            Button::ScrollLeft => return self.scroll(-1, Axis::Horizontal),
+            Button::ScrollRight => return self.scroll(1, Axis::Horizontal),
+        };
+
+        if matches!(direction, Direction::Press | Direction::Click) {
+            self.mouse.emit(&[KeyEvent::new(code, 1).into()])?;
+        }
+        if matches!(direction, Direction::Release | Direction::Click) {
+            self.mouse.emit(&[KeyEvent::new(code, 0).into()])?;
+        }
+        Ok(())
+    }
+
+    fn move_mouse(&mut self, x: i32, y: i32, coordinate: Coordinate) -> Result<()> {
+        if coordinate == Coordinate::Abs {
+            bail!("absolute mouse movement is not supported by the uinput backend");
+        }
+        self.mouse.emit(&[
+            relative(RelativeAxisCode::REL_X, x),
+            relative(RelativeAxisCode::REL_Y, y),
+        ])?;
+        Ok(())
+    }
+
+    fn scroll(&mut self, amount: i32, axis: Axis) -> Result<()> {
+        // Positive amounts scroll down, the opposite of REL_WHEEL
+        let event = match axis {
+            Axis::Vertical => relative(RelativeAxisCode::REL_WHEEL, -amount),
+            Axis::Horizontal => relative(RelativeAxisCode::REL_HWHEEL, amount),
+        };
+        self.mouse.emit(&[event])?;
+        Ok(())
+    }
+}
+
+fn relative(axis: RelativeAxisCode, value: i32) -> InputEvent {
+    RelativeAxisEvent::new(axis, value).into()
+}
+
+/// Linux key code of a key and whether Shift is needed for it. `Other` holds a raw
+/// Linux key code, characters are looked up on the US layout.
+fn key_code(key: Key) -> Option<(KeyCode, bool)> {
+    let code = match key {
+        Key::Unicode(c) => return char_key_code(c),
+        Key::Other(code) => KeyCode::new(u16::try_from(code).ok()?),
+        Key::Add => KeyCode::KEY_KPPLUS,
+        Key::Alt | Key::Option => KeyCode::KEY_LEFTALT,
+        Key::Backspace => KeyCode::KEY_BACKSPACE,
+        Key::Break => KeyCode::KEY_BREAK,
+        Key::Cancel => KeyCode::KEY_CANCEL,
+        Key::CapsLock => KeyCode::KEY_CAPSLOCK,
+        Key::Clear => KeyCode::KEY_CLEAR,
+        Key::Control | Key::LControl => KeyCode::KEY_LEFTCTRL,
+        Key::Decimal => KeyCode::KEY_KPDOT,
+        Key::Delete => KeyCode::KEY_DELETE,
+        Key::Divide => KeyCode::KEY_KPSLASH,
+        Key::DownArrow => KeyCode::KEY_DOWN,
+        Key::End => KeyCode::KEY_END,
+        Key::Escape => KeyCode::KEY_ESC,
+        Key::F1 => KeyCode::KEY_F1,
+        Key::F2 => KeyCode::KEY_F2,
+        Key::F3 => KeyCode::KEY_F3,
+        Key::F4 => KeyCode::KEY_F4,
+        Key::F5 => KeyCode::KEY_F5,
+        Key::F6 => KeyCode::KEY_F6,
+        Key::F7 => KeyCode::KEY_F7,
+        Key::F8 => KeyCode::KEY_F8,
+        Key::F9 => KeyCode::KEY_F9,
+        Key::F10 => KeyCode::KEY_F10,
+        Key::F11 => KeyCode::KEY_F11,
+        Key::F12 => KeyCode::KEY_F12,
+        Key::F13 => KeyCode::KEY_F13,
+        Key::F14 => KeyCode::KEY_F14,
+        Key::F15 => KeyCode::KEY_F15,
+        Key::F16 => KeyCode::KEY_F16,
+        Key::F17 => KeyCode::KEY_F17,
+        Key::F18 => KeyCode::KEY_F18,
+        Key::F19 => KeyCode::KEY_F19,
+        Key::F20 => KeyCode::KEY_F20,
+        Key::F21 => KeyCode::KEY_F21,
+        Key::F22 => KeyCode::KEY_F22,
+        Key::F23 => KeyCode::KEY_F23,
+        Key::F24 => KeyCode::KEY_F24,
+        Key::Find => KeyCode::KEY_FIND,
+        Key::Hangul => KeyCode::KEY_HANGEUL,
+        Key::Hanja => KeyCode::KEY_HANJA,
+        Key::Help => KeyCode::KEY_HELP,
+        Key::Home => KeyCode::KEY_HOME,
+        Key::Insert => KeyCode::KEY_INSERT,
+        Key::LeftArrow => KeyCode::KEY_LEFT,
+        Key::Linefeed => KeyCode::KEY_LINEFEED,
+        Key::LMenu => KeyCode::KEY_COMPOSE,
+        Key::Multiply => KeyCode::KEY_KPASTERISK,
+        Key::MediaNextTrack => KeyCode::KEY_NEXTSONG,
+        Key::MediaPlayPause => KeyCode::KEY_PLAYPAUSE,
+        Key::MediaPrevTrack => KeyCode::KEY_PREVIOUSSONG,
+        Key::MediaStop => KeyCode::KEY_STOPCD,
+        Key::Numlock => KeyCode::KEY_NUMLOCK,
+        Key::Numpad0 => KeyCode::KEY_KP0,
+        Key::Numpad1 => KeyCode::KEY_KP1,
+        Key::Numpad2 => KeyCode::KEY_KP2,
+        Key::Numpad3 => KeyCode::KEY_KP3,
+        Key::Numpad4 => KeyCode::KEY_KP4,
+        Key::Numpad5 => KeyCode::KEY_KP5,
+        Key::Numpad6 => KeyCode::KEY_KP6,
+        Key::Numpad7 => KeyCode::KEY_KP7,
+        Key::Numpad8 => KeyCode::KEY_KP8,
+        Key::Numpad9 => KeyCode::KEY_KP9,
+        Key::PageDown => KeyCode::KEY_PAGEDOWN,
+        Key::PageUp => KeyCode::KEY_PAGEUP,
+        Key::Pause => KeyCode::KEY_PAUSE,
+        Key::PrintScr | Key::SysReq => KeyCode::KEY_SYSRQ,
+        Key::RControl => KeyCode::KEY_RIGHTCTRL,
+        Key::Redo => KeyCode::KEY_REDO,
+        Key::Return => KeyCode::KEY_ENTER,
+        Key::RightArrow => KeyCode::KEY_RIGHT,
+        Key::RShift => KeyCode::KEY_RIGHTSHIFT,
+        Key::ScrollLock => KeyCode::KEY_SCROLLLOCK,
+        Key::Select => KeyCode::KEY_SELECT,
+        Key::Shift | Key::LShift => KeyCode::KEY_LEFTSHIFT,
+        Key::Space => KeyCode::KEY_SPACE,
+        Key::Subtract => KeyCode::KEY_KPMINUS,
+        Key::Tab => KeyCode::KEY_TAB,
+        Key::Undo => KeyCode::KEY_UNDO,
+        Key::UpArrow => KeyCode::KEY_UP,
+        Key::VolumeDown => KeyCode::KEY_VOLUMEDOWN,
+        Key::VolumeUp => KeyCode::KEY_VOLUMEUP,
+        Key::VolumeMute => KeyCode::KEY_MUTE,
+        Key::MicMute => KeyCode::KEY_MICMUTE,
+        Key::Meta => KeyCode::KEY_LEFTMETA,
+        // Keys without a dedicated Linux key code, e.g. F25-F35 or Begin
+        _ => return None,
+    };
+    Some((code, false))
+}
+
+/// Key code of a character on the US layout, and whether it needs Shift
+fn char_key_code(c: char) -> Option<(KeyCode, bool)> {
+    const LETTERS: [KeyCode; 26] = [
+        KeyCode::KEY_A,
+        KeyCode::KEY_B,
+        KeyCode::KEY_C,
+        KeyCode::KEY_D,
+        KeyCode::KEY_E,
+        KeyCode::KEY_F,
+        KeyCode::KEY_G,
+        KeyCode::KEY_H,
+        KeyCode::KEY_I,
+        KeyCode::KEY_J,
+        KeyCode::KEY_K,
+        KeyCode::KEY_L,
+        KeyCode::KEY_M,
+        KeyCode::KEY_N,
+        KeyCode::KEY_O,
+        KeyCode::KEY_P,
+        KeyCode::KEY_Q,
+        KeyCode::KEY_R,
+        KeyCode::KEY_S,
+        KeyCode::KEY_T,
+        KeyCode::KEY_U,
+        KeyCode::KEY_V,
+        KeyCode::KEY_W,
+        KeyCode::KEY_X,
+        KeyCode::KEY_Y,
+        KeyCode::KEY_Z,
+    ];
+    const DIGITS: [KeyCode; 10] = [
+        KeyCode::KEY_0,
+        KeyCode::KEY_1,
+        KeyCode::KEY_2,
+        KeyCode::KEY_3,
+        KeyCode::KEY_4,
+        KeyCode::KEY_5,
+        KeyCode::KEY_6,
+        KeyCode::KEY_7,
+        KeyCode::KEY_8,
+        KeyCode::KEY_9,
+    ];
+    // Shifted characters of the digit row, in the order of `DIGITS`
+    const DIGIT_SYMBOLS: &str = ")!@#$%^&*(";
+
+    if c.is_ascii_lowercase() {
+        return Some((LETTERS[(c as u8 - b'a') as usize], false));
+    }
+    if c.is_ascii_uppercase() {
+        return Some((LETTERS[(c as u8 - b'A') as usize], true));
+    }
+    if let Some(digit) = c.to_digit(10) {
+        return Some((DIGITS[digit as usize], false));
+    }
+    if let Some(index) = DIGIT_SYMBOLS.find(c) {
+        return Some((DIGITS[index], true));
+    }
+
+    let (code, shifted) = match c {
+        ' ' => (KeyCode::KEY_SPACE, false),
+        '\n' => (KeyCode::KEY_ENTER, false),
+        '\t' => (KeyCode::KEY_TAB, false),
+        '-' => (KeyCode::KEY_MINUS, false),
+        '_' => (KeyCode::KEY_MINUS, true),
+        '=' => (KeyCode::KEY_EQUAL, false),
+        '+' => (KeyCode::KEY_EQUAL, true),
+        '[' => (KeyCode::KEY_LEFTBRACE, false),
+        '{' => (KeyCode::KEY_LEFTBRACE, true),
+        ']' => (KeyCode::KEY_RIGHTBRACE, false),
+        '}' => (KeyCode::KEY_RIGHTBRACE, true),
+        '\\' => (KeyCode::KEY_BACKSLASH, false),
+        '|' => (KeyCode::KEY_BACKSLASH, true),
+        ';' => (KeyCode::KEY_SEMICOLON, false),
+        ':' => (KeyCode::KEY_SEMICOLON, true),
+        '\'' => (KeyCode::KEY_APOSTROPHE, false),
+        '"' => (KeyCode::KEY_APOSTROPHE, true),
+        '`' => (KeyCode::KEY_GRAVE, false),
+        '~' => (KeyCode::KEY_GRAVE, true),
+        ',' => (KeyCode::KEY_COMMA, false),
+        '<' => (KeyCode::KEY_COMMA, true),
+        '.' => (KeyCode::KEY_DOT, false),
+        '>' => (KeyCode::KEY_DOT, true),
+        '/' => (KeyCode::KEY_SLASH, false),
+        '?' => (KeyCode::KEY_SLASH, true),
+        _ => return None,
+    };
+    Some((code, shifted))
+}
@@ -0,0 +1,402 @@
+use crate::input_backend::InputBackend;
+use crate::token_based_config::InputBackendKind;
+use anyhow::{Context, Result, anyhow, bail};
+use enigo::{Axis, Button, Coordinate, Direction, Key};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::os::fd::AsFd;
+use std::time::Instant;
+use wayland_client::globals::{GlobalListContents, registry_queue_init};
+use wayland_client::protocol::wl_keyboard::KeymapFormat;
+use wayland_client::protocol::wl_output::{self, WlOutput};
+use wayland_client::protocol::wl_pointer::{self, ButtonState};
+use wayland_client::protocol::wl_registry::WlRegistry;
+use wayland_client::protocol::wl_seat::WlSeat;
+use wayland_client::{Connection, Dispatch, EventQueue, Proxy, QueueHandle, WEnum, delegate_noop};
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_manager_v1::ZwpVirtualKeyboardManagerV1;
+use wayland_protocols_misc::zwp_virtual_keyboard_v1::client::zwp_virtual_keyboard_v1::ZwpVirtualKeyboardV1;
+use wayland_protocols_wlr::virtual_pointer::v1::client::zwlr_virtual_pointer_manager_v1::ZwlrVirtualPointerManagerV1;
+use wayland_protocols_wlr::virtual_pointer::v1::client::zwlr_virtual_pointer_v1::ZwlrVirtualPointerV1;
+use xkeysym::Keysym;
+
+/// Number of keys in the generated keymap. Their XKB keycodes end at 255, the highest
+/// keycode XWayland clients can see.
+const MAX_KEYS: usize = 247;
+
+/// Length of one wheel step in `axis` events, as reported by libinput
+const SCROLL_STEP: f64 = 15.0;
+
+/// Virtual keyboard and pointer of the wlroots protocols (Sway, Hyprland, river, ...).
+///
+/// The keyboard has no fixed layout: every keysym that is needed gets a keycode of its
+/// own, and the keymap is uploaded again whenever a new one is added. Any character can
+/// therefore be typed, whatever layout the user has configured.
+pub struct WaylandDevice {
+    conn: Connection,
+    queue: EventQueue<WaylandState>,
+    state: WaylandState,
+    keyboard: ZwpVirtualKeyboardV1,
+    pointer: ZwlrVirtualPointerV1,
+    /// Keysym of every keycode, the evdev code is the index plus one
+    keysyms: Vec<Keysym>,
+    pressed: HashSet<u32>,
+    depressed_modifiers: u32,
+    locked_modifiers: u32,
+    started: Instant,
+}
+
+#[derive(Default)]
+struct WaylandState {
+    outputs: HashMap<usize, Output>,
+}
+
+/// Position and size of an output in the compositor's layout
+#[derive(Default)]
+struct Output {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+    scale: i32,
+}
+
+impl WaylandDevice {
+    pub fn new() -> Result<Self> {
+        let conn = Connection::connect_to_env().context("Failed to connect to Wayland.")?;
+        let (globals, mut queue) = registry_queue_init::<WaylandState>(&conn)?;
+        let qh = queue.handle();
+
+        let seat: WlSeat = globals.bind(&qh, 1..=7, ())?;
+        let keyboard_manager: ZwpVirtualKeyboardManagerV1 = globals
+            .bind(&qh, 1..=1, ())
+            .context("The compositor does not support zwp_virtual_keyboard_v1.")?;
+        let pointer_manager: ZwlrVirtualPointerManagerV1 = globals
+            .bind(&qh, 1..=2, ())
+            .context("The compositor does not support zwlr_virtual_pointer_v1.")?;
+
+        // Outputs are needed to map absolute mouse positions onto the layout
+        let outputs = globals.contents().with_list(|list| {
+            list.iter()
+                .filter(|global| global.interface == WlOutput::interface().name)
+                .map(|global| (global.name, global.version))
+                .collect::<Vec<_>>()
+        });
+        for (index, (name, version)) in outputs.into_iter().enumerate() {
+            globals
+                .registry()
+                .bind::<WlOutput, _, _>(name, version.min(4), &qh, index);
+        }
+
+        let keyboard = keyboard_manager.create_virtual_keyboard(&seat, &qh, ());
+        let pointer = pointer_manager.create_virtual_pointer(Some(&seat), &qh, ());
+
+        let mut state = WaylandState::default();
+        queue.roundtrip(&mut state)?;
+
+        Ok(WaylandDevice {
+            conn,
+            queue,
+            state,
+            keyboard,
+            pointer,
+            keysyms: Vec::new(),
+            pressed: HashSet::new(),
+            depressed_modifiers: 0,
+            locked_modifiers: 0,
+            started: Instant::now(),
+        })
+    }
+
+    fn send_keysym(&mut self, keysym: Keysym, direction: Direction) -> Result<()> {
+        let code = self.keycode(keysym)?;
+
+        if matches!(direction, Direction::Press | Direction::Click) {
+            self.keyboard.key(self.time(), code, 1);
+            self.pressed.insert(code);
+            if let Some((mask, locks)) = modifier(keysym) {
+                if locks {
+                    self.locked_modifiers ^= mask;
+                } else {
+                    self.depressed_modifiers |= mask;
+                }
+                self.send_modifiers();
+            }
+        }
+        if matches!(direction, Direction::Release | Direction::Click) {
+            self.keyboard.key(self.time(), code, 0);
+            self.pressed.remove(&code);
+            if let Some((mask, false)) = modifier(keysym) {
+                self.depressed_modifiers &= !mask;
+                self.send_modifiers();
+            }
+        }
+        Ok(())
+    }
+
+    /// Evdev code of a keysym, adding it to the keymap when it has none yet
+    fn keycode(&mut self, keysym: Keysym) -> Result<u32> {
+        if let Some(index) = self.keysyms.iter().position(|known| *known == keysym) {
+            return Ok(index as u32 + 1);
+        }
+
+        let index = if self.keysyms.len() < MAX_KEYS {
+            self.keysyms.push(keysym);
+            self.keysyms.len() - 1
+        } else {
+            // The keymap is full, reuse a key that is not held down
+            let index = (0..MAX_KEYS)
+                .find(|index| !self.pressed.contains(&(*index as u32 + 1)))
+                .ok_or_else(|| anyhow!("every key of the virtual keyboard is held down"))?;
+            self.keysyms[index] = keysym;
+            index
+        };
+        self.upload_keymap()?;
+        Ok(index as u32 + 1)
+    }
+
+    fn upload_keymap(&mut self) -> Result<()> {
+        let keymap = self.keymap();
+        let mut file = tempfile::tempfile().context("Failed to create the keymap file.")?;
+        file.write_all(keymap.as_bytes())?;
+        file.write_all(&[0])?;
+
+        self.keyboard.keymap(
+            KeymapFormat::XkbV1 as u32,
+            file.as_fd(),
+            keymap.len() as u32 + 1,
+        );
+        // The file descriptor is sent on flush, so the file must still be open
+        self.conn
+            .flush()
+            .context("Failed to send the keymap to the compositor.")?;
+        Ok(())
+    }
+
+    /// XKB keymap with one key per keysym in `keysyms`
+    fn keymap(&self) -> String {
+        let mut keycodes = String::new();
+        let mut symbols = String::new();
+        for (index, keysym) in self.keysyms.iter().enumerate() {
+            keycodes.push_str(&format!("    <K{index}> = {};\n", index + 9));
+            symbols.push_str(&format!(
+                "    key <K{index}> {{ [ 0x{:x} ] }};\n",
+                keysym.raw()
+            ));
+            if let Some((mask, _)) = modifier(*keysym) {
+                symbols.push_str(&format!(
+                    "    modifier_map {} {{ <K{index}> }};\n",
+                    modifier_name(mask)
+                ));
+            }
+        }
+
+        format!(
+            "xkb_keymap {{\n  xkb_keycodes \"pedal\" {{\n    minimum = 8;\n    maximum = 255;\n{keycodes}  }};\n  xkb_types \"pedal\" {{ include \"complete\" }};\n  xkb_compatibility \"pedal\" {{ include \"complete\" }};\n  xkb_symbols \"pedal\" {{\n{symbols}  }};\n}};\n"
+        )
+    }
+
+    fn send_modifiers(&self) {
+        self.keyboard
+            .modifiers(self.depressed_modifiers, 0, self.locked_modifiers, 0);
+    }
+
+    /// Move to a position in the layout spanned by all outputs
+    fn move_absolute(&mut self, x: i32, y: i32) -> Result<()> {
+        // Pick up outputs that were added, moved or resized since the last move
+        self.queue.roundtrip(&mut self.state)?;
+
+        let outputs = self
+            .state
+            .outputs
+            .values()
+            .filter(|output| output.width > 0);
+        let (mut left, mut top, mut right, mut bottom) = (i32::MAX, i32::MAX, i32::MIN, i32::MIN);
+        for output in outputs {
+            let scale = output.scale.max(1);
+            left = left.min(output.x);
+            top = top.min(output.y);
+            right = right.max(output.x + output.width / scale);
+            bottom = bottom.max(output.y + output.height / scale);
+        }
+        if left > right {
+            bail!("no output size is known, cannot move the mouse to a position");
+        }
+
+        let x = (x.clamp(left, right) - left) as u32;
+        let y = (y.clamp(top, bottom) - top) as u32;
+        self.pointer.motion_absolute(
+            self.time(),
+            x,
+            y,
+            (right - left) as u32,
+            (bottom - top) as u32,
+        );
+        self.pointer.frame();
+        Ok(())
+    }
+
+    /// Event timestamp in milliseconds
+    fn time(&self) -> u32 {
+        self.started.elapsed().as_millis() as u32
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.conn
+            .flush()
+            .context("Failed to send input to the compositor.")?;
+        Ok(())
+    }
+}
+
+impl InputBackend for WaylandDevice {
+    fn kind(&self) -> InputBackendKind {
+        InputBackendKind::Wayland
+    }
+
+    fn key(&mut self, key: Key, direction: Direction) -> Result<()> {
+        let keysym = match key {
+            Key::Unicode(c) => char_keysym(c),
+            key => Keysym::from(key),
+        };
+        self.send_keysym(keysym, direction)?;
+        self.flush()
+    }
+
+    fn text(&mut self, text: &str) -> Result<()> {
+        for c in text.chars() {
+            self.send_keysym(char_keysym(c), Direction::Click)?;
+        }
+        self.flush()
+    }
+
+    fn button(&mut self, button: Button, direction: Direction) -> Result<()> {
+        // Linux button codes, BTN_LEFT and following
+        let code = match button {
+            Button::Left => 0x110,
+            Button::Right => 0x111,
+            Button::Middle => 0x112,
+            Button::Back => 0x113,
+            Button::Forward => 0x114,
+            Button::ScrollUp => return self.scroll(-1, Axis::Vertical),
+            Button::ScrollDown => return self.scroll(1, Axis::Vertical),
+            Button::ScrollLeft => return self.scroll(-1, Axis::Horizontal),
+            Button::ScrollRight => return self.scroll(1, Axis::Horizontal),
+        };
+
+        if matches!(direction, Direction::Press | Direction::Click) {
+            self.pointer.button(self.time(), code, ButtonState::Pressed);
+            self.pointer.frame();
+        }
+        if matches!(direction, Direction::Release | Direction::Click) {
+            self.pointer
+                .button(self.time(), code, ButtonState::Released);
+            self.pointer.frame();
+        }
+        self.flush()
+    }
+
+    fn move_mouse(&mut self, x: i32, y: i32, coordinate: Coordinate) -> Result<()> {
+        match coordinate {
+            Coordinate::Rel => {
+                self.pointer.motion(self.time(), x as f64, y as f64);
+                self.pointer.frame();
+            }
+            Coordinate::Abs => self.move_absolute(x, y)?,
+        }
+        self.flush()
+    }
+
+    fn scroll(&mut self, amount: i32, axis: Axis) -> Result<()> {
+        // Positive amounts scroll down or right, like wl_pointer
+        let axis = match axis {
+            Axis::Vertical => wl_pointer::Axis::VerticalScroll,
+            Axis::Horizontal => wl_pointer::Axis::HorizontalScroll,
+        };
+        self.pointer.axis_source(wl_pointer::AxisSource::Wheel);
+        self.pointer
+            .axis_discrete(self.time(), axis, SCROLL_STEP * amount as f64, amount);
+        self.pointer.frame();
+        self.flush()
+    }
+}
+
+fn char_keysym(c: char) -> Keysym {
+    match c {
+        '\n' => Keysym::Return,
+        '\t' => Keysym::Tab,
+        _ => Keysym::from_char(c),
+    }
+}
+
+/// Modifier mask set by a keysym, and whether it toggles a lock instead of being held
+fn modifier(keysym: Keysym) -> Option<(u32, bool)> {
+    match keysym {
+        Keysym::Shift_L | Keysym::Shift_R => Some((1 << 0, false)),
+        Keysym::Caps_Lock => Some((1 << 1, true)),
+        Keysym::Control_L | Keysym::Control_R => Some((1 << 2, false)),
+        Keysym::Alt_L | Keysym::Alt_R => Some((1 << 3, false)),
+        Keysym::Num_Lock => Some((1 << 4, true)),
+        Keysym::Super_L | Keysym::Super_R => Some((1 << 6, false)),
+        _ => None,
+    }
+}
+
+/// XKB name of the real modifier with this mask
+fn modifier_name(mask: u32) -> &'static str {
+    match mask {
+        0b1 => "Shift",
+        0b10 => "Lock",
+        0b100 => "Control",
+        0b1000 => "Mod1",
+        0b10000 => "Mod2",
+        _ => "Mod4",
+    }
+}
+
+impl Dispatch<WlRegistry, GlobalListContents> for WaylandState {
+    fn event(
+        _: &mut Self,
+        _: &WlRegistry,
+        _: <WlRegistry as Proxy>::Event,
+        _: &GlobalListContents,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+    }
+}
+
+impl Dispatch<WlOutput, usize> for WaylandState {
+    fn event(
+        state: &mut Self,
+        _: &WlOutput,
+        event: wl_output::Event,
+        index: &usize,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let output = state.outputs.entry(*index).or_default();
+        match event {
+            wl_output::Event::Geometry { x, y, .. } => {
+                output.x = x;
+                output.y = y;
+            }
+            wl_output::Event::Mode {
+                flags: WEnum::Value(flags),
+                width,
+                height,
+                ..
+            } if flags.contains(wl_output::Mode::Current) => {
+                output.width = width;
+                output.height = height;
+            }
+            wl_output::Event::Scale { factor } => output.scale = factor,
+            _ => {}
+        }
+    }
+}
+
+delegate_noop!(WaylandState: ignore WlSeat);
+delegate_noop!(WaylandState: ZwpVirtualKeyboardManagerV1);
+delegate_noop!(WaylandState: ZwpVirtualKeyboardV1);
+delegate_noop!(WaylandState: ZwlrVirtualPointerManagerV1);
+delegate_noop!(WaylandState: ZwlrVirtualPointerV1);